@@ -0,0 +1,465 @@
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use pcap::Linktype;
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::vlan::VlanPacket;
+use std::borrow::Cow;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const ETHERTYPE_QINQ: EtherType = EtherType(0x88a8);
+const ETHERTYPE_QINQ_LEGACY: EtherType = EtherType(0x9100);
+
+const SLL_HEADER_LENGTH: usize = 16;
+const SLL2_HEADER_LENGTH: usize = 20;
+const LOOPBACK_HEADER_LENGTH: usize = 4;
+
+// Datagrams whose fragments don't all show up within this time are dropped,
+// as are the oldest ones when too many are pending at once.
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_PENDING_DATAGRAMS: usize = 1024;
+
+// A reassembled datagram can be as long as the IP packet that carried it
+pub const MAX_REASSEMBLED_DATAGRAM: usize = 65535;
+
+/// Pulls UDP payloads out of captured link layer frames, reassembling
+/// fragmented IP datagrams on the way. Anything that isn't UDP over IPv4 or
+/// IPv6 is skipped.
+pub struct FrameDecoder {
+    linktype: Linktype,
+    fragments: HashMap<FragmentKey, FragmentBuffer>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+struct FragmentKey {
+    src_ip: IpAddr,
+    dst_ip: IpAddr,
+    id: u32,
+    protocol: u8,
+}
+
+struct FragmentBuffer {
+    first_seen: Instant,
+    parts: BTreeMap<usize, Vec<u8>>,
+    buffered: usize,             // bytes held in parts, overlaps included
+    total_length: Option<usize>, // known once the last fragment arrives
+}
+
+struct IpPayload<'a> {
+    src_ip: IpAddr,
+    bytes: Cow<'a, [u8]>,
+}
+
+impl FrameDecoder {
+    pub fn new(linktype: Linktype) -> Self {
+        Self {
+            linktype,
+            fragments: HashMap::new(),
+        }
+    }
+
+    /// Returns the source address and payload of the UDP datagram carried in
+    /// `frame`, or None if the frame isn't UDP or completes no datagram yet.
+    pub fn decode<'a>(&mut self, frame: &'a [u8]) -> Option<(SocketAddr, Cow<'a, [u8]>)> {
+        let ip = match self.linktype {
+            Linktype::ETHERNET => self.ethernet(frame),
+            Linktype::LINUX_SLL => {
+                let ethertype = BigEndian::read_u16(frame.get(14..SLL_HEADER_LENGTH)?);
+                self.network(EtherType(ethertype), frame.get(SLL_HEADER_LENGTH..)?)
+            }
+            Linktype::LINUX_SLL2 => {
+                let ethertype = BigEndian::read_u16(frame.get(..2)?);
+                self.network(EtherType(ethertype), frame.get(SLL2_HEADER_LENGTH..)?)
+            }
+            Linktype::RAW | Linktype::IPV4 | Linktype::IPV6 => self.raw(frame),
+            Linktype::NULL | Linktype::LOOP => {
+                let family = frame.get(..LOOPBACK_HEADER_LENGTH)?;
+                // NULL stores the address family in host order, LOOP in
+                // network order, so accept either
+                let family = match self.linktype {
+                    Linktype::NULL => LittleEndian::read_u32(family),
+                    _ => BigEndian::read_u32(family),
+                };
+                match family {
+                    2 | 24 | 28 | 30 => self.raw(&frame[LOOPBACK_HEADER_LENGTH..]),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }?;
+        udp(ip)
+    }
+
+    fn ethernet<'a>(&mut self, frame: &'a [u8]) -> Option<IpPayload<'a>> {
+        let ethernet = EthernetPacket::new(frame)?;
+        let mut ethertype = ethernet.get_ethertype();
+        let mut bytes = &frame[EthernetPacket::minimum_packet_size()..];
+        while matches!(
+            ethertype,
+            EtherTypes::Vlan | ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY
+        ) {
+            ethertype = VlanPacket::new(bytes)?.get_ethertype();
+            bytes = &bytes[VlanPacket::minimum_packet_size()..];
+        }
+        self.network(ethertype, bytes)
+    }
+
+    fn network<'a>(&mut self, ethertype: EtherType, bytes: &'a [u8]) -> Option<IpPayload<'a>> {
+        match ethertype {
+            EtherTypes::Ipv4 => self.ipv4(bytes),
+            EtherTypes::Ipv6 => self.ipv6(bytes),
+            _ => None,
+        }
+    }
+
+    fn raw<'a>(&mut self, bytes: &'a [u8]) -> Option<IpPayload<'a>> {
+        match bytes.first()? >> 4 {
+            4 => self.ipv4(bytes),
+            6 => self.ipv6(bytes),
+            _ => None,
+        }
+    }
+
+    fn ipv4<'a>(&mut self, bytes: &'a [u8]) -> Option<IpPayload<'a>> {
+        let ipv4 = Ipv4Packet::new(bytes)?;
+        let protocol = ipv4.get_next_level_protocol();
+        if protocol != IpNextHeaderProtocols::Udp {
+            return None;
+        }
+        let src_ip = IpAddr::V4(ipv4.get_source());
+        let dst_ip = IpAddr::V4(ipv4.get_destination());
+        // trust the header over the capture length, which may include padding
+        let end = (ipv4.get_total_length() as usize).min(bytes.len());
+        let payload = bytes.get(ipv4.get_header_length() as usize * 4..end)?;
+
+        let more_fragments = ipv4.get_flags() & 0x1 != 0;
+        let fragment_offset = ipv4.get_fragment_offset() as usize * 8;
+        if !more_fragments && fragment_offset == 0 {
+            return Some(IpPayload {
+                src_ip,
+                bytes: Cow::Borrowed(payload),
+            });
+        }
+        let key = FragmentKey {
+            src_ip,
+            dst_ip,
+            id: ipv4.get_identification() as u32,
+            protocol: protocol.0,
+        };
+        let reassembled = self.reassemble(key, fragment_offset, more_fragments, payload)?;
+        Some(IpPayload {
+            src_ip,
+            bytes: Cow::Owned(reassembled),
+        })
+    }
+
+    fn ipv6<'a>(&mut self, bytes: &'a [u8]) -> Option<IpPayload<'a>> {
+        let ipv6 = Ipv6Packet::new(bytes)?;
+        let src_ip = IpAddr::V6(ipv6.get_source());
+        let dst_ip = IpAddr::V6(ipv6.get_destination());
+        let end = (Ipv6Packet::minimum_packet_size() + ipv6.get_payload_length() as usize)
+            .min(bytes.len());
+        let bytes = &bytes[..end];
+
+        let mut protocol = ipv6.get_next_header();
+        let mut offset = Ipv6Packet::minimum_packet_size();
+        let mut fragment = None;
+        while matches!(
+            protocol,
+            IpNextHeaderProtocols::Hopopt
+                | IpNextHeaderProtocols::Ipv6Route
+                | IpNextHeaderProtocols::Ipv6Frag
+                | IpNextHeaderProtocols::Ipv6Opts
+        ) {
+            let header = bytes.get(offset..offset + 8)?;
+            let length = match protocol {
+                IpNextHeaderProtocols::Ipv6Frag => {
+                    let field = BigEndian::read_u16(&header[2..4]);
+                    let id = BigEndian::read_u32(&header[4..8]);
+                    fragment = Some(((field >> 3) as usize * 8, field & 0x1 != 0, id));
+                    8
+                }
+                _ => (header[1] as usize + 1) * 8,
+            };
+            protocol = IpNextHeaderProtocol(header[0]);
+            offset += length;
+        }
+        if protocol != IpNextHeaderProtocols::Udp {
+            return None;
+        }
+        let payload = bytes.get(offset..)?;
+
+        let bytes = match fragment {
+            None | Some((0, false, _)) => Cow::Borrowed(payload),
+            Some((fragment_offset, more_fragments, id)) => {
+                let key = FragmentKey {
+                    src_ip,
+                    dst_ip,
+                    id,
+                    protocol: protocol.0,
+                };
+                Cow::Owned(self.reassemble(key, fragment_offset, more_fragments, payload)?)
+            }
+        };
+        Some(IpPayload { src_ip, bytes })
+    }
+
+    fn reassemble(
+        &mut self,
+        key: FragmentKey,
+        offset: usize,
+        more_fragments: bool,
+        payload: &[u8],
+    ) -> Option<Vec<u8>> {
+        // no datagram reaches past MAX_REASSEMBLED_DATAGRAM, so neither can
+        // the total length worked out from its last fragment
+        if offset + payload.len() > MAX_REASSEMBLED_DATAGRAM {
+            return None;
+        }
+        let now = Instant::now();
+        if !self.fragments.contains_key(&key) {
+            self.expire_fragments(now);
+        }
+        let buffer = self.fragments.entry(key).or_insert_with(|| FragmentBuffer {
+            first_seen: now,
+            parts: BTreeMap::new(),
+            buffered: 0,
+            total_length: None,
+        });
+        if let Entry::Vacant(entry) = buffer.parts.entry(offset) {
+            entry.insert(payload.to_vec());
+            buffer.buffered += payload.len();
+        }
+        // overlapping fragments could otherwise pile up without bound
+        if buffer.buffered > MAX_REASSEMBLED_DATAGRAM {
+            self.fragments.remove(&key);
+            return None;
+        }
+        if !more_fragments {
+            buffer.total_length = Some(offset + payload.len());
+        }
+
+        let total_length = buffer.total_length?;
+        let mut covered = 0;
+        for (&offset, part) in &buffer.parts {
+            if offset > covered {
+                return None; // still missing a piece
+            }
+            covered = covered.max(offset + part.len());
+        }
+        if covered < total_length {
+            return None;
+        }
+
+        let buffer = self.fragments.remove(&key)?;
+        let mut datagram = vec![0; total_length];
+        for (offset, part) in buffer.parts {
+            let end = (offset + part.len()).min(total_length);
+            if offset < end {
+                datagram[offset..end].copy_from_slice(&part[..end - offset]);
+            }
+        }
+        Some(datagram)
+    }
+
+    fn expire_fragments(&mut self, now: Instant) {
+        self.fragments
+            .retain(|_, buffer| now.duration_since(buffer.first_seen) < FRAGMENT_TIMEOUT);
+        if self.fragments.len() >= MAX_PENDING_DATAGRAMS {
+            let oldest = self
+                .fragments
+                .iter()
+                .min_by_key(|(_, buffer)| buffer.first_seen)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.fragments.remove(&oldest);
+            }
+        }
+    }
+}
+
+fn udp(ip: IpPayload) -> Option<(SocketAddr, Cow<[u8]>)> {
+    let udp = UdpPacket::new(&ip.bytes)?;
+    let src = SocketAddr::new(ip.src_ip, udp.get_source());
+    let header_length = UdpPacket::minimum_packet_size();
+    let end = (udp.get_length() as usize).clamp(header_length, ip.bytes.len());
+    let payload = match ip.bytes {
+        Cow::Borrowed(bytes) => Cow::Borrowed(&bytes[header_length..end]),
+        Cow::Owned(bytes) => Cow::Owned(bytes[header_length..end].to_vec()),
+    };
+    Some((src, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const PAYLOAD: &[u8] = b"an sFlow datagram, more or less";
+
+    fn udp_datagram(payload: &[u8]) -> Vec<u8> {
+        let mut datagram = [50000u16.to_be_bytes(), 6343u16.to_be_bytes()].concat();
+        datagram.extend((8 + payload.len() as u16).to_be_bytes());
+        datagram.extend([0, 0]);
+        [datagram, payload.to_vec()].concat()
+    }
+
+    // An IPv4 packet from 192.0.2.1 with id 7, `offset` in bytes
+    fn ipv4(offset: usize, more_fragments: bool, payload: &[u8]) -> Vec<u8> {
+        let mut header = vec![0x45, 0];
+        header.extend((20 + payload.len() as u16).to_be_bytes());
+        header.extend(7u16.to_be_bytes());
+        let flags = if more_fragments { 0x2000 } else { 0 };
+        header.extend((flags | (offset / 8) as u16).to_be_bytes());
+        header.extend([64, 17, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2]);
+        [header, payload.to_vec()].concat()
+    }
+
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = vec![0x60, 0, 0, 0];
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend([next_header, 64]);
+        header.extend(source_v6().octets());
+        header.extend(Ipv6Addr::LOCALHOST.octets());
+        [header, payload.to_vec()].concat()
+    }
+
+    // An IPv6 fragment header for UDP with id 9, `offset` in bytes
+    fn ipv6_fragment(offset: usize, more_fragments: bool, payload: &[u8]) -> Vec<u8> {
+        let field = offset as u16 | more_fragments as u16;
+        let header = [vec![17, 0], field.to_be_bytes().to_vec(), words(9)].concat();
+        ipv6(44, &[header, payload.to_vec()].concat())
+    }
+
+    fn words(value: u32) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        [&[0xff; 12][..], &ethertype.to_be_bytes(), payload].concat()
+    }
+
+    fn source_v4() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 50000)
+    }
+
+    fn source_v6() -> Ipv6Addr {
+        "2001:db8::1".parse().unwrap()
+    }
+
+    fn decode(decoder: &mut FrameDecoder, frame: &[u8]) -> Option<(SocketAddr, Vec<u8>)> {
+        decoder
+            .decode(frame)
+            .map(|(source, payload)| (source, payload.into_owned()))
+    }
+
+    #[test]
+    fn decodes_ethernet_and_linux_cooked_frames() {
+        let packet = ipv4(0, false, &udp_datagram(PAYLOAD));
+        let expected = Some((source_v4(), PAYLOAD.to_vec()));
+
+        let mut decoder = FrameDecoder::new(Linktype::ETHERNET);
+        assert_eq!(decode(&mut decoder, &ethernet(0x0800, &packet)), expected);
+        // Ethernet pads short frames, which the IP total length leaves out
+        let padded = [ethernet(0x0800, &packet), vec![0; 16]].concat();
+        assert_eq!(decode(&mut decoder, &padded), expected);
+
+        let sll = [&[0; 14][..], &[0x08, 0x00], &packet].concat();
+        let mut decoder = FrameDecoder::new(Linktype::LINUX_SLL);
+        assert_eq!(decode(&mut decoder, &sll), expected);
+
+        let sll2 = [&[0x08, 0x00], &[0; 18][..], &packet].concat();
+        let mut decoder = FrameDecoder::new(Linktype::LINUX_SLL2);
+        assert_eq!(decode(&mut decoder, &sll2), expected);
+
+        let null = [&2u32.to_le_bytes()[..], &packet].concat();
+        let mut decoder = FrameDecoder::new(Linktype::NULL);
+        assert_eq!(decode(&mut decoder, &null), expected);
+    }
+
+    #[test]
+    fn skips_vlan_tags() {
+        let packet = ipv6(17, &udp_datagram(PAYLOAD));
+        let tags = [0x00, 0x64, 0x81, 0x00, 0x00, 0xc8, 0x86, 0xdd];
+        let frame = ethernet(0x88a8, &[&tags[..], &packet].concat());
+        let mut decoder = FrameDecoder::new(Linktype::ETHERNET);
+        let (source, payload) = decode(&mut decoder, &frame).unwrap();
+        assert_eq!(source, SocketAddr::new(IpAddr::V6(source_v6()), 50000));
+        assert_eq!(payload, PAYLOAD);
+    }
+
+    #[test]
+    fn skips_frames_that_are_not_udp() {
+        let mut decoder = FrameDecoder::new(Linktype::ETHERNET);
+        let mut tcp = ipv4(0, false, &udp_datagram(PAYLOAD));
+        tcp[9] = 6;
+        assert!(decode(&mut decoder, &ethernet(0x0800, &tcp)).is_none());
+        assert!(decode(&mut decoder, &ethernet(0x0806, &[0; 28])).is_none());
+        assert!(decode(&mut decoder, &[0; 10]).is_none());
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_overlapping_ipv4_fragments() {
+        let datagram = udp_datagram(&[PAYLOAD; 4].concat());
+        let mut decoder = FrameDecoder::new(Linktype::RAW);
+        // the last fragment first, then the second, which overlaps the
+        // first by eight bytes
+        let last = ipv4(64, false, &datagram[64..]);
+        let second = ipv4(24, true, &datagram[24..64]);
+        let first = ipv4(0, true, &datagram[..32]);
+        assert!(decode(&mut decoder, &last).is_none());
+        assert!(decode(&mut decoder, &second).is_none());
+        let (source, payload) = decode(&mut decoder, &first).unwrap();
+        assert_eq!(source, source_v4());
+        assert_eq!(payload, [PAYLOAD; 4].concat());
+        assert!(decoder.fragments.is_empty());
+    }
+
+    #[test]
+    fn reassembles_ipv6_fragments() {
+        let datagram = udp_datagram(&[PAYLOAD; 2].concat());
+        let mut decoder = FrameDecoder::new(Linktype::RAW);
+        assert!(decode(&mut decoder, &ipv6_fragment(0, true, &datagram[..32])).is_none());
+        let (source, payload) =
+            decode(&mut decoder, &ipv6_fragment(32, false, &datagram[32..])).unwrap();
+        assert_eq!(source.ip(), IpAddr::V6(source_v6()));
+        assert_eq!(payload, [PAYLOAD; 2].concat());
+
+        // an atomic fragment is a whole datagram on its own
+        let (_, payload) = decode(
+            &mut decoder,
+            &ipv6_fragment(0, false, &udp_datagram(PAYLOAD)),
+        )
+        .unwrap();
+        assert_eq!(payload, PAYLOAD);
+    }
+
+    #[test]
+    fn drops_fragments_past_the_largest_datagram() {
+        let mut decoder = FrameDecoder::new(Linktype::RAW);
+        // the last fragment would end the datagram 8 bytes past the limit
+        let last = ipv4(65528, false, &[0; 15]);
+        assert!(decode(&mut decoder, &last).is_none());
+        assert!(decoder.fragments.is_empty());
+
+        // the same offset over and over with new data is never kept twice
+        for _ in 0..100 {
+            let part = ipv4(0, true, &[0; 1024]);
+            assert!(decode(&mut decoder, &part).is_none());
+        }
+        assert_eq!(decoder.fragments.len(), 1);
+
+        // overlapping fragments at distinct offsets are dropped together once
+        // they hold more than the largest datagram, the 63rd 1 KiB one here
+        for (i, offset) in (8..).step_by(512).take(63).enumerate() {
+            let part = ipv4(offset, true, &[0; 1024]);
+            assert!(decode(&mut decoder, &part).is_none());
+            assert_eq!(decoder.fragments.is_empty(), i == 62);
+        }
+    }
+}
@@ -0,0 +1,545 @@
+use crate::expiry::{EvictionPolicy, Limits};
+use crate::queue::OverflowPolicy;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::{
+    fmt::{Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "sFlow collector")]
+pub struct Args {
+    /// TOML configuration file
+    #[arg(short, long, env = "OXYFLOW_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Where datagrams are read from
+    #[arg(long, env = "OXYFLOW_RECEIVER")]
+    pub receiver: Option<ReceiverType>,
+    /// UDP bind addresses
+    #[arg(long, env = "OXYFLOW_BIND", value_delimiter = ',')]
+    pub bind: Option<Vec<SocketAddr>>,
+    /// SO_REUSEPORT sockets opened per bind address
+    #[arg(long, env = "OXYFLOW_SOCKETS")]
+    pub sockets: Option<usize>,
+    /// Socket receive buffer size in bytes
+    #[arg(long, env = "OXYFLOW_RCVBUF")]
+    pub rcvbuf: Option<usize>,
+    /// Capture interface for the pcap receiver
+    #[arg(long, env = "OXYFLOW_INTERFACE")]
+    pub interface: Option<String>,
+    /// BPF filter for the pcap receiver
+    #[arg(long, env = "OXYFLOW_FILTER")]
+    pub filter: Option<String>,
+    /// Capture snaplen for the pcap receiver
+    #[arg(long, env = "OXYFLOW_SNAPLEN")]
+    pub snaplen: Option<i32>,
+    /// Capture file replayed by the file receiver
+    #[arg(long, env = "OXYFLOW_FILE")]
+    pub file: Option<PathBuf>,
+    /// How fast the file receiver replays the capture
+    #[arg(long, env = "OXYFLOW_REPLAY")]
+    pub replay: Option<ReplaySpeed>,
+    /// Print the aggregates and exit once the capture file is exhausted
+    #[arg(long, env = "OXYFLOW_EXIT_ON_EOF")]
+    pub exit_on_eof: bool,
+    /// Threads decoding datagrams
+    #[arg(long, env = "OXYFLOW_WORKERS")]
+    pub workers: Option<usize>,
+    /// Datagrams each worker's queue holds
+    #[arg(long, env = "OXYFLOW_QUEUE_SIZE")]
+    pub queue_size: Option<usize>,
+    /// What receivers do when a worker's queue is full
+    #[arg(long, env = "OXYFLOW_OVERFLOW")]
+    pub overflow: Option<OverflowPolicy>,
+    /// Length of the flow aggregation windows in seconds
+    #[arg(long, env = "OXYFLOW_WINDOW_INTERVAL")]
+    pub window_interval: Option<u64>,
+    /// Closed flow aggregation windows kept in memory
+    #[arg(long, env = "OXYFLOW_WINDOW_HISTORY")]
+    pub window_history: Option<usize>,
+    /// Seconds without traffic after which an entry is evicted
+    #[arg(long, env = "OXYFLOW_IDLE_TIMEOUT")]
+    pub idle_timeout: Option<u64>,
+    /// Seconds after which an entry is evicted even if it is still active.
+    /// Windowed maps leave this to the window interval.
+    #[arg(long, env = "OXYFLOW_ACTIVE_TIMEOUT")]
+    pub active_timeout: Option<u64>,
+    /// Entries each aggregation map may hold
+    #[arg(long, env = "OXYFLOW_MAX_ENTRIES")]
+    pub max_entries: Option<usize>,
+    /// Which entries go first when a map is full
+    #[arg(long, env = "OXYFLOW_EVICTION")]
+    pub eviction: Option<EvictionPolicy>,
+    /// Address the HTTP metrics server listens on
+    #[arg(long, env = "OXYFLOW_HTTP_LISTEN")]
+    pub http_listen: Option<SocketAddr>,
+    /// Series per metric family on the Prometheus endpoint
+    #[arg(long, env = "OXYFLOW_MAX_SERIES")]
+    pub max_series: Option<usize>,
+    /// OpenTelemetry collector metrics are pushed to, e.g. http://localhost:4317
+    #[arg(long, env = "OXYFLOW_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// Transport used to push metrics to the OpenTelemetry collector
+    #[arg(long, env = "OXYFLOW_OTLP_PROTOCOL")]
+    pub otlp_protocol: Option<OtlpProtocol>,
+    /// Seconds between pushes to the OpenTelemetry collector
+    #[arg(long, env = "OXYFLOW_OTLP_INTERVAL")]
+    pub otlp_interval: Option<u64>,
+    /// Collectors sampled flows are re-exported to as IPFIX
+    #[arg(long, env = "OXYFLOW_EXPORT_IPFIX", value_delimiter = ',')]
+    pub export_ipfix: Option<Vec<SocketAddr>>,
+    /// Collectors sampled flows are re-exported to as NetFlow v9
+    #[arg(long, env = "OXYFLOW_EXPORT_NETFLOW9", value_delimiter = ',')]
+    pub export_netflow9: Option<Vec<SocketAddr>>,
+    /// Downstream collectors every received datagram is re-sent to
+    #[arg(long, env = "OXYFLOW_REPLICATE", value_delimiter = ',')]
+    pub replicate: Option<Vec<SocketAddr>>,
+    /// Collectors to run
+    #[arg(long, env = "OXYFLOW_COLLECTORS", value_delimiter = ',')]
+    pub collectors: Option<Vec<CollectorKind>>,
+    /// Keys each top-N summary keeps counters for
+    #[arg(long, env = "OXYFLOW_TOP_CAPACITY")]
+    pub top_capacity: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiverType {
+    Udp,
+    Pcap,
+    File,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplaySpeed {
+    /// As fast as the pipeline accepts datagrams
+    Fast,
+    /// Honouring the gaps between the original capture timestamps
+    Realtime,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OtlpProtocol {
+    /// OTLP/gRPC, usually on port 4317
+    Grpc,
+    /// OTLP/HTTP with protobuf payloads, usually on port 4318
+    Http,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CollectorKind {
+    Flow,
+    Ipflow,
+    Tunnel,
+    Asmatrix,
+    Interface,
+    Top,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub receiver: ReceiverConfig,
+    pub decoder: DecoderConfig,
+    pub windows: WindowConfig,
+    pub expiry: ExpiryConfig,
+    pub http: HttpConfig,
+    pub otlp: OtlpConfig,
+    pub export: ExportConfig,
+    pub replicate: Vec<ReplicaConfig>,
+    pub collectors: CollectorConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReceiverConfig {
+    #[serde(rename = "type")]
+    pub receiver_type: ReceiverType,
+    pub bind: Vec<SocketAddr>,
+    pub sockets: usize,
+    pub rcvbuf: Option<usize>,
+    pub interface: String,
+    pub filter: String,
+    pub snaplen: i32,
+    pub immediate_mode: bool,
+    pub file: Option<PathBuf>,
+    pub replay: ReplaySpeed,
+    pub exit_on_eof: bool,
+}
+
+impl Default for ReceiverConfig {
+    fn default() -> Self {
+        Self {
+            receiver_type: ReceiverType::Pcap,
+            bind: vec![([0, 0, 0, 0], 6343).into()],
+            sockets: 1,
+            rcvbuf: None,
+            interface: "any".to_string(),
+            filter: "udp dst port 6343".to_string(),
+            snaplen: 9000,
+            immediate_mode: true,
+            file: None,
+            replay: ReplaySpeed::Fast,
+            exit_on_eof: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecoderConfig {
+    /// Datagrams are spread across workers by exporter address, so each
+    /// exporter is still decoded in order.
+    pub workers: usize,
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for DecoderConfig {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            queue_size: 1024,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+/// Tumbling windows the flow and ipflow collectors also aggregate into.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub interval: u64, // seconds
+    pub history: usize,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            history: 60,
+        }
+    }
+}
+
+/// Bounds on every aggregation map, checked on insert for the entry cap and
+/// every `sweep_interval` seconds for the timeouts.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExpiryConfig {
+    pub idle_timeout: Option<u64>, // seconds
+    pub active_timeout: Option<u64>,
+    pub max_entries: usize,
+    pub eviction: EvictionPolicy,
+    pub sweep_interval: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Some(3600),
+            active_timeout: None,
+            max_entries: 1_000_000,
+            eviction: EvictionPolicy::Lru,
+            sweep_interval: 10,
+        }
+    }
+}
+
+impl ExpiryConfig {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            idle_timeout: self.idle_timeout.map(Duration::from_secs),
+            active_timeout: self.active_timeout.map(Duration::from_secs),
+            max_entries: self.max_entries,
+            eviction: self.eviction,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub listen: SocketAddr,
+    /// Beyond this many series per family on /metrics, the lightest ones are
+    /// summed into a single series labelled "other".
+    pub max_series: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            listen: ([0, 0, 0, 0], 3030).into(),
+            max_series: 1000,
+        }
+    }
+}
+
+/// Pushing to an OpenTelemetry collector is off unless an endpoint is set.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtlpConfig {
+    pub endpoint: Option<String>,
+    pub protocol: OtlpProtocol,
+    pub interval: u64, // seconds
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            protocol: OtlpProtocol::Grpc,
+            interval: 60,
+        }
+    }
+}
+
+/// Re-exporting sampled flows as IPFIX and NetFlow v9 is off unless a
+/// collector is listed.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    pub ipfix: Vec<SocketAddr>,
+    pub netflow9: Vec<SocketAddr>,
+    /// IPFIX observation domain and NetFlow v9 source id
+    pub domain_id: u32,
+    /// Seconds between template and sampling option refreshes
+    pub template_interval: u64,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            ipfix: Vec::new(),
+            netflow9: Vec::new(),
+            domain_id: 0,
+            template_interval: 60,
+        }
+    }
+}
+
+/// A downstream collector received datagrams are re-sent to. Sending from
+/// another source address than ours takes a raw socket, and CAP_NET_RAW.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicaConfig {
+    pub destination: SocketAddr,
+    /// Send from the exporter's address and port
+    #[serde(default)]
+    pub preserve_source: bool,
+    /// Send from this address, with the exporter's port
+    #[serde(default)]
+    pub spoof_source: Option<IpAddr>,
+    /// Only datagrams from these agents, or from all when empty
+    #[serde(default)]
+    pub agents: Vec<IpAddr>,
+    /// Only these sample types, or all when empty
+    #[serde(default)]
+    pub sample_types: Vec<u32>,
+    /// Keep one in this many flow samples
+    #[serde(default)]
+    pub sample_down: Option<u32>,
+}
+
+impl From<SocketAddr> for ReplicaConfig {
+    fn from(destination: SocketAddr) -> Self {
+        Self {
+            destination,
+            preserve_source: false,
+            spoof_source: None,
+            agents: Vec::new(),
+            sample_types: Vec::new(),
+            sample_down: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollectorConfig {
+    pub enabled: Vec<CollectorKind>,
+    /// Counters per top-N summary. Keys carrying more than 1/top_capacity of
+    /// the traffic are always listed.
+    pub top_capacity: usize,
+}
+
+impl Default for CollectorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: CollectorKind::value_variants().to_vec(),
+            top_capacity: 1000,
+        }
+    }
+}
+
+impl CollectorConfig {
+    pub fn is_enabled(&self, kind: CollectorKind) -> bool {
+        self.enabled.contains(&kind)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(&'static str),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from, in increasing order of precedence, the
+    /// defaults, the TOML file, environment variables and command line flags.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.receiver.receiver_type == ReceiverType::File && self.receiver.file.is_none() {
+            return Err(ConfigError::Invalid(
+                "the file receiver needs a capture file",
+            ));
+        }
+        if self.receiver.sockets == 0 {
+            return Err(ConfigError::Invalid("sockets must be at least 1"));
+        }
+        if self.decoder.workers == 0 {
+            return Err(ConfigError::Invalid("workers must be at least 1"));
+        }
+        // a queue of 0 hands each datagram over only if a worker is waiting
+        if self.decoder.queue_size == 0 {
+            return Err(ConfigError::Invalid("queue_size must be at least 1"));
+        }
+        // an interval of 0 would spin the sweeper, and a map capped at 0
+        // entries would evict everything it is given
+        if self.expiry.sweep_interval == 0 {
+            return Err(ConfigError::Invalid("sweep_interval must be at least 1"));
+        }
+        if self.expiry.max_entries == 0 {
+            return Err(ConfigError::Invalid("max_entries must be at least 1"));
+        }
+        // a summary without counters has nowhere to put the first key
+        if self.collectors.top_capacity == 0 {
+            return Err(ConfigError::Invalid("top_capacity must be at least 1"));
+        }
+        Ok(())
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))
+    }
+
+    fn apply(&mut self, args: Args) {
+        let receiver = &mut self.receiver;
+        if let Some(receiver_type) = args.receiver {
+            receiver.receiver_type = receiver_type;
+        }
+        if let Some(bind) = args.bind {
+            receiver.bind = bind;
+        }
+        if let Some(sockets) = args.sockets {
+            receiver.sockets = sockets;
+        }
+        if let Some(rcvbuf) = args.rcvbuf {
+            receiver.rcvbuf = Some(rcvbuf);
+        }
+        if let Some(interface) = args.interface {
+            receiver.interface = interface;
+        }
+        if let Some(filter) = args.filter {
+            receiver.filter = filter;
+        }
+        if let Some(snaplen) = args.snaplen {
+            receiver.snaplen = snaplen;
+        }
+        if let Some(file) = args.file {
+            receiver.file = Some(file);
+        }
+        if let Some(replay) = args.replay {
+            receiver.replay = replay;
+        }
+        if args.exit_on_eof {
+            receiver.exit_on_eof = true;
+        }
+        if let Some(workers) = args.workers {
+            self.decoder.workers = workers;
+        }
+        if let Some(queue_size) = args.queue_size {
+            self.decoder.queue_size = queue_size;
+        }
+        if let Some(overflow) = args.overflow {
+            self.decoder.overflow = overflow;
+        }
+        if let Some(interval) = args.window_interval {
+            self.windows.interval = interval;
+        }
+        if let Some(history) = args.window_history {
+            self.windows.history = history;
+        }
+        if let Some(idle_timeout) = args.idle_timeout {
+            self.expiry.idle_timeout = Some(idle_timeout);
+        }
+        if let Some(active_timeout) = args.active_timeout {
+            self.expiry.active_timeout = Some(active_timeout);
+        }
+        if let Some(max_entries) = args.max_entries {
+            self.expiry.max_entries = max_entries;
+        }
+        if let Some(eviction) = args.eviction {
+            self.expiry.eviction = eviction;
+        }
+        if let Some(listen) = args.http_listen {
+            self.http.listen = listen;
+        }
+        if let Some(max_series) = args.max_series {
+            self.http.max_series = max_series;
+        }
+        if let Some(endpoint) = args.otlp_endpoint {
+            self.otlp.endpoint = Some(endpoint);
+        }
+        if let Some(protocol) = args.otlp_protocol {
+            self.otlp.protocol = protocol;
+        }
+        if let Some(interval) = args.otlp_interval {
+            self.otlp.interval = interval;
+        }
+        if let Some(ipfix) = args.export_ipfix {
+            self.export.ipfix = ipfix;
+        }
+        if let Some(netflow9) = args.export_netflow9 {
+            self.export.netflow9 = netflow9;
+        }
+        if let Some(replicate) = args.replicate {
+            self.replicate = replicate.into_iter().map(ReplicaConfig::from).collect();
+        }
+        if let Some(enabled) = args.collectors {
+            self.collectors.enabled = enabled;
+        }
+        if let Some(top_capacity) = args.top_capacity {
+            self.collectors.top_capacity = top_capacity;
+        }
+    }
+}
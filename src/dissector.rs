@@ -0,0 +1,676 @@
+use byteorder::{BigEndian, ByteOrder};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::ipv6::Ipv6Packet;
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::vlan::VlanPacket;
+use pnet::util::MacAddr;
+use serde::Serialize;
+use std::net::IpAddr;
+
+// sFlow header_protocol values we know how to dissect
+pub const HEADER_ETHERNET: u32 = 1;
+pub const HEADER_IPV4: u32 = 11;
+pub const HEADER_IPV6: u32 = 12;
+
+const ETHERTYPE_QINQ: EtherType = EtherType(0x88a8);
+const ETHERTYPE_QINQ_LEGACY: EtherType = EtherType(0x9100);
+const ETHERTYPE_TRANSPARENT_BRIDGING: u16 = 0x6558;
+
+const VXLAN_PORT: u16 = 4789;
+const GENEVE_PORT: u16 = 6081;
+
+// Tunnels nested deeper than this are left undecoded
+const MAX_TUNNEL_DEPTH: usize = 2;
+
+#[derive(Debug, Default)]
+pub struct DecodedHeader {
+    pub src_mac: Option<MacAddr>,
+    pub dst_mac: Option<MacAddr>,
+    pub vlans: Vec<u16>,        // outermost tag first
+    pub ethertype: Option<u16>, // after any VLAN tags
+    pub mpls_labels: Vec<u32>,  // top of the stack first
+    pub ip: Option<IpHeader>,
+    pub transport: Option<TransportHeader>,
+    pub tunnel: Option<Tunnel>,
+    depth: usize,
+}
+
+#[derive(Debug)]
+pub struct IpHeader {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: u8, // upper layer protocol, past any IPv6 extension headers
+}
+
+#[derive(Debug)]
+pub enum TransportHeader {
+    Tcp {
+        src_port: u16,
+        dst_port: u16,
+        flags: u8,
+    },
+    Udp {
+        src_port: u16,
+        dst_port: u16,
+    },
+    Icmp {
+        icmp_type: u8,
+        icmp_code: u8,
+    },
+}
+
+#[derive(Eq, Hash, PartialEq, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelType {
+    Vxlan,
+    Geneve,
+    Gre,
+    Nvgre,
+    IpInIp,
+}
+
+/// An encapsulation found in the outer packet, with the dissected inner one.
+#[derive(Debug)]
+pub struct Tunnel {
+    pub tunnel_type: TunnelType,
+    pub vni: Option<u32>, // VXLAN/Geneve VNI, NVGRE VSID or GRE key
+    pub inner: Box<DecodedHeader>,
+}
+
+/// Dissects a sampled packet header layer by layer. Agents cut headers short,
+/// so decoding stops quietly at the first layer that doesn't fit and the
+/// deeper fields are left empty.
+pub fn dissect(header_protocol: u32, bytes: &[u8]) -> DecodedHeader {
+    let mut header = DecodedHeader::default();
+    match header_protocol {
+        HEADER_ETHERNET => header.ethernet(bytes),
+        HEADER_IPV4 => header.ipv4(bytes),
+        HEADER_IPV6 => header.ipv6(bytes),
+        _ => {}
+    }
+    header
+}
+
+impl DecodedHeader {
+    fn ethernet(&mut self, bytes: &[u8]) {
+        let Some(ethernet) = EthernetPacket::new(bytes) else {
+            return;
+        };
+        self.src_mac = Some(ethernet.get_source());
+        self.dst_mac = Some(ethernet.get_destination());
+
+        let mut ethertype = ethernet.get_ethertype();
+        let mut bytes = &bytes[EthernetPacket::minimum_packet_size()..];
+        while matches!(
+            ethertype,
+            EtherTypes::Vlan | ETHERTYPE_QINQ | ETHERTYPE_QINQ_LEGACY
+        ) {
+            let Some(tag) = VlanPacket::new(bytes) else {
+                return;
+            };
+            self.vlans.push(tag.get_vlan_identifier());
+            ethertype = tag.get_ethertype();
+            bytes = &bytes[VlanPacket::minimum_packet_size()..];
+        }
+        self.ethertype = Some(ethertype.0);
+        self.network(ethertype, bytes);
+    }
+
+    fn network(&mut self, ethertype: EtherType, bytes: &[u8]) {
+        match ethertype {
+            EtherTypes::Ipv4 => self.ipv4(bytes),
+            EtherTypes::Ipv6 => self.ipv6(bytes),
+            EtherTypes::Mpls | EtherTypes::MplsMcast => self.mpls(bytes),
+            _ => {}
+        }
+    }
+
+    fn mpls(&mut self, mut bytes: &[u8]) {
+        loop {
+            let Some(entry) = bytes.get(..4) else {
+                return;
+            };
+            let entry = BigEndian::read_u32(entry);
+            self.mpls_labels.push(entry >> 12);
+            bytes = &bytes[4..];
+            if entry & 0x100 != 0 {
+                break; // bottom of stack
+            }
+        }
+        // the label stack doesn't say what it carries, so go by the IP version
+        match bytes.first().map(|b| b >> 4) {
+            Some(4) => self.ipv4(bytes),
+            Some(6) => self.ipv6(bytes),
+            _ => {}
+        }
+    }
+
+    fn ipv4(&mut self, bytes: &[u8]) {
+        let Some(ipv4) = Ipv4Packet::new(bytes) else {
+            return;
+        };
+        let protocol = ipv4.get_next_level_protocol();
+        self.ip = Some(IpHeader {
+            src_ip: IpAddr::V4(ipv4.get_source()),
+            dst_ip: IpAddr::V4(ipv4.get_destination()),
+            protocol: protocol.0,
+        });
+        // only the first fragment carries the transport header
+        if ipv4.get_fragment_offset() == 0 {
+            if let Some(payload) = bytes.get(ipv4.get_header_length() as usize * 4..) {
+                self.transport(protocol, payload);
+            }
+        }
+    }
+
+    fn ipv6(&mut self, bytes: &[u8]) {
+        let Some(ipv6) = Ipv6Packet::new(bytes) else {
+            return;
+        };
+        let mut protocol = ipv6.get_next_header();
+        let mut offset = Ipv6Packet::minimum_packet_size();
+        let mut fragment_offset = 0;
+        let mut complete = true;
+
+        // walk the extension header chain down to the upper layer protocol
+        while matches!(
+            protocol,
+            IpNextHeaderProtocols::Hopopt
+                | IpNextHeaderProtocols::Ipv6Route
+                | IpNextHeaderProtocols::Ipv6Frag
+                | IpNextHeaderProtocols::Ipv6Opts
+                | IpNextHeaderProtocols::Ah
+        ) {
+            let (Some(&next), Some(&length)) = (bytes.get(offset), bytes.get(offset + 1)) else {
+                complete = false;
+                break;
+            };
+            let length = match protocol {
+                IpNextHeaderProtocols::Ipv6Frag => {
+                    fragment_offset = bytes
+                        .get(offset + 2..offset + 4)
+                        .map(|field| BigEndian::read_u16(field) >> 3)
+                        .unwrap_or(0);
+                    8
+                }
+                IpNextHeaderProtocols::Ah => (length as usize + 2) * 4,
+                _ => (length as usize + 1) * 8,
+            };
+            protocol = IpNextHeaderProtocol(next);
+            offset += length;
+        }
+
+        self.ip = Some(IpHeader {
+            src_ip: IpAddr::V6(ipv6.get_source()),
+            dst_ip: IpAddr::V6(ipv6.get_destination()),
+            protocol: protocol.0,
+        });
+        if complete && fragment_offset == 0 {
+            if let Some(payload) = bytes.get(offset..) {
+                self.transport(protocol, payload);
+            }
+        }
+    }
+
+    fn transport(&mut self, protocol: IpNextHeaderProtocol, bytes: &[u8]) {
+        match protocol {
+            IpNextHeaderProtocols::Tcp => {
+                self.transport = TcpPacket::new(bytes).map(|tcp| TransportHeader::Tcp {
+                    src_port: tcp.get_source(),
+                    dst_port: tcp.get_destination(),
+                    flags: tcp.get_flags(),
+                });
+            }
+            IpNextHeaderProtocols::Udp => {
+                let Some(udp) = UdpPacket::new(bytes) else {
+                    return;
+                };
+                self.transport = Some(TransportHeader::Udp {
+                    src_port: udp.get_source(),
+                    dst_port: udp.get_destination(),
+                });
+                let payload = &bytes[UdpPacket::minimum_packet_size()..];
+                match udp.get_destination() {
+                    VXLAN_PORT => self.vxlan(payload),
+                    GENEVE_PORT => self.geneve(payload),
+                    _ => {}
+                }
+            }
+            IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
+                self.transport = bytes.get(..2).map(|icmp| TransportHeader::Icmp {
+                    icmp_type: icmp[0],
+                    icmp_code: icmp[1],
+                });
+            }
+            IpNextHeaderProtocols::Gre => self.gre(bytes),
+            IpNextHeaderProtocols::Ipv4 => {
+                self.decapsulate(TunnelType::IpInIp, None, |inner| inner.ipv4(bytes))
+            }
+            IpNextHeaderProtocols::Ipv6 => {
+                self.decapsulate(TunnelType::IpInIp, None, |inner| inner.ipv6(bytes))
+            }
+            _ => {}
+        }
+    }
+
+    fn vxlan(&mut self, bytes: &[u8]) {
+        // the I flag marks a valid VNI
+        let Some(header) = bytes.get(..8).filter(|header| header[0] & 0x08 != 0) else {
+            return;
+        };
+        let vni = BigEndian::read_u32(&header[4..8]) >> 8;
+        self.decapsulate(TunnelType::Vxlan, Some(vni), |inner| {
+            inner.ethernet(&bytes[8..])
+        });
+    }
+
+    fn geneve(&mut self, bytes: &[u8]) {
+        let Some(header) = bytes.get(..8) else {
+            return;
+        };
+        let options_length = (header[0] & 0x3f) as usize * 4;
+        let protocol_type = BigEndian::read_u16(&header[2..4]);
+        let vni = BigEndian::read_u32(&header[4..8]) >> 8;
+        let Some(payload) = bytes.get(8 + options_length..) else {
+            return;
+        };
+        self.decapsulate(TunnelType::Geneve, Some(vni), |inner| {
+            inner.encapsulated(protocol_type, payload)
+        });
+    }
+
+    fn gre(&mut self, bytes: &[u8]) {
+        let Some(header) = bytes.get(..4) else {
+            return;
+        };
+        let flags = BigEndian::read_u16(&header[0..2]);
+        let protocol_type = BigEndian::read_u16(&header[2..4]);
+        if flags & 0x0007 != 0 {
+            return; // enhanced GRE (PPTP) carries PPP, not something we can key on
+        }
+        let mut offset = 4;
+        if flags & 0x8000 != 0 {
+            offset += 4; // checksum
+        }
+        let mut key = None;
+        if flags & 0x2000 != 0 {
+            let Some(field) = bytes.get(offset..offset + 4) else {
+                return;
+            };
+            key = Some(BigEndian::read_u32(field));
+            offset += 4;
+        }
+        if flags & 0x1000 != 0 {
+            offset += 4; // sequence number
+        }
+        let Some(payload) = bytes.get(offset..) else {
+            return;
+        };
+        // NVGRE is bridged GRE with the VSID in the top 24 bits of the key
+        let (tunnel_type, vni) = match key {
+            Some(key) if protocol_type == ETHERTYPE_TRANSPARENT_BRIDGING => {
+                (TunnelType::Nvgre, Some(key >> 8))
+            }
+            key => (TunnelType::Gre, key),
+        };
+        self.decapsulate(tunnel_type, vni, |inner| {
+            inner.encapsulated(protocol_type, payload)
+        });
+    }
+
+    fn encapsulated(&mut self, protocol_type: u16, bytes: &[u8]) {
+        match protocol_type {
+            ETHERTYPE_TRANSPARENT_BRIDGING => self.ethernet(bytes),
+            ethertype => self.network(EtherType(ethertype), bytes),
+        }
+    }
+
+    fn decapsulate(
+        &mut self,
+        tunnel_type: TunnelType,
+        vni: Option<u32>,
+        dissect_inner: impl FnOnce(&mut DecodedHeader),
+    ) {
+        if self.depth >= MAX_TUNNEL_DEPTH {
+            return;
+        }
+        let mut inner = DecodedHeader {
+            depth: self.depth + 1,
+            ..Default::default()
+        };
+        dissect_inner(&mut inner);
+        self.tunnel = Some(Tunnel {
+            tunnel_type,
+            vni,
+            inner: Box::new(inner),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const SRC_MAC: [u8; 6] = [0, 1, 2, 3, 4, 5];
+    const DST_MAC: [u8; 6] = [0, 1, 2, 3, 4, 6];
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        [&DST_MAC[..], &SRC_MAC, &ethertype.to_be_bytes(), payload].concat()
+    }
+
+    fn vlan_tag(vlan: u16, ethertype: u16) -> Vec<u8> {
+        [vlan.to_be_bytes(), ethertype.to_be_bytes()].concat()
+    }
+
+    fn ipv4(protocol: u8, fragment_offset: u16, payload: &[u8]) -> Vec<u8> {
+        let length = 20 + payload.len() as u16;
+        let mut header = vec![0x45, 0];
+        header.extend(length.to_be_bytes());
+        header.extend([0, 0]);
+        header.extend(fragment_offset.to_be_bytes());
+        header.extend([64, protocol, 0, 0, 10, 0, 0, 1, 10, 0, 0, 2]);
+        [header, payload.to_vec()].concat()
+    }
+
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = vec![0x60, 0, 0, 0];
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend([next_header, 64]);
+        header.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        header.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        [header, payload.to_vec()].concat()
+    }
+
+    fn tcp(src_port: u16, dst_port: u16, flags: u8) -> Vec<u8> {
+        let mut header = [src_port.to_be_bytes(), dst_port.to_be_bytes()].concat();
+        header.extend([0; 8]);
+        header.extend([0x50, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        header
+    }
+
+    fn udp(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let length = 8 + payload.len() as u16;
+        let mut header = [src_port.to_be_bytes(), dst_port.to_be_bytes()].concat();
+        header.extend(length.to_be_bytes());
+        header.extend([0, 0]);
+        [header, payload.to_vec()].concat()
+    }
+
+    fn ports(header: &DecodedHeader) -> Option<(u16, u16)> {
+        match header.transport {
+            Some(TransportHeader::Tcp {
+                src_port, dst_port, ..
+            })
+            | Some(TransportHeader::Udp { src_port, dst_port }) => Some((src_port, dst_port)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn reads_every_vlan_tag_of_a_qinq_frame() {
+        let ip = ipv4(6, 0, &tcp(40000, 443, 0x12));
+        let frame = ethernet(
+            0x88a8,
+            &[vlan_tag(100, 0x8100), vlan_tag(200, 0x0800), ip].concat(),
+        );
+        let header = dissect(HEADER_ETHERNET, &frame);
+        assert_eq!(header.src_mac, Some(MacAddr::from(SRC_MAC)));
+        assert_eq!(header.dst_mac, Some(MacAddr::from(DST_MAC)));
+        assert_eq!(header.vlans, [100, 200]);
+        assert_eq!(header.ethertype, Some(0x0800));
+        let ip = header.ip.as_ref().unwrap();
+        assert_eq!(ip.src_ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(ip.protocol, 6);
+        assert!(matches!(
+            header.transport,
+            Some(TransportHeader::Tcp {
+                src_port: 40000,
+                dst_port: 443,
+                flags: 0x12,
+            })
+        ));
+    }
+
+    #[test]
+    fn walks_an_mpls_label_stack_down_to_ip() {
+        // labels 16 and 1000, the second at the bottom of the stack
+        let stack = [
+            (16u32 << 12).to_be_bytes(),
+            ((1000u32 << 12) | 0x100).to_be_bytes(),
+        ];
+        let frame = ethernet(
+            0x8847,
+            &[stack.concat(), ipv6(17, &udp(53, 5353, &[]))].concat(),
+        );
+        let header = dissect(HEADER_ETHERNET, &frame);
+        assert_eq!(header.ethertype, Some(0x8847));
+        assert_eq!(header.mpls_labels, [16, 1000]);
+        assert_eq!(header.ip.as_ref().unwrap().protocol, 17);
+        assert_eq!(ports(&header), Some((53, 5353)));
+
+        // a stack cut short before its bottom entry carries no IP
+        let header = dissect(HEADER_ETHERNET, &frame[..18]);
+        assert_eq!(header.mpls_labels, [16]);
+        assert!(header.ip.is_none());
+    }
+
+    #[test]
+    fn walks_ipv6_extension_headers_to_the_upper_layer() {
+        // hop-by-hop options (8 bytes), then a routing header (16 bytes)
+        let mut extensions = vec![43, 0, 0, 0, 0, 0, 0, 0];
+        extensions.extend([6, 1, 0, 0, 0, 0, 0, 0]);
+        extensions.extend([0; 8]);
+        let packet = ipv6(0, &[extensions, tcp(22, 50000, 0x18)].concat());
+        let header = dissect(HEADER_IPV6, &packet);
+        assert_eq!(header.ip.as_ref().unwrap().protocol, 6);
+        assert_eq!(ports(&header), Some((22, 50000)));
+
+        // the headers run past the sampled bytes, so the upper layer is unknown
+        let header = dissect(HEADER_IPV6, &packet[..44]);
+        assert_eq!(header.ip.as_ref().unwrap().protocol, 43);
+        assert!(header.transport.is_none());
+    }
+
+    #[test]
+    fn only_the_first_fragment_has_ports() {
+        // an IPv6 fragment header at offset 0, then at offset 1480
+        let first = ipv6(
+            44,
+            &[vec![17, 0, 0, 1, 0, 0, 0, 1], udp(500, 4500, &[])].concat(),
+        );
+        let header = dissect(HEADER_IPV6, &first);
+        assert_eq!(header.ip.as_ref().unwrap().protocol, 17);
+        assert_eq!(ports(&header), Some((500, 4500)));
+
+        let later = ipv6(
+            44,
+            &[vec![17, 0, 0x2e, 0x41, 0, 0, 0, 1], vec![0; 8]].concat(),
+        );
+        let header = dissect(HEADER_IPV6, &later);
+        assert_eq!(header.ip.as_ref().unwrap().protocol, 17);
+        assert!(header.transport.is_none());
+
+        let later = ipv4(17, 185, &udp(500, 4500, &[]));
+        let header = dissect(HEADER_IPV4, &later);
+        assert_eq!(header.ip.as_ref().unwrap().protocol, 17);
+        assert!(header.transport.is_none());
+    }
+
+    #[test]
+    fn reads_icmp_type_and_code() {
+        let header = dissect(HEADER_IPV4, &ipv4(1, 0, &[3, 4, 0, 0]));
+        assert!(matches!(
+            header.transport,
+            Some(TransportHeader::Icmp {
+                icmp_type: 3,
+                icmp_code: 4,
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_headers_leave_the_deeper_layers_empty() {
+        let ip = ipv4(6, 0, &tcp(40000, 443, 0x02));
+        let frame = ethernet(0x8100, &[vlan_tag(100, 0x0800), ip].concat());
+        for length in 0..frame.len() {
+            let header = dissect(HEADER_ETHERNET, &frame[..length]);
+            if length < 14 {
+                assert!(header.src_mac.is_none());
+            }
+            if length < 18 {
+                assert!(header.vlans.is_empty() && header.ethertype.is_none());
+            }
+            if length < 38 {
+                assert!(header.ip.is_none());
+            }
+            if length < 58 {
+                assert!(header.transport.is_none());
+            }
+        }
+        assert!(dissect(HEADER_ETHERNET, &frame).transport.is_some());
+
+        for protocol in [HEADER_IPV4, HEADER_IPV6, 2] {
+            let header = dissect(protocol, &[0x45, 0, 0]);
+            assert!(header.ip.is_none() && header.transport.is_none());
+        }
+    }
+
+    fn vxlan(vni: u32, frame: &[u8]) -> Vec<u8> {
+        let header = [[0x08, 0, 0, 0], (vni << 8).to_be_bytes()].concat();
+        udp(50000, VXLAN_PORT, &[header, frame.to_vec()].concat())
+    }
+
+    fn inner_frame() -> Vec<u8> {
+        ethernet(0x0800, &ipv4(17, 0, &udp(1234, 53, &[])))
+    }
+
+    fn tunnel_of(header: &DecodedHeader) -> &Tunnel {
+        header.tunnel.as_ref().unwrap()
+    }
+
+    #[test]
+    fn decapsulates_vxlan() {
+        let packet = ipv4(17, 0, &vxlan(5000, &inner_frame()));
+        let header = dissect(HEADER_IPV4, &packet);
+        assert_eq!(ports(&header), Some((50000, VXLAN_PORT)));
+        let tunnel = tunnel_of(&header);
+        assert_eq!(tunnel.tunnel_type, TunnelType::Vxlan);
+        assert_eq!(tunnel.vni, Some(5000));
+        assert_eq!(tunnel.inner.src_mac, Some(MacAddr::from(SRC_MAC)));
+        assert_eq!(ports(&tunnel.inner), Some((1234, 53)));
+
+        // without the I flag the VNI isn't valid and nothing is decapsulated
+        let mut packet = packet;
+        packet[28] = 0;
+        assert!(dissect(HEADER_IPV4, &packet).tunnel.is_none());
+    }
+
+    #[test]
+    fn decapsulates_geneve_past_its_options() {
+        // two words of options, carrying bridged Ethernet
+        let mut geneve = vec![0x02, 0, 0x65, 0x58];
+        geneve.extend((77u32 << 8).to_be_bytes());
+        geneve.extend([0xff; 8]);
+        geneve.extend(inner_frame());
+        let header = dissect(HEADER_IPV6, &ipv6(17, &udp(50000, GENEVE_PORT, &geneve)));
+        let tunnel = tunnel_of(&header);
+        assert_eq!(tunnel.tunnel_type, TunnelType::Geneve);
+        assert_eq!(tunnel.vni, Some(77));
+        assert_eq!(tunnel.inner.ethertype, Some(0x0800));
+        assert_eq!(ports(&tunnel.inner), Some((1234, 53)));
+
+        // options running past the sampled bytes leave the tunnel undecoded
+        let truncated = udp(50000, GENEVE_PORT, &geneve[..12]);
+        assert!(dissect(HEADER_IPV6, &ipv6(17, &truncated)).tunnel.is_none());
+    }
+
+    #[test]
+    fn decapsulates_gre_with_checksum_key_and_sequence() {
+        let mut gre = vec![0xb0, 0, 0x08, 0];
+        gre.extend([0; 4]); // checksum
+        gre.extend(1234u32.to_be_bytes());
+        gre.extend([0; 4]); // sequence number
+        gre.extend(ipv4(6, 0, &tcp(40000, 443, 0x02)));
+        let header = dissect(HEADER_IPV4, &ipv4(47, 0, &gre));
+        let tunnel = tunnel_of(&header);
+        assert_eq!(tunnel.tunnel_type, TunnelType::Gre);
+        assert_eq!(tunnel.vni, Some(1234));
+        assert_eq!(tunnel.inner.ip.as_ref().unwrap().protocol, 6);
+        assert_eq!(ports(&tunnel.inner), Some((40000, 443)));
+
+        // enhanced GRE is left alone
+        gre[1] = 0x01;
+        assert!(dissect(HEADER_IPV4, &ipv4(47, 0, &gre)).tunnel.is_none());
+    }
+
+    #[test]
+    fn decapsulates_nvgre() {
+        let mut gre = vec![0x20, 0, 0x65, 0x58];
+        gre.extend(((4321u32 << 8) | 0x01).to_be_bytes());
+        gre.extend(inner_frame());
+        let header = dissect(HEADER_IPV4, &ipv4(47, 0, &gre));
+        let tunnel = tunnel_of(&header);
+        assert_eq!(tunnel.tunnel_type, TunnelType::Nvgre);
+        assert_eq!(tunnel.vni, Some(4321));
+        assert_eq!(tunnel.inner.dst_mac, Some(MacAddr::from(DST_MAC)));
+        assert_eq!(ports(&tunnel.inner), Some((1234, 53)));
+    }
+
+    #[test]
+    fn decapsulates_ip_in_ip() {
+        let header = dissect(HEADER_IPV4, &ipv4(4, 0, &ipv4(17, 0, &udp(1, 2, &[]))));
+        let tunnel = tunnel_of(&header);
+        assert_eq!(tunnel.tunnel_type, TunnelType::IpInIp);
+        assert_eq!(tunnel.vni, None);
+        assert_eq!(ports(&tunnel.inner), Some((1, 2)));
+
+        let header = dissect(HEADER_IPV4, &ipv4(41, 0, &ipv6(6, &tcp(3, 4, 0x10))));
+        let tunnel = tunnel_of(&header);
+        assert_eq!(tunnel.tunnel_type, TunnelType::IpInIp);
+        assert!(matches!(
+            tunnel.inner.ip.as_ref().unwrap().src_ip,
+            IpAddr::V6(_)
+        ));
+        assert_eq!(ports(&tunnel.inner), Some((3, 4)));
+    }
+
+    #[test]
+    fn stops_decapsulating_past_the_depth_limit() {
+        let innermost = ethernet(0x0800, &ipv4(4, 0, &ipv4(17, 0, &udp(1, 2, &[]))));
+        let middle = ethernet(0x0800, &ipv4(17, 0, &vxlan(2, &innermost)));
+        let header = dissect(HEADER_IPV4, &ipv4(17, 0, &vxlan(1, &middle)));
+
+        let outer = tunnel_of(&header);
+        assert_eq!(outer.vni, Some(1));
+        let middle = tunnel_of(&outer.inner);
+        assert_eq!(middle.vni, Some(2));
+        // the third tunnel is past MAX_TUNNEL_DEPTH
+        assert_eq!(middle.inner.ip.as_ref().unwrap().protocol, 4);
+        assert!(middle.inner.tunnel.is_none());
+        assert!(middle.inner.transport.is_none());
+    }
+
+    #[test]
+    fn truncated_tunnels_do_not_panic() {
+        let mut gre = vec![0xb0, 0, 0x65, 0x58];
+        gre.extend([0; 12]);
+        gre.extend(inner_frame());
+        let packets = [
+            ipv4(17, 0, &vxlan(5000, &inner_frame())),
+            ipv4(47, 0, &gre),
+            ipv4(41, 0, &ipv6(6, &tcp(3, 4, 0x10))),
+        ];
+        for packet in packets {
+            for length in 0..packet.len() {
+                let header = dissect(HEADER_IPV4, &packet[..length]);
+                if let Some(tunnel) = header.tunnel {
+                    assert!(tunnel.inner.transport.is_none());
+                }
+            }
+        }
+    }
+}
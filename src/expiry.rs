@@ -0,0 +1,103 @@
+use crate::window::Windowed;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Which entries go first once a map is over its entry cap.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicy {
+    /// The ones seen least recently
+    Lru,
+    /// The ones that accounted for the fewest bytes
+    LeastBytes,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub idle_timeout: Option<Duration>,
+    pub active_timeout: Option<Duration>,
+    pub max_entries: usize,
+    pub eviction: EvictionPolicy,
+}
+
+/// A value in one of the aggregation maps that can be aged out.
+pub trait Expiring {
+    fn first_seen(&self) -> Option<Instant>;
+    fn last_seen(&self) -> Option<Instant>;
+    fn bytes(&self) -> u64;
+}
+
+pub trait Expire {
+    /// Evicts entries idle or active for longer than the timeouts, then
+    /// whatever is over the entry cap. Returns how many entries went.
+    fn expire(&mut self, limits: &Limits, now: Instant) -> u64;
+
+    /// Only evicts whatever is over the entry cap, cheap enough to call after
+    /// every insert.
+    fn enforce_cap(&mut self, limits: &Limits) -> u64;
+}
+
+fn older_than(seen: Option<Instant>, timeout: Option<Duration>, now: Instant) -> bool {
+    match (seen, timeout) {
+        (Some(seen), Some(timeout)) => now.duration_since(seen) > timeout,
+        _ => false,
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Expiring> Expire for HashMap<K, V> {
+    fn expire(&mut self, limits: &Limits, now: Instant) -> u64 {
+        let before = self.len();
+        self.retain(|_, value| {
+            !older_than(value.last_seen(), limits.idle_timeout, now)
+                && !older_than(value.first_seen(), limits.active_timeout, now)
+        });
+        (before - self.len()) as u64 + self.enforce_cap(limits)
+    }
+
+    fn enforce_cap(&mut self, limits: &Limits) -> u64 {
+        if self.len() <= limits.max_entries {
+            return 0;
+        }
+        // evict down to 90% of the cap so a full map doesn't go through this
+        // on every insert
+        let excess = self.len() - limits.max_entries * 9 / 10;
+        let mut candidates: Vec<(u64, Option<Instant>, &K)> = self
+            .iter()
+            .map(|(key, value)| (value.bytes(), value.last_seen(), key))
+            .collect();
+        match limits.eviction {
+            EvictionPolicy::Lru => candidates.select_nth_unstable_by_key(excess - 1, |c| c.1),
+            EvictionPolicy::LeastBytes => {
+                candidates.select_nth_unstable_by_key(excess - 1, |c| c.0)
+            }
+        };
+        let evicted: Vec<K> = candidates[..excess]
+            .iter()
+            .map(|(_, _, key)| (*key).clone())
+            .collect();
+        for key in &evicted {
+            self.remove(key);
+        }
+        evicted.len() as u64
+    }
+}
+
+// Closed windows are never written again and are bounded by the history, so
+// only the open one needs expiring. Its entries end with the window anyway, so
+// the active timeout would only cut the window's totals short.
+impl<C: Expire + Default> Expire for Windowed<C> {
+    fn expire(&mut self, limits: &Limits, now: Instant) -> u64 {
+        let limits = Limits {
+            active_timeout: None,
+            ..*limits
+        };
+        self.current_mut().expire(&limits, now)
+    }
+
+    fn enforce_cap(&mut self, limits: &Limits) -> u64 {
+        self.current_mut().enforce_cap(limits)
+    }
+}
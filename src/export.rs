@@ -0,0 +1,650 @@
+use crate::config::ExportConfig;
+use crate::sflow5::{FlowSample, SampledFlow, SampledIp};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Keeps messages inside a 1500 byte MTU once IP and UDP headers are added
+const MAX_MESSAGE: usize = 1400;
+
+const TEMPLATE_L2: u16 = 256;
+const TEMPLATE_IPV4: u16 = 257;
+const TEMPLATE_IPV6: u16 = 258;
+const TEMPLATE_SAMPLING: u16 = 259;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Ipfix,
+    Netflow9,
+}
+
+impl Format {
+    fn header_length(self) -> usize {
+        match self {
+            Format::Ipfix => 16,
+            Format::Netflow9 => 20,
+        }
+    }
+
+    fn template_set(self) -> u16 {
+        match self {
+            Format::Ipfix => 2,
+            Format::Netflow9 => 0,
+        }
+    }
+
+    fn options_template_set(self) -> u16 {
+        match self {
+            Format::Ipfix => 3,
+            Format::Netflow9 => 1,
+        }
+    }
+}
+
+// (information element id, length) of every field in a template
+type Fields = Vec<(u16, u16)>;
+
+// Both formats share the IANA ids below 128 and only differ in how timestamps
+// and the sampler are carried.
+fn template_fields(format: Format, template: u16) -> Fields {
+    let mut fields = match format {
+        // flowStartMilliseconds, flowEndMilliseconds, selectorId
+        Format::Ipfix => vec![(152, 8), (153, 8), (302, 8)],
+        // FIRST_SWITCHED, LAST_SWITCHED, FLOW_SAMPLER_ID
+        Format::Netflow9 => vec![(22, 4), (21, 4), (48, 2)],
+    };
+    // octets, packets, input and output ifIndex, source and destination MAC,
+    // VLAN
+    fields.extend([(1, 8), (2, 8), (10, 4), (14, 4), (56, 6), (80, 6), (58, 2)]);
+    match template {
+        TEMPLATE_IPV4 => fields.extend([(8, 4), (12, 4)]),
+        TEMPLATE_IPV6 => fields.extend([(27, 16), (28, 16)]),
+        _ => return fields,
+    }
+    // protocol, ports, TCP flags, source and destination AS
+    fields.extend([(4, 1), (7, 2), (11, 2), (6, 1), (16, 4), (17, 4)]);
+    fields
+}
+
+// Scope and option fields of the sampling options template. sFlow samples 1
+// in N packets at random.
+fn sampling_fields(format: Format) -> (Fields, Fields) {
+    match format {
+        // selectorId; selectorAlgorithm, samplingSize, samplingPopulation
+        Format::Ipfix => (vec![(302, 8)], vec![(304, 1), (309, 4), (310, 4)]),
+        // System; FLOW_SAMPLER_ID, FLOW_SAMPLER_MODE, FLOW_SAMPLER_RANDOM_INTERVAL
+        Format::Netflow9 => (vec![(1, 4)], vec![(48, 2), (49, 1), (50, 4)]),
+    }
+}
+
+/// Re-exports every flow sample as an IPFIX (RFC 7011) or NetFlow v9
+/// (RFC 3954) record. Records carry the sampled packet as it was, one packet
+/// of `frame_length` bytes, and name the sampler it came from, whose rate is
+/// sent in a sampling options record so collectors can scale it up.
+pub struct FlowExporter {
+    targets: Vec<Target>,
+    domain_id: u32,
+    template_interval: Duration,
+    templates_sent: Option<Instant>,
+    started: Instant,
+    // every (agent, source id type, source id index) is one sampler
+    samplers: HashMap<(IpAddr, u32, u32), Sampler>,
+    // ids of expired samplers, handed out again before new ones
+    free_ids: Vec<u16>,
+    next_id: Option<u16>,
+    records: Vec<Record>,
+    changed_samplers: Vec<Sampler>,
+}
+
+struct Target {
+    format: Format,
+    addr: SocketAddr,
+    socket: UdpSocket,
+    // IPFIX counts data records, NetFlow v9 counts export packets
+    sequence: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Sampler {
+    id: u16, // FLOW_SAMPLER_ID is 2 bytes in NetFlow v9
+    rate: u32,
+    last_seen: Instant,
+}
+
+struct Record {
+    template: u16,
+    flow: SampledFlow,
+    sampler: u16,
+    unix_millis: u64,
+    uptime_millis: u32,
+}
+
+impl FlowExporter {
+    pub fn new(config: &ExportConfig) -> Result<Self, io::Error> {
+        let targets = config
+            .ipfix
+            .iter()
+            .map(|addr| (Format::Ipfix, addr))
+            .chain(config.netflow9.iter().map(|addr| (Format::Netflow9, addr)))
+            .map(|(format, addr)| {
+                let local: SocketAddr = match addr {
+                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                    SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(addr)?;
+                Ok(Target {
+                    format,
+                    addr: *addr,
+                    socket,
+                    sequence: 0,
+                })
+            })
+            .collect::<Result<_, io::Error>>()?;
+        Ok(Self {
+            targets,
+            domain_id: config.domain_id,
+            template_interval: Duration::from_secs(config.template_interval),
+            templates_sent: None,
+            started: Instant::now(),
+            samplers: HashMap::new(),
+            free_ids: Vec::new(),
+            next_id: Some(1),
+            records: Vec::new(),
+            changed_samplers: Vec::new(),
+        })
+    }
+
+    /// Sends the decoded flow samples of one datagram. Samples of new
+    /// samplers are skipped while all 65535 sampler ids are taken.
+    pub fn export(&mut self, agent: IpAddr, flows: &[(FlowSample, SampledFlow)]) {
+        for (sample, flow) in flows {
+            self.add(agent, sample, flow);
+        }
+        self.flush();
+    }
+
+    fn add(&mut self, agent: IpAddr, sample: &FlowSample, flow: &SampledFlow) {
+        let now = Instant::now();
+        let key = (agent, sample.source_id_type, sample.source_id_index);
+        if !self.samplers.contains_key(&key) {
+            let Some(id) = self.free_ids.pop().or(self.next_id) else {
+                return; // every sampler id is taken
+            };
+            if Some(id) == self.next_id {
+                self.next_id = id.checked_add(1);
+            }
+            let sampler = Sampler {
+                id,
+                rate: 0,
+                last_seen: now,
+            };
+            self.samplers.insert(key, sampler);
+        }
+        let sampler = self.samplers.get_mut(&key).unwrap();
+        sampler.last_seen = now;
+        if sampler.rate != sample.sampling_rate {
+            sampler.rate = sample.sampling_rate;
+            self.changed_samplers.push(*sampler);
+        }
+
+        let template = match &flow.ip {
+            Some(SampledIp {
+                src_ip: IpAddr::V4(_),
+                ..
+            }) => TEMPLATE_IPV4,
+            Some(_) => TEMPLATE_IPV6,
+            None => TEMPLATE_L2,
+        };
+        self.records.push(Record {
+            template,
+            flow: flow.clone(),
+            sampler: sampler.id,
+            unix_millis: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |now| now.as_millis() as u64),
+            uptime_millis: self.started.elapsed().as_millis() as u32,
+        });
+    }
+
+    // Templates and every sampler's rate go out again every template_interval,
+    // for collectors that restarted or missed them. Samplers no sample came
+    // from since the last time are dropped then, and their ids reused.
+    fn flush(&mut self) {
+        if self.records.is_empty() && self.changed_samplers.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let refresh = self
+            .templates_sent
+            .is_none_or(|sent| now.duration_since(sent) >= self.template_interval);
+        let samplers: Vec<Sampler> = match refresh {
+            true => {
+                if let Some(sent) = self.templates_sent {
+                    let free_ids = &mut self.free_ids;
+                    self.samplers.retain(|_, sampler| {
+                        let seen = sampler.last_seen >= sent;
+                        if !seen {
+                            free_ids.push(sampler.id);
+                        }
+                        seen
+                    });
+                }
+                self.templates_sent = Some(now);
+                self.changed_samplers.clear();
+                self.samplers.values().copied().collect()
+            }
+            false => std::mem::take(&mut self.changed_samplers),
+        };
+
+        let unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as u32);
+        let uptime_millis = self.started.elapsed().as_millis() as u32;
+        for target in &mut self.targets {
+            let messages = messages(
+                target.format,
+                refresh,
+                &samplers,
+                &self.records,
+                self.domain_id,
+            );
+            for message in messages {
+                let data_records = message.data_records;
+                let datagram = message.finish(
+                    target.format,
+                    target.sequence,
+                    self.domain_id,
+                    uptime_millis,
+                    unix_secs,
+                );
+                if let Err(e) = target.socket.send(&datagram) {
+                    println!("Error: cannot export to {}: {}", target.addr, e);
+                }
+                target.sequence = match target.format {
+                    Format::Ipfix => target.sequence.wrapping_add(data_records),
+                    Format::Netflow9 => target.sequence.wrapping_add(1),
+                };
+            }
+        }
+        self.records.clear();
+    }
+}
+
+// Packs templates, sampling options and data records into as few messages as
+// fit the MTU.
+fn messages(
+    format: Format,
+    templates: bool,
+    samplers: &[Sampler],
+    records: &[Record],
+    domain_id: u32,
+) -> Vec<Message> {
+    let mut messages = vec![Message::new(format)];
+    let mut push = |set_id: u16, record: Vec<u8>, data: bool| {
+        let message = messages.last_mut().unwrap();
+        if !message.push(format, set_id, &record, data) {
+            let mut message = Message::new(format);
+            message.push(format, set_id, &record, data);
+            messages.push(message);
+        }
+    };
+
+    if templates {
+        for template in [TEMPLATE_L2, TEMPLATE_IPV4, TEMPLATE_IPV6] {
+            let fields = template_fields(format, template);
+            let mut record = Vec::new();
+            put(&mut record, template as u64, 2);
+            put(&mut record, fields.len() as u64, 2);
+            for (id, length) in fields {
+                put(&mut record, id as u64, 2);
+                put(&mut record, length as u64, 2);
+            }
+            push(format.template_set(), record, false);
+        }
+        let (scope, options) = sampling_fields(format);
+        let mut record = Vec::new();
+        put(&mut record, TEMPLATE_SAMPLING as u64, 2);
+        match format {
+            Format::Ipfix => {
+                put(&mut record, (scope.len() + options.len()) as u64, 2);
+                put(&mut record, scope.len() as u64, 2);
+            }
+            // NetFlow v9 gives the length in bytes of both lists instead
+            Format::Netflow9 => {
+                put(&mut record, scope.len() as u64 * 4, 2);
+                put(&mut record, options.len() as u64 * 4, 2);
+            }
+        }
+        for (id, length) in scope.into_iter().chain(options) {
+            put(&mut record, id as u64, 2);
+            put(&mut record, length as u64, 2);
+        }
+        push(format.options_template_set(), record, false);
+    }
+
+    for sampler in samplers {
+        let mut record = Vec::new();
+        match format {
+            Format::Ipfix => {
+                put(&mut record, sampler.id as u64, 8);
+                put(&mut record, 3, 1); // random n-out-of-N
+                put(&mut record, 1, 4);
+                put(&mut record, sampler.rate as u64, 4);
+            }
+            Format::Netflow9 => {
+                put(&mut record, domain_id as u64, 4);
+                put(&mut record, sampler.id as u64, 2);
+                put(&mut record, 2, 1); // random
+                put(&mut record, sampler.rate as u64, 4);
+            }
+        }
+        push(TEMPLATE_SAMPLING, record, true);
+    }
+
+    for record in records {
+        let mut encoded = Vec::new();
+        for (id, length) in template_fields(format, record.template) {
+            encode_field(&mut encoded, id, length, record);
+        }
+        push(record.template, encoded, true);
+    }
+    messages
+}
+
+fn encode_field(buf: &mut Vec<u8>, id: u16, length: u16, record: &Record) {
+    let flow = &record.flow;
+    let ip = flow.ip.as_ref();
+    match id {
+        8 | 12 | 27 | 28 => {
+            let address = match id {
+                8 | 27 => ip.map(|ip| ip.src_ip),
+                _ => ip.map(|ip| ip.dst_ip),
+            };
+            // the template was picked by the source address family, so the
+            // record length must not depend on the destination's
+            match (address, length) {
+                (Some(IpAddr::V4(address)), 4) => buf.extend_from_slice(&address.octets()),
+                (Some(IpAddr::V6(address)), 16) => buf.extend_from_slice(&address.octets()),
+                _ => buf.resize(buf.len() + length as usize, 0),
+            }
+        }
+        56 | 80 => {
+            let mac = match id {
+                56 => flow.src_mac,
+                _ => flow.dst_mac,
+            };
+            let mac = mac.unwrap_or_default();
+            buf.extend_from_slice(&mac.octets());
+        }
+        _ => {
+            let value = match id {
+                1 => flow.frame_length as u64,
+                2 => 1,
+                4 => ip.map_or(0, |ip| ip.protocol as u64),
+                6 => ip.map_or(0, |ip| ip.tcp_flags as u64),
+                7 => ip.map_or(0, |ip| ip.src_port as u64),
+                11 => ip.map_or(0, |ip| ip.dst_port as u64),
+                10 => flow.input_interface.unwrap_or(0) as u64,
+                14 => flow.output_interface.unwrap_or(0) as u64,
+                16 => flow.src_as.unwrap_or(0) as u64,
+                17 => flow.dst_as.unwrap_or(0) as u64,
+                58 => flow.vlan.unwrap_or(0) as u64,
+                21 | 22 => record.uptime_millis as u64,
+                152 | 153 => record.unix_millis,
+                48 | 302 => record.sampler as u64,
+                _ => 0,
+            };
+            put(buf, value, length);
+        }
+    }
+}
+
+// Writes the low `length` bytes of `value` in network order
+fn put(buf: &mut Vec<u8>, value: u64, length: u16) {
+    buf.extend_from_slice(&value.to_be_bytes()[8 - length as usize..]);
+}
+
+/// One export packet being filled, with room left for the header.
+struct Message {
+    buf: Vec<u8>,
+    set: Option<(u16, usize)>, // id and offset of the set being filled
+    records: u16,
+    data_records: u32,
+}
+
+impl Message {
+    fn new(format: Format) -> Self {
+        Self {
+            buf: vec![0; format.header_length()],
+            set: None,
+            records: 0,
+            data_records: 0,
+        }
+    }
+
+    /// Appends a record to the set `set_id`, returning false if the message
+    /// is full. A record too big for any message still goes into an empty one.
+    fn push(&mut self, format: Format, set_id: u16, record: &[u8], data: bool) -> bool {
+        let opening = self.set.is_none_or(|(id, _)| id != set_id);
+        // a new set header, plus padding for the set being closed
+        let overhead = if opening { 4 + 3 } else { 0 };
+        if self.records > 0 && self.buf.len() + overhead + record.len() > MAX_MESSAGE {
+            return false;
+        }
+        if opening {
+            self.close_set(format);
+            self.set = Some((set_id, self.buf.len()));
+            self.buf.extend_from_slice(&[0; 4]);
+        }
+        self.buf.extend_from_slice(record);
+        self.records += 1;
+        if data {
+            self.data_records += 1;
+        }
+        true
+    }
+
+    // NetFlow v9 flowsets are padded to 32 bits, IPFIX sets don't need to be
+    fn close_set(&mut self, format: Format) {
+        let Some((id, start)) = self.set.take() else {
+            return;
+        };
+        if format == Format::Netflow9 {
+            let padded = (self.buf.len() - start).div_ceil(4) * 4;
+            self.buf.resize(start + padded, 0);
+        }
+        let length = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&id.to_be_bytes());
+        self.buf[start + 2..start + 4].copy_from_slice(&length.to_be_bytes());
+    }
+
+    fn finish(
+        mut self,
+        format: Format,
+        sequence: u32,
+        domain_id: u32,
+        uptime_millis: u32,
+        unix_secs: u32,
+    ) -> Vec<u8> {
+        self.close_set(format);
+        let mut header = Vec::with_capacity(format.header_length());
+        match format {
+            Format::Ipfix => {
+                put(&mut header, 10, 2);
+                put(&mut header, self.buf.len() as u64, 2);
+                put(&mut header, unix_secs as u64, 4);
+                put(&mut header, sequence as u64, 4);
+                put(&mut header, domain_id as u64, 4);
+            }
+            Format::Netflow9 => {
+                put(&mut header, 9, 2);
+                put(&mut header, self.records as u64, 2);
+                put(&mut header, uptime_millis as u64, 4);
+                put(&mut header, unix_secs as u64, 4);
+                put(&mut header, sequence as u64, 4);
+                put(&mut header, domain_id as u64, 4);
+            }
+        }
+        self.buf[..header.len()].copy_from_slice(&header);
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sflow5::{SFlowPacket, SFlowSamplePacket};
+    use std::net::Ipv4Addr;
+
+    // The fixture capture ends with one datagram from agent 172.16.1.19
+    // holding four expanded flow samples, each sampled 1 in 524288.
+    const CAPTURE: &[u8] = include_bytes!("../tests/fixtures/sflow.pcap");
+    const DATAGRAM_LENGTH: usize = 1196;
+    const SAMPLING_RATE: u32 = 524288;
+
+    fn sets(format: Format, message: &[u8]) -> Vec<(u16, &[u8])> {
+        let mut sets = Vec::new();
+        let mut rest = &message[format.header_length()..];
+        while !rest.is_empty() {
+            let id = u16::from_be_bytes([rest[0], rest[1]]);
+            let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            sets.push((id, &rest[4..length]));
+            rest = &rest[length..];
+        }
+        sets
+    }
+
+    // decodes the flow samples the way the decoder does before exporting
+    fn flows<'a>(samples: &'a [SFlowSamplePacket]) -> Vec<(FlowSample<'a>, SampledFlow)> {
+        samples
+            .iter()
+            .filter(|sample| matches!(sample.get_sample_type(), 1 | 3))
+            .map(|sample| {
+                let sample = sample.get_flow_sample().unwrap();
+                let flow = sample.sampled_flow().unwrap();
+                (sample, flow)
+            })
+            .collect()
+    }
+
+    fn read(bytes: &[u8], offset: usize, length: usize) -> u64 {
+        bytes[offset..offset + length]
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as u64)
+    }
+
+    #[test]
+    fn exports_templates_sampling_options_and_records() {
+        let ipfix = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let netflow9 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for socket in [&ipfix, &netflow9] {
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        let config = ExportConfig {
+            ipfix: vec![ipfix.local_addr().unwrap()],
+            netflow9: vec![netflow9.local_addr().unwrap()],
+            domain_id: 7,
+            template_interval: 600,
+        };
+        let mut exporter = FlowExporter::new(&config).unwrap();
+        let datagram = &CAPTURE[CAPTURE.len() - DATAGRAM_LENGTH..];
+        let sflow = SFlowPacket::decode(datagram).unwrap();
+        let agent = IpAddr::V4(Ipv4Addr::new(172, 16, 1, 19));
+        let samples = sflow.get_samples().unwrap();
+        exporter.export(agent, &flows(&samples));
+
+        for (format, socket) in [(Format::Ipfix, ipfix), (Format::Netflow9, netflow9)] {
+            let mut buf = [0; 65535];
+            let len = socket.recv(&mut buf).unwrap();
+            let message = &buf[..len];
+            let (version, sampler_id, sampler_rate) = match format {
+                Format::Ipfix => (10, (0, 8), 13),
+                Format::Netflow9 => (9, (4, 2), 7),
+            };
+            assert_eq!(read(message, 0, 2), version);
+
+            let mut templates = Vec::new();
+            let mut samplers = Vec::new();
+            let mut records = 0;
+            for (id, set) in sets(format, message) {
+                if id == format.template_set() {
+                    let mut rest = set;
+                    while !rest.is_empty() {
+                        let fields = read(rest, 2, 2) as usize;
+                        templates.push(read(rest, 0, 2) as u16);
+                        rest = &rest[4 + 4 * fields..];
+                    }
+                } else if id == format.options_template_set() {
+                    assert_eq!(read(set, 0, 2) as u16, TEMPLATE_SAMPLING);
+                    let (scope, options) = sampling_fields(format);
+                    let fields: Vec<_> = set[6..]
+                        .chunks(4)
+                        .take(scope.len() + options.len())
+                        .map(|field| (read(field, 0, 2) as u16, read(field, 2, 2) as u16))
+                        .collect();
+                    assert_eq!(fields, [scope, options].concat());
+                } else if id == TEMPLATE_SAMPLING {
+                    for record in set.chunks_exact(sampler_rate + 4) {
+                        let id = read(record, sampler_id.0, sampler_id.1);
+                        samplers.push((id, read(record, sampler_rate, 4) as u32));
+                    }
+                } else {
+                    assert!(matches!(id, TEMPLATE_L2 | TEMPLATE_IPV4 | TEMPLATE_IPV6));
+                    let fields = template_fields(format, id);
+                    let length: usize = fields.iter().map(|(_, length)| *length as usize).sum();
+                    for record in set.chunks_exact(length) {
+                        // the sampler id follows the two timestamps
+                        let offset = fields[0].1 as usize + fields[1].1 as usize;
+                        let sampler = read(record, offset, fields[2].1 as usize);
+                        assert!(samplers.iter().any(|(id, _)| *id == sampler));
+                        records += 1;
+                    }
+                }
+            }
+            assert_eq!(templates, [TEMPLATE_L2, TEMPLATE_IPV4, TEMPLATE_IPV6]);
+            assert!(!samplers.is_empty());
+            assert!(samplers.iter().all(|(_, rate)| *rate == SAMPLING_RATE));
+            assert_eq!(records, 4);
+        }
+    }
+
+    #[test]
+    fn reuses_the_ids_of_expired_samplers() {
+        let config = ExportConfig {
+            ipfix: Vec::new(),
+            netflow9: Vec::new(),
+            domain_id: 7,
+            template_interval: 0,
+        };
+        let mut exporter = FlowExporter::new(&config).unwrap();
+        let datagram = &CAPTURE[CAPTURE.len() - DATAGRAM_LENGTH..];
+        let sflow = SFlowPacket::decode(datagram).unwrap();
+        let samples = sflow.get_samples().unwrap();
+        let flows = flows(&samples);
+        let ids = |exporter: &mut FlowExporter, agent: [u8; 4]| {
+            let agent = IpAddr::from(agent);
+            exporter.export(agent, &flows);
+            let mut ids: Vec<_> = exporter
+                .samplers
+                .iter()
+                .filter(|((sampler_agent, _, _), _)| *sampler_agent == agent)
+                .map(|(_, sampler)| sampler.id)
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        // every refresh drops the samplers of the agent before
+        let first = ids(&mut exporter, [192, 0, 2, 1]);
+        let second = ids(&mut exporter, [192, 0, 2, 2]);
+        let third = ids(&mut exporter, [192, 0, 2, 3]);
+        assert!(!first.is_empty());
+        assert!(second.iter().all(|id| !first.contains(id)));
+        assert_eq!(third, first);
+        assert_eq!(exporter.samplers.len(), third.len());
+    }
+}
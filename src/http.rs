@@ -1,37 +1,241 @@
-use std::{
-    collections::HashMap,
-    net::IpAddr,
-    sync::{Arc, RwLock},
-};
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
 
-use crate::{metrics::FlowCounter, Counter};
-use serde_json::{self, json};
-use tokio;
-use warp::{reply::Reply, Filter};
+use crate::{
+    config::HttpConfig,
+    metrics::{
+        AgentStats, AsMatrix, FlowCounter, InterfaceCounter, IpFlowCounter, ReceiverStats, Stats,
+        TunnelCounter,
+    },
+    prometheus::{Scraper, OPENMETRICS_CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE},
+    queue::QueueStats,
+    replicate::ReplicaStats,
+    topn::{Dimension, Metric, TopTalkers},
+    window::{Merge, Window, Windowed},
+    Counter,
+};
+use serde::Deserialize;
+use serde_json::{self, json, Value};
+use warp::Filter;
 
 #[tokio::main]
-pub async fn start_http_server(
-    statmap: Arc<RwLock<HashMap<IpAddr, Counter>>>,
-    flow_agent_stats: Arc<RwLock<HashMap<IpAddr, HashMap<String, Counter>>>>,
-    flowstat: Arc<RwLock<FlowCounter>>,
-) {
-    let net = warp::path("net").map(move || metrics(&statmap.read().unwrap()));
-    let agent = warp::path("agent").map(move || get_agent_stats(&flow_agent_stats.read().unwrap()));
-    let flow = warp::path("flow").map(move || flowstats(&flowstat.read().unwrap()));
+pub async fn start_http_server(stats: Stats, config: HttpConfig) {
+    // /metrics itself is the Prometheus scrape, in OpenMetrics if asked for
+    let scraped = stats.clone();
+    let scraper = Arc::new(Scraper::new(config.max_series));
+    let exposition = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("accept"))
+        .map(move |accept: Option<String>| {
+            let openmetrics =
+                accept.is_some_and(|accept| accept.contains("application/openmetrics-text"));
+            let content_type = match openmetrics {
+                true => OPENMETRICS_CONTENT_TYPE,
+                false => PROMETHEUS_CONTENT_TYPE,
+            };
+            warp::reply::with_header(
+                scraper.scrape(&scraped, openmetrics),
+                "content-type",
+                content_type,
+            )
+        });
+    let exporters = stats.clone();
+    let net = warp::path("net").map(move || {
+        exporters.merge_exporters();
+        warp::reply::json(&metrics(&exporters.exporters.read().unwrap()))
+    });
+    let receivers = stats.receivers.clone();
+    let receiver = warp::path("receiver")
+        .map(move || warp::reply::json(&get_receiver_stats(&receivers.read().unwrap())));
+    let queues = stats.queues.clone();
+    let queue = warp::path("queue")
+        .map(move || warp::reply::json(&get_queue_stats(&queues.read().unwrap())));
+    let replicas = stats.replicas.clone();
+    let replica = warp::path("replicas")
+        .map(move || warp::reply::json(&get_replica_stats(&replicas.read().unwrap())));
+    let agents = stats.agents.clone();
+    let agent = warp::path("agent")
+        .map(move || warp::reply::json(&get_agent_stats(&agents.read().unwrap())));
+    let flows = stats.flows.clone();
+    let flow =
+        warp::path("flow").map(move || warp::reply::json(&flowstats(&flows.read().unwrap())));
+    let ipflows = stats.ipflows.clone();
+    let ipflow =
+        warp::path("ipflow").map(move || warp::reply::json(&ipflowstats(&ipflows.read().unwrap())));
+    let flow_windows = stats.flow_windows.clone();
+    let window_flow = warp::path!("window" / "flow")
+        .and(warp::query::<WindowQuery>())
+        .map(move |query: WindowQuery| {
+            let mut windows = flow_windows.write().unwrap();
+            windows.roll();
+            warp::reply::json(&windowed(&windows, &query, "flows", flowstats))
+        });
+    let ipflow_windows = stats.ipflow_windows.clone();
+    let window_ipflow = warp::path!("window" / "ipflow")
+        .and(warp::query::<WindowQuery>())
+        .map(move |query: WindowQuery| {
+            let mut windows = ipflow_windows.write().unwrap();
+            windows.roll();
+            warp::reply::json(&windowed(&windows, &query, "ipflows", ipflowstats))
+        });
+    let tunnels = stats.tunnels.clone();
+    let tunnel =
+        warp::path("tunnel").map(move || warp::reply::json(&tunnelstats(&tunnels.read().unwrap())));
+    let asmatrix = stats.asmatrix.clone();
+    let asmatrix = warp::path("asmatrix")
+        .map(move || warp::reply::json(&asmatrixstats(&asmatrix.read().unwrap())));
+    let decode_errors = stats.decode_errors.clone();
+    let errors = warp::path("errors")
+        .map(move || warp::reply::json(&get_decode_errors(&decode_errors.read().unwrap())));
+    let evictions = stats.evictions.clone();
+    let eviction = warp::path("evictions")
+        .map(move || warp::reply::json(&get_evictions(&evictions.read().unwrap())));
+    let interfaces = stats.interfaces.clone();
+    let interface = warp::path("interface")
+        .map(move || warp::reply::json(&interface_stats(&interfaces.read().unwrap())));
+    let top = stats.top.clone();
+    let top = warp::path("top")
+        .and(warp::query::<TopQuery>())
+        .map(move |query: TopQuery| warp::reply::json(&topstats(&top.read().unwrap(), &query)));
+
+    let routes = exposition.or(warp::path("metrics").and(
+        net.or(receiver)
+            .or(queue)
+            .or(replica)
+            .or(flow)
+            .or(ipflow)
+            .or(window_flow)
+            .or(window_ipflow)
+            .or(tunnel)
+            .or(asmatrix)
+            .or(agent)
+            .or(errors)
+            .or(eviction)
+            .or(interface)
+            .or(top),
+    ));
+    warp::serve(routes).run(config.listen).await
+}
+
+/// Picks the windows to report, either `window` windows back (0 being the one
+/// still open, and the default) or all of those overlapping [from, to) in unix
+/// seconds.
+#[derive(Deserialize)]
+struct WindowQuery {
+    window: Option<usize>,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+fn windowed<C: Merge + Default>(
+    windows: &Windowed<C>,
+    query: &WindowQuery,
+    name: &str,
+    render: fn(&C) -> Value,
+) -> Value {
+    let selected: Vec<&Window<C>> = match (query.from, query.to) {
+        (None, None) => windows
+            .window(query.window.unwrap_or(0))
+            .into_iter()
+            .collect(),
+        (from, to) => windows
+            .range(from.unwrap_or(0), to.unwrap_or(u64::MAX))
+            .collect(),
+    };
+    let mut merged = C::default();
+    for window in &selected {
+        merged.merge(&window.counters);
+    }
+    json!({
+        "start": selected.first().map(|window| window.start),
+        "end": selected.last().map(|window| window.end),
+        "windows": selected.len(),
+        name: render(&merged),
+    })
+}
+
+/// Defaults to the 20 source addresses sending the most bytes.
+#[derive(Deserialize)]
+struct TopQuery {
+    key: Option<Dimension>,
+    by: Option<Metric>,
+    n: Option<usize>,
+}
+
+// Each count overestimates by at most its error, so count - error is a lower
+// bound on the real total.
+fn topstats(top: &TopTalkers, query: &TopQuery) -> Value {
+    let key = query.key.unwrap_or(Dimension::SrcIp);
+    let by = query.by.unwrap_or(Metric::Bytes);
+    let n = query.n.unwrap_or(20).min(top.capacity());
+    // entries are labelled like the other endpoints, e.g. "src_ip" and "bytes"
+    let (key_name, by_name) = (json!(key), json!(by));
+    let (key_name, by_name) = (key_name.as_str().unwrap(), by_name.as_str().unwrap());
+    let res: Vec<Value> = top
+        .top(key, by, n)
+        .into_iter()
+        .map(|(value, count, error)| json!({ key_name: value, by_name: count, "error": error }))
+        .collect();
+    json!({
+        "key": key,
+        "by": by,
+        "capacity": top.capacity(),
+        "top": res,
+    })
+}
+
+/// Everything the HTTP server exposes, in one document.
+pub fn snapshot(stats: &Stats) -> Value {
+    stats.merge_exporters();
+    json!({
+        "net": metrics(&stats.exporters.read().unwrap()),
+        "receiver": get_receiver_stats(&stats.receivers.read().unwrap()),
+        "queue": get_queue_stats(&stats.queues.read().unwrap()),
+        "agent": get_agent_stats(&stats.agents.read().unwrap()),
+        "errors": get_decode_errors(&stats.decode_errors.read().unwrap()),
+        "evictions": get_evictions(&stats.evictions.read().unwrap()),
+        "flow": flowstats(&stats.flows.read().unwrap()),
+        "ipflow": ipflowstats(&stats.ipflows.read().unwrap()),
+        "tunnel": tunnelstats(&stats.tunnels.read().unwrap()),
+        "asmatrix": asmatrixstats(&stats.asmatrix.read().unwrap()),
+        "interface": interface_stats(&stats.interfaces.read().unwrap()),
+    })
+}
+
+fn metrics(counters: &HashMap<IpAddr, Counter>) -> Value {
+    json!(counters)
+}
+
+fn get_receiver_stats(counters: &HashMap<String, Arc<ReceiverStats>>) -> Value {
+    json!(counters
+        .iter()
+        .map(|(name, receiver)| (name, &**receiver))
+        .collect::<HashMap<_, _>>())
+}
+
+fn get_queue_stats(queues: &[Arc<QueueStats>]) -> Value {
+    json!(queues.iter().map(|queue| &**queue).collect::<Vec<_>>())
+}
 
-    let routes = warp::path("metrics").and(net.or(flow).or(agent));
-    warp::serve(routes).run(([0, 0, 0, 0], 3030)).await
+fn get_replica_stats(replicas: &[Arc<ReplicaStats>]) -> Value {
+    json!(replicas
+        .iter()
+        .map(|replica| &**replica)
+        .collect::<Vec<_>>())
 }
 
-fn metrics(counters: &HashMap<IpAddr, Counter>) -> impl Reply {
-    warp::reply::json(&counters)
+fn get_agent_stats(counters: &HashMap<IpAddr, AgentStats>) -> Value {
+    json!(counters)
 }
 
-fn get_agent_stats(counters: &HashMap<IpAddr, HashMap<String, Counter>>) -> impl Reply {
-    warp::reply::json(&counters)
+fn get_decode_errors(counters: &HashMap<String, u64>) -> Value {
+    json!(counters)
 }
 
-fn flowstats(counters: &FlowCounter) -> impl Reply {
+fn get_evictions(counters: &HashMap<String, u64>) -> Value {
+    json!(counters)
+}
+
+fn flowstats(counters: &FlowCounter) -> Value {
     let mut res = Vec::new();
     for (k, v) in counters {
         res.push(json!({
@@ -39,9 +243,89 @@ fn flowstats(counters: &FlowCounter) -> impl Reply {
             "dst_mac": k.dst_mac,
             "vlan": k.vlan,
             "protocol": k.protocol,
+            "input_interface": k.input_interface,
+            "output_interface": k.output_interface,
             "packets": v.packets,
-            "bytes": v.bytes
+            "bytes": v.bytes,
+            "samples": v.samples,
+            "error_percent": v.error_percent()
+        }));
+    }
+    json!(res)
+}
+
+fn ipflowstats(counters: &IpFlowCounter) -> Value {
+    let mut res = Vec::new();
+    for (k, v) in counters {
+        res.push(json!({
+            "src_ip": k.src_ip,
+            "dst_ip": k.dst_ip,
+            "protocol": k.protocol,
+            "src_port": k.src_port,
+            "dst_port": k.dst_port,
+            "tcp_flags": v.tcp_flags,
+            "extended": v.extended,
+            "packets": v.estimate.packets,
+            "bytes": v.estimate.bytes,
+            "samples": v.estimate.samples,
+            "error_percent": v.estimate.error_percent()
+        }));
+    }
+    json!(res)
+}
+
+fn tunnelstats(counters: &TunnelCounter) -> Value {
+    let mut res = Vec::new();
+    for (k, v) in counters {
+        res.push(json!({
+            "tunnel_type": k.tunnel_type,
+            "vni": k.vni,
+            "outer_src_ip": k.outer_src_ip,
+            "outer_dst_ip": k.outer_dst_ip,
+            "src_ip": k.src_ip,
+            "dst_ip": k.dst_ip,
+            "protocol": k.protocol,
+            "src_port": k.src_port,
+            "dst_port": k.dst_port,
+            "packets": v.packets,
+            "bytes": v.bytes,
+            "samples": v.samples,
+            "error_percent": v.error_percent()
+        }));
+    }
+    json!(res)
+}
+
+fn asmatrixstats(counters: &AsMatrix) -> Value {
+    let mut res = Vec::new();
+    for (k, v) in counters {
+        res.push(json!({
+            "src_as": k.src_as,
+            "src_peer_as": k.src_peer_as,
+            "dst_as": k.dst_as,
+            "dst_peer_as": k.dst_peer_as,
+            "next_hop": k.next_hop,
+            "packets": v.packets,
+            "bytes": v.bytes,
+            "samples": v.samples,
+            "error_percent": v.error_percent()
+        }));
+    }
+    json!(res)
+}
+
+fn interface_stats(counters: &InterfaceCounter) -> Value {
+    let mut res = Vec::new();
+    for (k, v) in counters {
+        res.push(json!({
+            "agent": k.agent,
+            "source_id_type": k.source_id_type,
+            "source_id_index": k.source_id_index,
+            "interface": v.interface,
+            "ethernet": v.ethernet,
+            "vlan": v.vlan,
+            "processor": v.processor
         }));
     }
-    warp::reply::json(&res)
+    json!(res)
 }
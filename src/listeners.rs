@@ -1,63 +1,313 @@
-use pcap::{Active, Capture};
-use pnet::packet::{ipv4::Ipv4Packet, sll::SLLPacket, udp::UdpPacket, Packet};
+use crate::capture::{FrameDecoder, MAX_REASSEMBLED_DATAGRAM};
+use crate::queue::MAX_DATAGRAM;
+use nix::cmsg_space;
+use nix::sys::socket::{
+    bind, recvmmsg, setsockopt, socket, sockopt, AddressFamily, ControlMessageOwned, MsgFlags,
+    MultiHeaders, SockFlag, SockType, SockaddrLike, SockaddrStorage,
+};
+use pcap::{Active, Capture, Offline};
 use std::{
-    io::Error,
-    net::{IpAddr, SocketAddr, UdpSocket},
+    collections::VecDeque,
+    io::{Error, ErrorKind, IoSliceMut},
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::fd::AsRawFd,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
 };
 
 pub trait Receiver {
     fn receive(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error>;
+
+    /// The size of the buffers `receive` needs to fit any datagram in.
+    fn max_datagram(&self) -> usize {
+        MAX_DATAGRAM
+    }
+
+    /// Datagrams the kernel dropped before they could be received, if the
+    /// receiver can tell.
+    fn kernel_drops(&self) -> Option<u64> {
+        None
+    }
+
+    /// Datagrams dropped for not fitting the buffer.
+    fn truncated(&self) -> u64 {
+        0
+    }
 }
 
+// Datagrams read from the socket per system call, at most
+const UDP_BATCH: usize = 32;
+
 pub struct UdpReceiver {
     socket: UdpSocket,
+    buffers: Vec<Vec<u8>>, // one per datagram of a batch
+    received: VecDeque<(usize, usize, SocketAddr)>, // buffer, length, source
+    kernel_drops: u32,
+    truncated: u64,
 }
+
 impl UdpReceiver {
-    pub fn new(addr: &str) -> Self {
-        let socket = UdpSocket::bind(addr).expect("couldn't bind to address");
-        Self { socket }
+    /// Binds a socket to `addr`. With `reuse_port` several receivers can bind
+    /// the same address and the kernel spreads datagrams across them.
+    pub fn new(addr: SocketAddr, reuse_port: bool, rcvbuf: Option<usize>) -> Result<Self, Error> {
+        let family = match addr {
+            SocketAddr::V4(_) => AddressFamily::Inet,
+            SocketAddr::V6(_) => AddressFamily::Inet6,
+        };
+        let fd = socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+        if reuse_port {
+            setsockopt(&fd, sockopt::ReusePort, &true)?;
+        }
+        if let Some(size) = rcvbuf {
+            setsockopt(&fd, sockopt::RcvBuf, &size)?;
+        }
+        // have the kernel attach its drop counter to every datagram
+        setsockopt(&fd, sockopt::RxqOvfl, &1)?;
+        bind(fd.as_raw_fd(), &SockaddrStorage::from(addr))?;
+        Ok(Self {
+            socket: UdpSocket::from(fd),
+            buffers: vec![vec![0; MAX_DATAGRAM]; UDP_BATCH],
+            received: VecDeque::with_capacity(UDP_BATCH),
+            kernel_drops: 0,
+            truncated: 0,
+        })
+    }
+
+    // Waits for at least one datagram, then takes whatever else is already
+    // queued on the socket, up to a batch, in the same call.
+    fn receive_batch(&mut self) -> Result<(), Error> {
+        let mut headers =
+            MultiHeaders::<SockaddrStorage>::preallocate(UDP_BATCH, Some(cmsg_space!(u32)));
+        let mut iovs: Vec<[IoSliceMut; 1]> = self
+            .buffers
+            .iter_mut()
+            .map(|buffer| [IoSliceMut::new(buffer)])
+            .collect();
+        let messages = recvmmsg(
+            self.socket.as_raw_fd(),
+            &mut headers,
+            &mut iovs,
+            MsgFlags::MSG_WAITFORONE,
+            None,
+        )?;
+        for (slot, msg) in messages.enumerate() {
+            for cmsg in msg.cmsgs()? {
+                if let ControlMessageOwned::RxqOvfl(drops) = cmsg {
+                    self.kernel_drops = drops;
+                }
+            }
+            // the rest of a datagram that didn't fit is gone, and decoding
+            // what is left would only make up records
+            if msg.flags.contains(MsgFlags::MSG_TRUNC) {
+                self.truncated += 1;
+                continue;
+            }
+            // a UDP datagram always has a source, so this is never skipped
+            if let Some(addr) = msg.address.as_ref().and_then(socket_addr) {
+                self.received.push_back((slot, msg.bytes, addr));
+            }
+        }
+        Ok(())
     }
 }
 
 impl Receiver for UdpReceiver {
     fn receive(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
-        self.socket.recv_from(buffer)
+        loop {
+            if let Some((slot, len, addr)) = self.received.pop_front() {
+                let buffer = buffer.get_mut(..len).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} byte datagram from {} exceeds buffer", len, addr),
+                    )
+                })?;
+                buffer.copy_from_slice(&self.buffers[slot][..len]);
+                return Ok((len, addr));
+            }
+            self.receive_batch()?;
+        }
+    }
+
+    fn kernel_drops(&self) -> Option<u64> {
+        Some(self.kernel_drops as u64)
+    }
+
+    fn truncated(&self) -> u64 {
+        self.truncated
+    }
+}
+
+fn socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    match addr.family()? {
+        AddressFamily::Inet => Some(SocketAddrV4::from(*addr.as_sockaddr_in()?).into()),
+        AddressFamily::Inet6 => Some(SocketAddrV6::from(*addr.as_sockaddr_in6()?).into()),
+        _ => None,
     }
 }
 
 pub struct PCapReceiver {
     cap: Capture<Active>,
+    decoder: FrameDecoder,
 }
 
 impl PCapReceiver {
-    pub fn new(iface: &str, filter: &str, snaplen: i32, immediate_mode: bool) -> Self {
+    /// Opens `iface` for capture, which usually takes CAP_NET_RAW.
+    pub fn new(
+        iface: &str,
+        filter: &str,
+        snaplen: i32,
+        immediate_mode: bool,
+    ) -> Result<Self, Error> {
         let mut cap = Capture::from_device(iface)
-            .unwrap()
-            .promisc(true)
-            .immediate_mode(immediate_mode)
-            .snaplen(snaplen)
-            .open()
-            .unwrap();
-        cap.filter(filter, true).unwrap();
-        Self { cap }
+            .and_then(|cap| {
+                cap.promisc(true)
+                    .immediate_mode(immediate_mode)
+                    .snaplen(snaplen)
+                    .open()
+            })
+            .map_err(|e| Error::other(format!("cannot capture on {}: {}", iface, e)))?;
+        cap.filter(filter, true)
+            .map_err(|e| filter_error(filter, e))?;
+        let decoder = FrameDecoder::new(cap.get_datalink());
+        Ok(Self { cap, decoder })
     }
 }
 
+fn filter_error(filter: &str, e: pcap::Error) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("invalid filter \"{}\": {}", filter, e),
+    )
+}
+
 impl Receiver for PCapReceiver {
     fn receive(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
-        match self.cap.next_packet() {
-            Ok(packet) => {
-                let sllp = SLLPacket::new(packet.data).unwrap();
-                let ip = Ipv4Packet::new(sllp.payload()).unwrap();
-                let udp = UdpPacket::new(ip.payload()).unwrap();
-                let addr = SocketAddr::new(IpAddr::V4(ip.get_source()), udp.get_source());
+        // keep reading until a frame completes a UDP datagram, skipping
+        // everything else the filter let through
+        loop {
+            let packet = self.cap.next_packet().map_err(Error::other)?;
+            let Some((addr, payload)) = self.decoder.decode(packet.data) else {
+                continue;
+            };
+            let Some(buffer) = buffer.get_mut(..payload.len()) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} byte datagram from {} exceeds buffer",
+                        payload.len(),
+                        addr
+                    ),
+                ));
+            };
+            buffer.copy_from_slice(&payload);
+            return Ok((payload.len(), addr));
+        }
+    }
+
+    fn max_datagram(&self) -> usize {
+        MAX_REASSEMBLED_DATAGRAM
+    }
+}
+
+/// Replays a saved capture through the same pipeline as a live one. The end
+/// of the file is reported as an `UnexpectedEof` error.
+pub struct FileReceiver {
+    cap: Capture<Offline>,
+    decoder: FrameDecoder,
+    realtime: bool,
+    // capture timestamp of the first packet and when it was replayed
+    replay_start: Option<(Duration, Instant)>,
+}
+
+impl FileReceiver {
+    pub fn new(path: &Path, filter: &str, realtime: bool) -> Result<Self, Error> {
+        let mut cap = Capture::from_file(path)
+            .map_err(|e| Error::other(format!("cannot open {}: {}", path.display(), e)))?;
+        cap.filter(filter, true)
+            .map_err(|e| filter_error(filter, e))?;
+        let decoder = FrameDecoder::new(cap.get_datalink());
+        Ok(Self {
+            cap,
+            decoder,
+            realtime,
+            replay_start: None,
+        })
+    }
 
-                let len = udp.payload().len();
+    fn wait_for(&mut self, timestamp: Duration) {
+        let (first, started) = *self
+            .replay_start
+            .get_or_insert_with(|| (timestamp, Instant::now()));
+        let due = timestamp.saturating_sub(first);
+        if let Some(delay) = due.checked_sub(started.elapsed()) {
+            thread::sleep(delay);
+        }
+    }
+}
 
-                buffer[..len].copy_from_slice(udp.payload());
-                Ok((len, addr))
+impl Receiver for FileReceiver {
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), Error> {
+        loop {
+            let packet = match self.cap.next_packet() {
+                Ok(packet) => packet,
+                Err(pcap::Error::NoMorePackets) => {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "end of capture file"))
+                }
+                Err(e) => return Err(Error::other(e)),
+            };
+            let timestamp = Duration::new(
+                packet.header.ts.tv_sec as u64,
+                packet.header.ts.tv_usec as u32 * 1000,
+            );
+            let Some((addr, payload)) = self.decoder.decode(packet.data) else {
+                continue;
+            };
+            let Some(buffer) = buffer.get_mut(..payload.len()) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} byte datagram from {} exceeds buffer",
+                        payload.len(),
+                        addr
+                    ),
+                ));
+            };
+            buffer.copy_from_slice(&payload);
+            let len = payload.len();
+            if self.realtime {
+                self.wait_for(timestamp);
             }
-            Err(e) => Err(Error::new(std::io::ErrorKind::Other, e)),
+            return Ok((len, addr));
+        }
+    }
+
+    fn max_datagram(&self) -> usize {
+        MAX_REASSEMBLED_DATAGRAM
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receives_a_batch_in_order_and_counts_truncated_datagrams() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut receiver = UdpReceiver::new(addr, false, None).unwrap();
+        let local = receiver.socket.local_addr().unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"first", local).unwrap();
+        sender.send_to(&[0; MAX_DATAGRAM + 1], local).unwrap();
+        sender.send_to(b"second", local).unwrap();
+
+        let mut buffer = vec![0; receiver.max_datagram()];
+        for expected in [&b"first"[..], b"second"] {
+            let (len, source) = receiver.receive(&mut buffer).unwrap();
+            assert_eq!(&buffer[..len], expected);
+            assert_eq!(source, sender.local_addr().unwrap());
         }
+        assert_eq!(receiver.truncated(), 1);
+        assert_eq!(receiver.kernel_drops(), Some(0));
     }
 }
@@ -1,66 +1,524 @@
+mod capture;
+mod config;
+mod dissector;
+mod expiry;
+mod export;
 mod http;
 mod listeners;
 mod metrics;
+mod netflow;
+mod otel;
+mod prometheus;
+mod queue;
+mod replicate;
+mod sflow4;
 mod sflow5;
+mod topn;
+mod window;
 
-use crate::{http::start_http_server, metrics::Collector, sflow5::*};
-use listeners::{PCapReceiver, Receiver};
-use metrics::{Counter, FlowCounter};
+use crate::{
+    http::{snapshot, start_http_server},
+    metrics::{Collector, FlowSink},
+    sflow5::*,
+};
+use config::{CollectorConfig, CollectorKind, Config, ReceiverConfig, ReceiverType, ReplaySpeed};
+use expiry::{Expire, Limits};
+use export::FlowExporter;
+use listeners::{FileReceiver, PCapReceiver, Receiver, UdpReceiver};
+use metrics::{Counter, Stats};
+use netflow::{is_netflow, NetflowDecoder};
+use otel::start_otlp_exporter;
+use queue::{BufferPool, Datagram, QueueReceiver, QueueSender};
+use replicate::Replicator;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind};
 use std::net::IpAddr;
-use std::sync::{mpsc, Arc, RwLock};
+use std::process;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+use topn::TopTalkers;
+use window::Windowed;
 
 fn main() {
-    let mut socket = PCapReceiver::new("any", "udp dst port 6343", 9000, true);
-    let mut buf = [0; 9000];
-    let (tx, rx) = mpsc::channel::<[u8; 9000]>();
-
-    let statmap: HashMap<IpAddr, Counter> = HashMap::new();
-    let smarc: Arc<RwLock<HashMap<IpAddr, Counter>>> = Arc::new(RwLock::new(statmap));
-    let sc = smarc.clone();
-    let flowstats = FlowCounter::new();
-    let fsarc: Arc<RwLock<FlowCounter>> = Arc::new(RwLock::new(flowstats));
-    let fsc = fsarc.clone();
-    let flow_agent_stats: Arc<RwLock<HashMap<IpAddr, HashMap<String, Counter>>>> =
-        Arc::new(RwLock::new(HashMap::new()));
-    let fas = flow_agent_stats.clone();
-
-    thread::spawn(move || loop {
-        let flow_agent_stats = flow_agent_stats.clone();
-        let boffer = rx.recv().unwrap();
-        let datagram = SFlowPacket::new(&boffer).unwrap();
-
-        for sample in datagram.get_samples() {
-            let agent_stats = &mut flow_agent_stats.write().unwrap();
-            let agent_stats = agent_stats
-                .entry(IpAddr::V4(datagram.get_agent_address()))
-                .or_insert(HashMap::new())
-                .entry(sample.get_sample_type().to_string())
-                .or_insert(Counter::default());
-            agent_stats.packets += 1;
-            agent_stats.bytes += sample.get_sample_length() as u64;
-
-            if let Err(e) = fsarc.write().unwrap().collect(sample) {
-                println!("Error: {:?}", e);
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    let receivers = match open_receivers(&config.receiver) {
+        Ok(receivers) => receivers,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let exporter = match config.export.ipfix.is_empty() && config.export.netflow9.is_empty() {
+        true => None,
+        false => match FlowExporter::new(&config.export) {
+            Ok(exporter) => Some(Arc::new(Mutex::new(exporter))),
+            Err(e) => {
+                println!("Error: {}", e);
+                process::exit(1);
+            }
+        },
+    };
+
+    let replicator = match Replicator::new(&config.replicate) {
+        Ok(replicator) => Arc::new(replicator),
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let stats = Stats::default();
+    *stats.replicas.write().unwrap() = replicator.stats();
+    *stats.flow_windows.write().unwrap() =
+        Windowed::new(config.windows.interval, config.windows.history);
+    *stats.ipflow_windows.write().unwrap() =
+        Windowed::new(config.windows.interval, config.windows.history);
+    *stats.top.write().unwrap() = TopTalkers::new(config.collectors.top_capacity);
+    let mut workers = Vec::new();
+    let mut senders = Vec::new();
+    for _ in 0..config.decoder.workers {
+        let (tx, rx, queue_stats) =
+            queue::bounded(config.decoder.queue_size, config.decoder.overflow);
+        stats.queues.write().unwrap().push(queue_stats);
+        let decoder = Decoder {
+            stats: stats.clone(),
+            collectors: config.collectors.clone(),
+            limits: config.expiry.limits(),
+            exporter: exporter.clone(),
+        };
+        workers.push(thread::spawn(move || decoder.run(rx)));
+        senders.push(tx);
+    }
+
+    {
+        let stats = stats.clone();
+        let limits = config.expiry.limits();
+        let interval = Duration::from_secs(config.expiry.sweep_interval);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            sweep(&stats, &limits);
+        });
+    }
+
+    for receiver in receivers {
+        let senders = senders.clone();
+        let stats = stats.clone();
+        let replicator = replicator.clone();
+        thread::spawn(move || receive(receiver, &senders, &stats, &replicator));
+    }
+    drop(senders);
+
+    // the worker channels close once every receiver has run out of input,
+    // which only happens when replaying a capture file
+    if config.receiver.exit_on_eof {
+        let stats = stats.clone();
+        thread::spawn(move || {
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            println!("{:#}", snapshot(&stats));
+            process::exit(0);
+        });
+    }
+
+    if config.otlp.endpoint.is_some() {
+        let stats = stats.clone();
+        let otlp = config.otlp;
+        thread::spawn(move || start_otlp_exporter(stats, otlp));
+    }
+
+    start_http_server(stats, config.http);
+}
+
+// Receivers are named in /metrics/receiver by type and address
+type NamedReceiver = (String, Box<dyn Receiver + Send>);
+
+fn open_receivers(config: &ReceiverConfig) -> Result<Vec<NamedReceiver>, io::Error> {
+    let mut receivers: Vec<NamedReceiver> = Vec::new();
+    match config.receiver_type {
+        ReceiverType::Udp => {
+            for addr in &config.bind {
+                for i in 0..config.sockets {
+                    let socket = UdpReceiver::new(*addr, config.sockets > 1, config.rcvbuf)?;
+                    receivers.push((format!("udp {} #{}", addr, i), Box::new(socket)));
+                }
             }
         }
-    });
+        ReceiverType::Pcap => receivers.push((
+            format!("pcap {}", config.interface),
+            Box::new(PCapReceiver::new(
+                &config.interface,
+                &config.filter,
+                config.snaplen,
+                config.immediate_mode,
+            )?),
+        )),
+        ReceiverType::File => {
+            let file = config.file.as_deref().unwrap();
+            receivers.push((
+                format!("file {}", file.display()),
+                Box::new(FileReceiver::new(
+                    file,
+                    &config.filter,
+                    config.replay == ReplaySpeed::Realtime,
+                )?),
+            ))
+        }
+    }
+    Ok(receivers)
+}
 
-    thread::spawn(move || start_http_server(sc, fas, fsc));
+// Hands datagrams to the decode workers until the receiver runs out of input
+// or the workers are gone.
+fn receive(
+    (name, mut socket): NamedReceiver,
+    senders: &[QueueSender],
+    stats: &Stats,
+    replicator: &Replicator,
+) {
+    let counters = stats.add_receiver(&name);
+    let mut pool = BufferPool::new(socket.max_datagram());
     loop {
-        match socket.receive(&mut buf) {
+        match socket.receive(pool.buffer()) {
             Ok((amt, src)) => {
-                tx.send(buf.clone()).unwrap();
-                let mut kys = smarc.write().unwrap();
-                let metric = kys.entry(src.ip()).or_insert(Counter {
-                    packets: 0,
-                    bytes: 0,
-                });
-                metric.packets += 1;
-                metric.bytes += amt as u64;
+                let datagram = Datagram {
+                    src,
+                    payload: pool.take(amt),
+                };
+                replicator.replicate(src, &datagram.payload);
+                if !senders[worker_for(src.ip(), senders.len())].send(datagram) {
+                    break;
+                }
+                counters.count_datagram(src.ip(), amt, Instant::now());
             }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
             Err(e) => println!("Error: {}", e),
         }
+        if let Some(drops) = socket.kernel_drops() {
+            counters.set_kernel_drops(drops);
+        }
+        counters.set_truncated(socket.truncated());
+    }
+}
+
+// Datagrams from one exporter always go to the same worker, so its samples are
+// still decoded in the order they arrived.
+fn worker_for(exporter: IpAddr, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    exporter.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+struct Decoder {
+    stats: Stats,
+    collectors: CollectorConfig,
+    limits: Limits,
+    // shared by every worker so that templates, sampler ids and sequence
+    // numbers stay consistent across them
+    exporter: Option<Arc<Mutex<FlowExporter>>>,
+}
+
+impl Decoder {
+    fn run(&self, rx: QueueReceiver) {
+        let stats = &self.stats;
+        let mut netflow = NetflowDecoder::new(self.limits.max_entries);
+        while let Some(Datagram { src, payload }) = rx.recv() {
+            if is_netflow(&payload) {
+                self.decode_netflow(&mut netflow, src.ip(), &payload);
+                continue;
+            }
+            let payload = match sflow4::upgrade(payload) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    count_decode_error(&stats.decode_errors, e.kind());
+                    continue;
+                }
+            };
+            let datagram = match SFlowPacket::decode(&payload) {
+                Ok(datagram) => datagram,
+                Err(e) => {
+                    count_decode_error(&stats.decode_errors, e.kind());
+                    continue;
+                }
+            };
+            let decoded = datagram.get_agent_address().and_then(|agent| {
+                let sequence = (
+                    datagram.get_sub_agent_id()?,
+                    datagram.get_sequence_number()?,
+                    datagram.get_uptime()?,
+                );
+                Ok((agent, sequence, datagram.get_samples()?))
+            });
+            let (agent, (sub_agent_id, sequence_number, uptime), samples) = match decoded {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    count_decode_error(&stats.decode_errors, e.kind());
+                    continue;
+                }
+            };
+
+            // each flow sample is decoded once here and the flow handed to
+            // every sink, so a malformed one is counted once for the sample
+            // rather than per collector
+            let flow_samples: Vec<Option<FlowSample>> = samples
+                .iter()
+                .map(|sample| match sample.get_sample_type() {
+                    1 | 3 => match sample.get_flow_sample() {
+                        Ok(flow_sample) => Some(flow_sample),
+                        Err(e) => {
+                            count_decode_error(&stats.decode_errors, e.kind());
+                            None
+                        }
+                    },
+                    _ => None,
+                })
+                .collect();
+
+            let scales: Vec<u64> = {
+                let mut agents = stats.agents.write().unwrap();
+                let agent_stats = agents.entry(agent).or_default();
+                agent_stats.record_datagram(sub_agent_id, sequence_number, uptime);
+                samples
+                    .iter()
+                    .zip(&flow_samples)
+                    .map(|(sample, flow_sample)| {
+                        agent_stats.record_sample(sample, flow_sample.as_ref())
+                    })
+                    .collect()
+            };
+
+            let mut flows = Vec::new();
+            for ((sample, flow_sample), scale) in samples.iter().zip(flow_samples).zip(scales) {
+                match sample.get_sample_type() {
+                    1 | 3 => {
+                        let Some(flow_sample) = flow_sample else {
+                            continue;
+                        };
+                        match flow_sample.sampled_flow() {
+                            Ok(flow) => {
+                                let bytes = flow.frame_length as u64 * scale;
+                                self.add_sampled_flow((agent, &flow, scale, bytes));
+                                flows.push((flow_sample, flow));
+                            }
+                            Err(e) => count_decode_error(&stats.decode_errors, e.kind()),
+                        }
+                    }
+                    2 | 4 => {
+                        if self.collectors.is_enabled(CollectorKind::Interface) {
+                            let collected =
+                                self.collect("interface", &stats.interfaces, agent, sample);
+                            if let Err(e) = collected {
+                                count_decode_error(&stats.decode_errors, e.kind());
+                            }
+                        }
+                    }
+                    typ => count_decode_error(
+                        &stats.decode_errors,
+                        DecodeError::UnsupportedSampleType(typ).kind(),
+                    ),
+                }
+            }
+            if let Some(exporter) = &self.exporter {
+                exporter.lock().unwrap().export(agent, &flows);
+            }
+        }
+    }
+
+    fn add_sampled_flow(&self, flow: (IpAddr, &SampledFlow, u64, u64)) {
+        let stats = &self.stats;
+        if self.collectors.is_enabled(CollectorKind::Flow) {
+            self.add_flow("flow", &stats.flows, flow);
+            self.add_flow("flow_window", &stats.flow_windows, flow);
+        }
+        if self.collectors.is_enabled(CollectorKind::Ipflow) {
+            self.add_flow("ipflow", &stats.ipflows, flow);
+            self.add_flow("ipflow_window", &stats.ipflow_windows, flow);
+        }
+        if self.collectors.is_enabled(CollectorKind::Tunnel) {
+            self.add_flow("tunnel", &stats.tunnels, flow);
+        }
+        if self.collectors.is_enabled(CollectorKind::Asmatrix) {
+            self.add_flow("asmatrix", &stats.asmatrix, flow);
+        }
+        if self.collectors.is_enabled(CollectorKind::Top) {
+            self.add_flow("top", &stats.top, flow);
+        }
+    }
+
+    fn collect(
+        &self,
+        name: &str,
+        collector: &RwLock<impl Collector + Expire>,
+        agent: IpAddr,
+        sample: &SFlowSamplePacket,
+    ) -> Result<(), DecodeError> {
+        let mut collector = collector.write().unwrap();
+        let collected = collector.collect(agent, sample);
+        count_evictions(&self.stats, name, collector.enforce_cap(&self.limits));
+        collected
+    }
+
+    // NetFlow and IPFIX records are already flows, so they only feed the maps
+    // keyed on what a flow record carries. The exporter's address stands in
+    // for the agent.
+    fn decode_netflow(&self, decoder: &mut NetflowDecoder, exporter: IpAddr, payload: &[u8]) {
+        let stats = &self.stats;
+        let mut records = Vec::new();
+        if let Err(e) = decoder.decode(exporter, payload, &mut records) {
+            count_decode_error(&stats.decode_errors, e.kind());
+        }
+        count_evictions(stats, "netflow_template", decoder.take_evicted());
+        for record in &records {
+            let flow = (exporter, &record.flow, record.packets, record.bytes);
+            if self.collectors.is_enabled(CollectorKind::Flow) {
+                self.add_flow("flow", &stats.flows, flow);
+                self.add_flow("flow_window", &stats.flow_windows, flow);
+            }
+            if self.collectors.is_enabled(CollectorKind::Ipflow) {
+                self.add_flow("ipflow", &stats.ipflows, flow);
+                self.add_flow("ipflow_window", &stats.ipflow_windows, flow);
+            }
+            if self.collectors.is_enabled(CollectorKind::Top) {
+                self.add_flow("top", &stats.top, flow);
+            }
+        }
+    }
+
+    fn add_flow(
+        &self,
+        name: &str,
+        sink: &RwLock<impl FlowSink + Expire>,
+        (agent, flow, packets, bytes): (IpAddr, &SampledFlow, u64, u64),
+    ) {
+        let mut sink = sink.write().unwrap();
+        sink.add_flow(agent, flow, packets, bytes);
+        count_evictions(&self.stats, name, sink.enforce_cap(&self.limits));
+    }
+}
+
+// Ages out idle and long-lived entries in every map, including the exporter
+// and agent ones that aren't collectors.
+fn sweep(stats: &Stats, limits: &Limits) {
+    let now = Instant::now();
+    stats.merge_exporters();
+    let evicted = stats.exporters.write().unwrap().expire(limits, now);
+    count_evictions(stats, "exporter", evicted);
+    let evicted = stats.agents.write().unwrap().expire(limits, now);
+    count_evictions(stats, "agent", evicted);
+    let evicted = stats.flows.write().unwrap().expire(limits, now);
+    count_evictions(stats, "flow", evicted);
+    let evicted = stats.flow_windows.write().unwrap().expire(limits, now);
+    count_evictions(stats, "flow_window", evicted);
+    let evicted = stats.ipflows.write().unwrap().expire(limits, now);
+    count_evictions(stats, "ipflow", evicted);
+    let evicted = stats.ipflow_windows.write().unwrap().expire(limits, now);
+    count_evictions(stats, "ipflow_window", evicted);
+    let evicted = stats.tunnels.write().unwrap().expire(limits, now);
+    count_evictions(stats, "tunnel", evicted);
+    let evicted = stats.asmatrix.write().unwrap().expire(limits, now);
+    count_evictions(stats, "asmatrix", evicted);
+    let evicted = stats.interfaces.write().unwrap().expire(limits, now);
+    count_evictions(stats, "interface", evicted);
+}
+
+fn count_evictions(stats: &Stats, name: &str, evicted: u64) {
+    if evicted > 0 {
+        *stats
+            .evictions
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert(0) += evicted;
+    }
+}
+
+// Malformed datagrams and records are dropped, but counted per error kind so
+// they show up on /metrics/errors instead of taking the decode thread down.
+// Nothing is printed, as one broken agent would flood the output.
+fn count_decode_error(decode_errors: &RwLock<HashMap<String, u64>>, kind: &str) {
+    *decode_errors
+        .write()
+        .unwrap()
+        .entry(kind.to_string())
+        .or_insert(0) += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ExpiryConfig;
+    use metrics::IpFlowCounterKey;
+    use queue::OverflowPolicy;
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+
+    // An ARP frame the replay skips, then one datagram from agent 172.16.1.19
+    // holding four expanded flow samples, each standing for 524288 packets.
+    const CAPTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/sflow.pcap");
+    const SAMPLING_RATE: u64 = 524288;
+
+    #[test]
+    fn replayed_capture_reaches_the_aggregates() {
+        let stats = Stats::default();
+        let (tx, rx, _) = queue::bounded(16, OverflowPolicy::Block);
+        let file = FileReceiver::new(Path::new(CAPTURE), "udp dst port 6343", false).unwrap();
+        let replicator = Replicator::new(&[]).unwrap();
+        receive(
+            ("file".to_string(), Box::new(file)),
+            &[tx],
+            &stats,
+            &replicator,
+        );
+        let decoder = Decoder {
+            stats: stats.clone(),
+            collectors: CollectorConfig::default(),
+            limits: ExpiryConfig::default().limits(),
+            exporter: None,
+        };
+        decoder.run(rx);
+
+        let agent = IpAddr::V4(Ipv4Addr::new(172, 16, 1, 19));
+        assert!(stats.decode_errors.read().unwrap().is_empty());
+        stats.merge_exporters();
+        assert_eq!(stats.exporters.read().unwrap()[&agent].packets, 1);
+        let agents = stats.agents.read().unwrap();
+        assert_eq!(agents[&agent].datagrams, 1);
+        assert_eq!(agents[&agent].samples["3"].packets, 4);
+
+        let flows = stats.flows.read().unwrap();
+        assert_eq!(
+            flows.values().map(|flow| flow.packets).sum::<u64>(),
+            4 * SAMPLING_RATE
+        );
+        assert!(flows
+            .keys()
+            .all(|key| key.vlan == 3210 && key.output_interface == 61));
+        // the sampled headers are Ethernet frames, keyed by their ethertype
+        assert!(flows
+            .keys()
+            .all(|key| matches!(key.protocol, 0x0800 | 0x86dd)));
+
+        let ipflows = stats.ipflows.read().unwrap();
+        assert_eq!(ipflows.len(), 4);
+        let https = &ipflows[&IpFlowCounterKey {
+            src_ip: IpAddr::V4(Ipv4Addr::new(104, 71, 60, 42)),
+            dst_ip: IpAddr::V4(Ipv4Addr::new(23, 63, 108, 100)),
+            protocol: 6,
+            src_port: 443,
+            dst_port: 36116,
+        }];
+        assert_eq!(https.estimate.packets, SAMPLING_RATE);
+        assert_eq!(https.estimate.bytes, 1500 * SAMPLING_RATE);
+        assert_eq!(https.tcp_flags, 0x10);
     }
 }
@@ -4,126 +4,913 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     fmt::{Display, Error, Formatter},
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
 };
 
+use crate::dissector::TunnelType;
+use crate::expiry::Expiring;
+use crate::queue::QueueStats;
+use crate::replicate::ReplicaStats;
 use crate::sflow5::*;
+use crate::topn::TopTalkers;
+use crate::window::{Merge, Windowed};
 
-#[derive(Debug)]
-pub enum CollectError {
-    InvalidSampleType(u32),
-    InvalidRecordType(u32),
-    InvalidIpv4Packet,
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Counter {
+    pub packets: u64,
+    pub bytes: u64,
+    #[serde(skip)]
+    pub last_seen: Option<Instant>,
 }
 
-impl Display for CollectError {
+impl Display for Counter {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        match self {
-            CollectError::InvalidSampleType(typ) => write!(f, "Invalid sample type: {typ}"),
-            CollectError::InvalidRecordType(typ) => write!(f, "Invalid record type: {typ}"),
-            CollectError::InvalidIpv4Packet => write!(f, "Invalid IPv4 packet"),
-        }
+        write!(f, "packets={},bytes={}", self.packets, self.bytes)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Counter {
+/// Packet and byte totals scaled up from flow samples. By the sFlow accuracy
+/// formula they are within ±196/sqrt(samples) percent of the real totals 95%
+/// of the time.
+#[derive(Serialize, Default, Debug)]
+pub struct Estimate {
     pub packets: u64,
     pub bytes: u64,
+    pub samples: u64,
+    #[serde(skip)]
+    first_seen: Option<Instant>,
+    #[serde(skip)]
+    last_seen: Option<Instant>,
 }
 
-impl Display for Counter {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
-        write!(f, "packets={},bytes={}", self.packets, self.bytes)
+impl Estimate {
+    fn add(&mut self, packets: u64, bytes: u64) {
+        self.packets += packets;
+        self.bytes += bytes;
+        self.samples += 1;
+        let now = Instant::now();
+        self.first_seen.get_or_insert(now);
+        self.last_seen = Some(now);
+    }
+
+    pub fn error_percent(&self) -> f64 {
+        if self.samples == 0 {
+            return 100.0;
+        }
+        196.0 / (self.samples as f64).sqrt()
+    }
+}
+
+impl Merge for Estimate {
+    fn merge(&mut self, other: &Self) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+        self.samples += other.samples;
+        self.first_seen = match (self.first_seen, other.first_seen) {
+            (Some(first), Some(other)) => Some(first.min(other)),
+            (first, other) => first.or(other),
+        };
+        self.last_seen = self.last_seen.max(other.last_seen);
+    }
+}
+
+impl Expiring for Estimate {
+    fn first_seen(&self) -> Option<Instant> {
+        self.first_seen
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.last_seen
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Expiring for Counter {
+    fn first_seen(&self) -> Option<Instant> {
+        None
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.last_seen
+    }
+
+    fn bytes(&self) -> u64 {
+        self.bytes
     }
 }
 
-impl Default for Counter {
-    fn default() -> Self {
-        Counter {
-            packets: 0,
-            bytes: 0,
+/// Per receiver counters, keyed by a name like "udp 0.0.0.0:6343 #0". Each
+/// receiver thread owns one, so counting a datagram never waits on the others.
+#[derive(Serialize, Debug, Default)]
+pub struct ReceiverStats {
+    pub kernel_drops: AtomicU64,
+    pub truncated: AtomicU64, // datagrams too large for the buffer, dropped
+    #[serde(skip)]
+    exporters: Mutex<HashMap<IpAddr, Counter>>, // since the last merge into Stats
+}
+
+impl ReceiverStats {
+    pub fn count_datagram(&self, exporter: IpAddr, bytes: usize, now: Instant) {
+        let mut exporters = self.exporters.lock().unwrap();
+        let counter = exporters.entry(exporter).or_default();
+        counter.packets += 1;
+        counter.bytes += bytes as u64;
+        counter.last_seen = Some(now);
+    }
+
+    // Stores only what changed, so an idle counter's cache line stays shared
+    pub fn set_kernel_drops(&self, drops: u64) {
+        if self.kernel_drops.load(Ordering::Relaxed) != drops {
+            self.kernel_drops.store(drops, Ordering::Relaxed);
+        }
+    }
+
+    pub fn set_truncated(&self, truncated: u64) {
+        if self.truncated.load(Ordering::Relaxed) != truncated {
+            self.truncated.store(truncated, Ordering::Relaxed);
         }
     }
 }
 
+/// What an agent sent and how much of it went missing on the way, going by
+/// the datagram and sample sequence numbers.
+#[derive(Serialize, Debug, Default)]
+pub struct AgentStats {
+    pub samples: HashMap<String, Counter>, // by sample type
+    pub datagrams: u64,
+    pub lost_datagrams: u64,
+    pub lost_samples: u64,
+    pub restarts: u64,
+    pub agent_drops: u64, // samples the agent couldn't export for lack of resources
+    pub sources: HashMap<String, SourceSampling>, // flow sampling by "type:index"
+    #[serde(skip)]
+    last_seen: Option<Instant>,
+    #[serde(skip)]
+    datagram_sequences: HashMap<u32, (u32, u32)>, // sub_agent_id -> (sequence, uptime)
+    #[serde(skip)]
+    sample_sequences: HashMap<(bool, u32, u32), u32>, // (counter, source id) -> sequence
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct SourceSampling {
+    pub sampling_rate: u32,           // as configured on the agent
+    pub effective_sampling_rate: f64, // packets seen per sample received
+    pub drops: u32,
+    #[serde(skip)]
+    sample_pool: u32,
+    #[serde(skip)]
+    pool_total: u64,
+    #[serde(skip)]
+    samples: u64,
+}
+
+impl SourceSampling {
+    // Every received sample stands for all the packets the source saw since
+    // the previous one, which makes up for samples the agent dropped or that
+    // got lost on the way. Without a previous sample, or once the pool was
+    // reset, the configured rate is all there is to go by.
+    fn update(&mut self, sample: &FlowSample) -> (u64, u32) {
+        let scale = match self.samples {
+            0 => sample.sampling_rate,
+            _ => sequence_gap(self.sample_pool, sample.sample_pool)
+                .filter(|delta| *delta > 0)
+                .unwrap_or(sample.sampling_rate),
+        };
+        let new_drops = match self.samples {
+            0 => 0,
+            _ => sequence_gap(self.drops, sample.drops).unwrap_or(0),
+        };
+        self.sampling_rate = sample.sampling_rate;
+        self.sample_pool = sample.sample_pool;
+        self.drops = sample.drops;
+        self.pool_total += scale as u64;
+        self.samples += 1;
+        self.effective_sampling_rate = self.pool_total as f64 / self.samples as f64;
+        (scale as u64, new_drops)
+    }
+}
+
+impl Expiring for AgentStats {
+    fn first_seen(&self) -> Option<Instant> {
+        None
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.last_seen
+    }
+
+    fn bytes(&self) -> u64 {
+        self.samples.values().map(|counter| counter.bytes).sum()
+    }
+}
+
+// Sequence numbers and uptime wrap, so anything less than half the number
+// space ahead counts as moving forward.
+fn sequence_gap(last: u32, current: u32) -> Option<u32> {
+    let gap = current.wrapping_sub(last);
+    (gap < 1 << 31).then_some(gap)
+}
+
+impl AgentStats {
+    pub fn record_datagram(&mut self, sub_agent_id: u32, sequence_number: u32, uptime: u32) {
+        self.datagrams += 1;
+        self.last_seen = Some(Instant::now());
+        let last = self
+            .datagram_sequences
+            .insert(sub_agent_id, (sequence_number, uptime));
+        let Some((last_sequence, last_uptime)) = last else {
+            return;
+        };
+        match (
+            sequence_gap(last_sequence, sequence_number),
+            sequence_gap(last_uptime, uptime),
+        ) {
+            // the agent started counting again, possibly from a sequence
+            // number past the last one if it was down long enough
+            (None, _) | (_, None) => self.restarts += 1,
+            (Some(gap), _) => self.lost_datagrams += gap.saturating_sub(1) as u64,
+        }
+    }
+
+    /// Accounts for `sample` and returns how many packets it stands for,
+    /// which is only meaningful for flow samples. `flow_sample` is `sample`
+    /// decoded, if it is a flow sample that could be.
+    pub fn record_sample(
+        &mut self,
+        sample: &SFlowSamplePacket,
+        flow_sample: Option<&FlowSample>,
+    ) -> u64 {
+        let counter = self
+            .samples
+            .entry(sample.get_sample_type().to_string())
+            .or_default();
+        counter.packets += 1;
+        counter.bytes += sample.get_sample_length() as u64;
+
+        // flow and counter samples of a source are numbered separately
+        let Ok((sequence_number, source_id_type, source_id_index)) = sample.get_sequence() else {
+            return 1;
+        };
+        let counter_sample = matches!(sample.get_sample_type(), 2 | 4);
+        let key = (counter_sample, source_id_type, source_id_index);
+        let last = self.sample_sequences.insert(key, sequence_number);
+        // a sequence going backwards means the source was reset, which the
+        // datagram sequence already reports as a restart
+        if let Some(gap) = last.and_then(|last| sequence_gap(last, sequence_number)) {
+            self.lost_samples += gap.saturating_sub(1) as u64;
+        }
+
+        let Some(flow_sample) = flow_sample else {
+            return 1;
+        };
+        let (scale, new_drops) = self
+            .sources
+            .entry(format!("{}:{}", source_id_type, source_id_index))
+            .or_default()
+            .update(flow_sample);
+        self.agent_drops += new_drops as u64;
+        scale
+    }
+}
+
+/// Takes counter samples, which are read straight from the datagram.
 pub trait Collector {
-    fn collect(&mut self, sample: SFlowSamplePacket) -> Result<(), CollectError>;
+    fn collect(&mut self, agent: IpAddr, sample: &SFlowSamplePacket) -> Result<(), DecodeError>;
+}
+
+/// Takes flows decoded from sFlow flow samples or NetFlow and IPFIX records,
+/// with counts already scaled up by the sampling rate.
+pub trait FlowSink {
+    fn add_flow(&mut self, agent: IpAddr, flow: &SampledFlow, packets: u64, bytes: u64);
 }
 
-#[derive(Eq, Hash, PartialEq, Serialize, Debug)]
+#[derive(Eq, Hash, PartialEq, Clone, Serialize, Debug)]
 pub struct FlowCounterKey {
     pub src_mac: MacAddr,
     pub dst_mac: MacAddr,
     pub vlan: u32,
     pub protocol: u32,
+    pub input_interface: u32,
+    pub output_interface: u32,
 }
 
-pub type FlowCounter = HashMap<FlowCounterKey, Counter>;
-
-impl Collector for FlowCounter {
-    fn collect(&mut self, sample: SFlowSamplePacket) -> Result<(), CollectError> {
-        match sample.get_sample_type() {
-            1 | 3 => {
-                //sFlow sample or an expanded sFlow sample
-                let mut key = FlowCounterKey {
-                    src_mac: MacAddr::zero(),
-                    dst_mac: MacAddr::zero(),
-                    vlan: 0,
-                    protocol: 0,
-                };
-                let pkts = sample.get_sampling_rate() as u64;
-                let mut bytes: u64 = 0;
-
-                for record in sample.get_records() {
-                    match record.get_record_type() {
-                        //TODO: enumerate these
-                        1 => {
-                            let raw_packet_header =
-                                SFlowRawHeaderPacket::new(record.payload()).unwrap();
-                            bytes = raw_packet_header.get_frame_length() as u64 * pkts as u64;
-                            key.protocol = raw_packet_header.get_protocol();
-                            key.src_mac = raw_packet_header.get_src_mac();
-                            key.dst_mac = raw_packet_header.get_dst_mac();
-                            match raw_packet_header.get_vlan() {
-                                Ok(vlan) => {
-                                    key.vlan = vlan;
-                                }
-                                Err(_) => {} // Do nothing, we can't expect every packet to have a VLAN
-                            }
-                        }
-                        2 => {
-                            let ethernet_frame: SFlowEthernetFrame = record.payload().into();
-                            key.src_mac = ethernet_frame.src_mac;
-                            key.dst_mac = ethernet_frame.dst_mac;
-                            key.protocol = ethernet_frame.ethertype;
-                        }
-                        3 => match SFlowIpv4Packet::new(record.payload()) {
-                            Some(ipv4) => {
-                                // key.protocol = ipv4.get_protocol();
-                                bytes = ipv4.get_length() as u64 * pkts as u64;
-                            }
-                            None => Err(CollectError::InvalidIpv4Packet)?,
-                        },
-                        4 => {
-                            let ipv6 = SFlowIpv6Packet::new(record.payload()).unwrap();
-                            // key.protocol = ipv6.get_protocol();
-                            bytes = ipv6.get_length() as u64 * pkts as u64;
-                        }
-                        1001 => {
-                            let extended_switch =
-                                SFlowExtendedSwitchPacket::new(record.payload()).unwrap();
-                            key.vlan = extended_switch.get_src_vlan();
-                        }
-                        typ => Err(CollectError::InvalidRecordType(typ))?,
-                    }
-                }
-                let counter = self.entry(key).or_insert(Counter::default());
-                counter.packets += pkts;
-                counter.bytes += bytes;
+pub type FlowCounter = HashMap<FlowCounterKey, Estimate>;
+
+impl FlowSink for FlowCounter {
+    fn add_flow(&mut self, _agent: IpAddr, flow: &SampledFlow, packets: u64, bytes: u64) {
+        // the ethertype, told by the IP version when no record gave it
+        let protocol = match (flow.ethertype, flow.ip.as_ref().map(|ip| ip.src_ip)) {
+            (Some(ethertype), _) => ethertype,
+            (None, Some(IpAddr::V4(_))) => 0x0800,
+            (None, Some(IpAddr::V6(_))) => 0x86dd,
+            (None, None) => 0,
+        };
+        let key = FlowCounterKey {
+            src_mac: flow.src_mac.unwrap_or(MacAddr::zero()),
+            dst_mac: flow.dst_mac.unwrap_or(MacAddr::zero()),
+            vlan: flow.vlan.unwrap_or(0),
+            protocol,
+            input_interface: flow.input_interface.unwrap_or(0),
+            output_interface: flow.output_interface.unwrap_or(0),
+        };
+        self.entry(key).or_default().add(packets, bytes);
+    }
+}
+
+#[derive(Eq, Hash, PartialEq, Clone, Serialize, Debug)]
+pub struct IpFlowCounterKey {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: u32,
+    pub src_port: u32,
+    pub dst_port: u32,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct IpFlowStats {
+    pub estimate: Estimate,
+    pub tcp_flags: u32, // every flag seen on the conversation
+    pub extended: SFlowExtendedData,
+}
+
+impl Merge for IpFlowStats {
+    fn merge(&mut self, other: &Self) {
+        self.estimate.merge(&other.estimate);
+        self.tcp_flags |= other.tcp_flags;
+        self.extended.update(other.extended.clone());
+    }
+}
+
+impl Expiring for IpFlowStats {
+    fn first_seen(&self) -> Option<Instant> {
+        self.estimate.first_seen
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.estimate.last_seen
+    }
+
+    fn bytes(&self) -> u64 {
+        self.estimate.bytes
+    }
+}
+
+pub type IpFlowCounter = HashMap<IpFlowCounterKey, IpFlowStats>;
+
+// Non-IP traffic is only accounted in FlowCounter
+fn add_ip_flow<'a>(
+    counter: &'a mut IpFlowCounter,
+    flow: &SampledFlow,
+    packets: u64,
+    bytes: u64,
+) -> Option<&'a mut IpFlowStats> {
+    let ip = flow.ip.as_ref()?;
+    let stats = counter
+        .entry(IpFlowCounterKey {
+            src_ip: ip.src_ip,
+            dst_ip: ip.dst_ip,
+            protocol: ip.protocol,
+            src_port: ip.src_port,
+            dst_port: ip.dst_port,
+        })
+        .or_default();
+    stats.estimate.add(packets, bytes);
+    stats.tcp_flags |= ip.tcp_flags;
+    Some(stats)
+}
+
+impl FlowSink for IpFlowCounter {
+    fn add_flow(&mut self, _agent: IpAddr, flow: &SampledFlow, packets: u64, bytes: u64) {
+        if let Some(stats) = add_ip_flow(self, flow, packets, bytes) {
+            stats.extended.update(flow.extended.clone());
+        }
+    }
+}
+
+/// Keys encapsulated traffic on both the VTEP-to-VTEP outer header and the
+/// tenant's inner addressing.
+#[derive(Eq, Hash, PartialEq, Clone, Serialize, Debug)]
+pub struct TunnelCounterKey {
+    pub tunnel_type: TunnelType,
+    pub vni: u32,
+    pub outer_src_ip: IpAddr,
+    pub outer_dst_ip: IpAddr,
+    pub src_ip: Option<IpAddr>,
+    pub dst_ip: Option<IpAddr>,
+    pub protocol: u32,
+    pub src_port: u32,
+    pub dst_port: u32,
+}
+
+pub type TunnelCounter = HashMap<TunnelCounterKey, Estimate>;
+
+impl FlowSink for TunnelCounter {
+    fn add_flow(&mut self, _agent: IpAddr, flow: &SampledFlow, packets: u64, bytes: u64) {
+        // tunnels are only visible in the raw header
+        let (Some(outer), Some(tunnel)) = (&flow.ip, &flow.tunnel) else {
+            return;
+        };
+        let inner = tunnel.inner.as_ref();
+        let counter = self
+            .entry(TunnelCounterKey {
+                tunnel_type: tunnel.tunnel_type,
+                vni: tunnel.vni.unwrap_or(0),
+                outer_src_ip: outer.src_ip,
+                outer_dst_ip: outer.dst_ip,
+                src_ip: inner.map(|ip| ip.src_ip),
+                dst_ip: inner.map(|ip| ip.dst_ip),
+                protocol: inner.map_or(0, |ip| ip.protocol),
+                src_port: inner.map_or(0, |ip| ip.src_port),
+                dst_port: inner.map_or(0, |ip| ip.dst_port),
+            })
+            .or_default();
+        counter.add(packets, bytes);
+    }
+}
+
+#[derive(Eq, Hash, PartialEq, Clone, Serialize, Debug)]
+pub struct AsMatrixKey {
+    pub src_as: u32,
+    pub src_peer_as: u32,
+    pub dst_as: u32,
+    pub dst_peer_as: u32,
+    pub next_hop: IpAddr,
+}
+
+pub type AsMatrix = HashMap<AsMatrixKey, Estimate>;
+
+impl FlowSink for AsMatrix {
+    fn add_flow(&mut self, _agent: IpAddr, flow: &SampledFlow, packets: u64, bytes: u64) {
+        // only agents exporting BGP data contribute to the matrix
+        let (Some(src_as), Some(dst_as), Some(next_hop)) =
+            (flow.src_as, flow.dst_as, flow.next_hop)
+        else {
+            return;
+        };
+        let counter = self
+            .entry(AsMatrixKey {
+                src_as,
+                src_peer_as: flow.src_peer_as.unwrap_or(0),
+                dst_as,
+                dst_peer_as: flow.dst_peer_as.unwrap_or(0),
+                next_hop,
+            })
+            .or_default();
+        counter.add(packets, bytes);
+    }
+}
+
+#[derive(Eq, Hash, PartialEq, Clone, Debug)]
+pub struct InterfaceCounterKey {
+    pub agent: IpAddr,
+    pub source_id_type: u32,
+    pub source_id_index: u32,
+}
+
+// 64 bit counters only go backwards when the agent resets them, while 32 bit
+// counters are expected to wrap.
+fn delta64(previous: u64, current: u64) -> u64 {
+    current.saturating_sub(previous)
+}
+
+fn delta32(previous: u32, current: u32) -> u64 {
+    current.wrapping_sub(previous) as u64
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct InterfaceStats {
+    pub if_index: u32,
+    pub if_type: u32,
+    pub if_speed: u64,
+    pub if_direction: u32,
+    pub if_status: u32,
+    pub in_octets: u64,
+    pub in_ucast_pkts: u32,
+    pub in_multicast_pkts: u32,
+    pub in_broadcast_pkts: u32,
+    pub in_discards: u32,
+    pub in_errors: u32,
+    pub out_octets: u64,
+    pub out_ucast_pkts: u32,
+    pub out_multicast_pkts: u32,
+    pub out_broadcast_pkts: u32,
+    pub out_discards: u32,
+    pub out_errors: u32,
+    pub in_bps: f64,
+    pub out_bps: f64,
+    pub in_pps: f64,
+    pub out_pps: f64,
+    #[serde(skip)]
+    updated: Option<Instant>,
+}
+
+impl InterfaceStats {
+    fn update(&mut self, counters: &SFlowGenericInterfaceCountersPacket, now: Instant) {
+        if let Some(updated) = self.updated {
+            let elapsed = now.duration_since(updated).as_secs_f64();
+            if elapsed > 0.0 {
+                let in_pkts = delta32(self.in_ucast_pkts, counters.get_if_in_ucast_pkts())
+                    + delta32(self.in_multicast_pkts, counters.get_if_in_multicast_pkts())
+                    + delta32(self.in_broadcast_pkts, counters.get_if_in_broadcast_pkts());
+                let out_pkts = delta32(self.out_ucast_pkts, counters.get_if_out_ucast_pkts())
+                    + delta32(
+                        self.out_multicast_pkts,
+                        counters.get_if_out_multicast_pkts(),
+                    )
+                    + delta32(
+                        self.out_broadcast_pkts,
+                        counters.get_if_out_broadcast_pkts(),
+                    );
+                self.in_bps =
+                    delta64(self.in_octets, counters.get_if_in_octets()) as f64 * 8.0 / elapsed;
+                self.out_bps =
+                    delta64(self.out_octets, counters.get_if_out_octets()) as f64 * 8.0 / elapsed;
+                self.in_pps = in_pkts as f64 / elapsed;
+                self.out_pps = out_pkts as f64 / elapsed;
+            }
+        }
+        self.if_index = counters.get_if_index();
+        self.if_type = counters.get_if_type();
+        self.if_speed = counters.get_if_speed();
+        self.if_direction = counters.get_if_direction();
+        self.if_status = counters.get_if_status();
+        self.in_octets = counters.get_if_in_octets();
+        self.in_ucast_pkts = counters.get_if_in_ucast_pkts();
+        self.in_multicast_pkts = counters.get_if_in_multicast_pkts();
+        self.in_broadcast_pkts = counters.get_if_in_broadcast_pkts();
+        self.in_discards = counters.get_if_in_discards();
+        self.in_errors = counters.get_if_in_errors();
+        self.out_octets = counters.get_if_out_octets();
+        self.out_ucast_pkts = counters.get_if_out_ucast_pkts();
+        self.out_multicast_pkts = counters.get_if_out_multicast_pkts();
+        self.out_broadcast_pkts = counters.get_if_out_broadcast_pkts();
+        self.out_discards = counters.get_if_out_discards();
+        self.out_errors = counters.get_if_out_errors();
+        self.updated = Some(now);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct EthernetStats {
+    pub alignment_errors: u32,
+    pub fcs_errors: u32,
+    pub single_collision_frames: u32,
+    pub multiple_collision_frames: u32,
+    pub sqe_test_errors: u32,
+    pub deferred_transmissions: u32,
+    pub late_collisions: u32,
+    pub excessive_collisions: u32,
+    pub internal_mac_transmit_errors: u32,
+    pub carrier_sense_errors: u32,
+    pub frame_too_longs: u32,
+    pub internal_mac_receive_errors: u32,
+    pub symbol_errors: u32,
+}
+
+impl From<&SFlowEthernetCountersPacket<'_>> for EthernetStats {
+    fn from(counters: &SFlowEthernetCountersPacket) -> Self {
+        EthernetStats {
+            alignment_errors: counters.get_alignment_errors(),
+            fcs_errors: counters.get_fcs_errors(),
+            single_collision_frames: counters.get_single_collision_frames(),
+            multiple_collision_frames: counters.get_multiple_collision_frames(),
+            sqe_test_errors: counters.get_sqe_test_errors(),
+            deferred_transmissions: counters.get_deferred_transmissions(),
+            late_collisions: counters.get_late_collisions(),
+            excessive_collisions: counters.get_excessive_collisions(),
+            internal_mac_transmit_errors: counters.get_internal_mac_transmit_errors(),
+            carrier_sense_errors: counters.get_carrier_sense_errors(),
+            frame_too_longs: counters.get_frame_too_longs(),
+            internal_mac_receive_errors: counters.get_internal_mac_receive_errors(),
+            symbol_errors: counters.get_symbol_errors(),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct VlanStats {
+    pub vlan_id: u32,
+    pub octets: u64,
+    pub ucast_pkts: u32,
+    pub multicast_pkts: u32,
+    pub broadcast_pkts: u32,
+    pub discards: u32,
+    pub bps: f64,
+    pub pps: f64,
+    #[serde(skip)]
+    updated: Option<Instant>,
+}
+
+impl VlanStats {
+    fn update(&mut self, counters: &SFlowVlanCountersPacket, now: Instant) {
+        if let Some(updated) = self.updated {
+            let elapsed = now.duration_since(updated).as_secs_f64();
+            if elapsed > 0.0 {
+                let pkts = delta32(self.ucast_pkts, counters.get_ucast_pkts())
+                    + delta32(self.multicast_pkts, counters.get_multicast_pkts())
+                    + delta32(self.broadcast_pkts, counters.get_broadcast_pkts());
+                self.bps = delta64(self.octets, counters.get_octets()) as f64 * 8.0 / elapsed;
+                self.pps = pkts as f64 / elapsed;
             }
-            typ => Err(CollectError::InvalidSampleType(typ))?,
         }
+        self.vlan_id = counters.get_vlan_id();
+        self.octets = counters.get_octets();
+        self.ucast_pkts = counters.get_ucast_pkts();
+        self.multicast_pkts = counters.get_multicast_pkts();
+        self.broadcast_pkts = counters.get_broadcast_pkts();
+        self.discards = counters.get_discards();
+        self.updated = Some(now);
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProcessorStats {
+    pub cpu_5s: f64,
+    pub cpu_1m: f64,
+    pub cpu_5m: f64,
+    pub total_memory: u64,
+    pub free_memory: u64,
+}
+
+impl From<&SFlowProcessorCountersPacket<'_>> for ProcessorStats {
+    fn from(counters: &SFlowProcessorCountersPacket) -> Self {
+        ProcessorStats {
+            cpu_5s: counters.get_cpu_5s() as f64 / 100.0,
+            cpu_1m: counters.get_cpu_1m() as f64 / 100.0,
+            cpu_5m: counters.get_cpu_5m() as f64 / 100.0,
+            total_memory: counters.get_total_memory(),
+            free_memory: counters.get_free_memory(),
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct InterfaceState {
+    pub interface: Option<InterfaceStats>,
+    pub ethernet: Option<EthernetStats>,
+    pub vlan: Option<VlanStats>,
+    pub processor: Option<ProcessorStats>,
+    #[serde(skip)]
+    last_seen: Option<Instant>,
+}
+
+// Interfaces only expire once their agent stops sending counters for them
+impl Expiring for InterfaceState {
+    fn first_seen(&self) -> Option<Instant> {
+        None
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.last_seen
+    }
+
+    fn bytes(&self) -> u64 {
+        // the octet counters are raw totals from the agent and can be near
+        // u64::MAX, or anything at all from a broken one
+        self.interface.as_ref().map_or(0, |interface| {
+            interface.in_octets.saturating_add(interface.out_octets)
+        })
+    }
+}
+
+pub type InterfaceCounter = HashMap<InterfaceCounterKey, InterfaceState>;
 
+impl Collector for InterfaceCounter {
+    fn collect(&mut self, agent: IpAddr, sample: &SFlowSamplePacket) -> Result<(), DecodeError> {
+        let sample = sample.get_counter_sample()?;
+        let now = Instant::now();
+        let state = self
+            .entry(InterfaceCounterKey {
+                agent,
+                source_id_type: sample.source_id_type,
+                source_id_index: sample.source_id_index,
+            })
+            .or_default();
+        state.last_seen = Some(now);
+
+        for record in sample.records {
+            match record.get_record_type() {
+                1 => {
+                    let counters = SFlowGenericInterfaceCountersPacket::new(record.payload())
+                        .ok_or(DecodeError::Truncated("generic interface counters"))?;
+                    state
+                        .interface
+                        .get_or_insert_with(InterfaceStats::default)
+                        .update(&counters, now);
+                }
+                2 => {
+                    let counters = SFlowEthernetCountersPacket::new(record.payload())
+                        .ok_or(DecodeError::Truncated("ethernet counters"))?;
+                    state.ethernet = Some((&counters).into());
+                }
+                5 => {
+                    let counters = SFlowVlanCountersPacket::new(record.payload())
+                        .ok_or(DecodeError::Truncated("vlan counters"))?;
+                    state
+                        .vlan
+                        .get_or_insert_with(VlanStats::default)
+                        .update(&counters, now);
+                }
+                1001 => {
+                    let counters = SFlowProcessorCountersPacket::new(record.payload())
+                        .ok_or(DecodeError::Truncated("processor counters"))?;
+                    state.processor = Some((&counters).into());
+                }
+                _ => {} // vendor and host counter records we don't track
+            }
+        }
         Ok(())
     }
 }
+
+/// The maps filled by the receiver and decode threads and served over HTTP.
+#[derive(Clone, Default)]
+pub struct Stats {
+    pub exporters: Arc<RwLock<HashMap<IpAddr, Counter>>>,
+    pub receivers: Arc<RwLock<HashMap<String, Arc<ReceiverStats>>>>,
+    pub queues: Arc<RwLock<Vec<Arc<QueueStats>>>>, // one per decode worker
+    pub replicas: Arc<RwLock<Vec<Arc<ReplicaStats>>>>, // one per destination
+    pub agents: Arc<RwLock<HashMap<IpAddr, AgentStats>>>,
+    pub decode_errors: Arc<RwLock<HashMap<String, u64>>>,
+    pub flows: Arc<RwLock<FlowCounter>>,
+    pub ipflows: Arc<RwLock<IpFlowCounter>>,
+    pub flow_windows: Arc<RwLock<Windowed<FlowCounter>>>,
+    pub ipflow_windows: Arc<RwLock<Windowed<IpFlowCounter>>>,
+    pub tunnels: Arc<RwLock<TunnelCounter>>,
+    pub asmatrix: Arc<RwLock<AsMatrix>>,
+    pub interfaces: Arc<RwLock<InterfaceCounter>>,
+    pub top: Arc<RwLock<TopTalkers>>,
+    pub evictions: Arc<RwLock<HashMap<String, u64>>>, // by map name
+}
+
+impl Stats {
+    /// Registers a receiver under `name` and returns the counters it fills.
+    pub fn add_receiver(&self, name: &str) -> Arc<ReceiverStats> {
+        let receiver = Arc::new(ReceiverStats::default());
+        self.receivers
+            .write()
+            .unwrap()
+            .insert(name.to_string(), receiver.clone());
+        receiver
+    }
+
+    /// Folds the per receiver exporter counts into `exporters`. Called before
+    /// `exporters` is swept or read, so each scrape sees every datagram.
+    pub fn merge_exporters(&self) {
+        let receivers = self.receivers.read().unwrap();
+        let mut exporters = self.exporters.write().unwrap();
+        for receiver in receivers.values() {
+            let counted = std::mem::take(&mut *receiver.exporters.lock().unwrap());
+            for (exporter, counter) in counted {
+                let merged = exporters.entry(exporter).or_default();
+                merged.packets += counter.packets;
+                merged.bytes += counter.bytes;
+                merged.last_seen = merged.last_seen.max(counter.last_seen);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    // A compact flow sample with no records, from ifIndex `source`
+    fn flow_sample(sequence: u32, source: u32, rate: u32, pool: u32, drops: u32) -> Vec<u8> {
+        words(&[1, 32, sequence, source, rate, pool, drops, source, 0, 0])
+    }
+
+    // A compact counter sample with no records, from ifIndex `source`
+    fn counter_sample(sequence: u32, source: u32) -> Vec<u8> {
+        words(&[2, 12, sequence, source, 0])
+    }
+
+    fn record(agent: &mut AgentStats, sample: &[u8]) -> u64 {
+        let sample = SFlowSamplePacket::new(sample).unwrap();
+        agent.record_sample(&sample, sample.get_flow_sample().ok().as_ref())
+    }
+
+    #[test]
+    fn counts_gaps_in_the_datagram_sequence_as_lost() {
+        let mut agent = AgentStats::default();
+        agent.record_datagram(0, 10, 1000);
+        agent.record_datagram(0, 11, 1001);
+        agent.record_datagram(0, 15, 1005);
+        assert_eq!(agent.datagrams, 3);
+        assert_eq!(agent.lost_datagrams, 3);
+
+        // sub-agents number their datagrams separately
+        agent.record_datagram(1, 500, 1005);
+        agent.record_datagram(0, 16, 1006);
+        assert_eq!(agent.lost_datagrams, 3);
+
+        // a repeated sequence number loses nothing
+        agent.record_datagram(0, 16, 1006);
+        assert_eq!(agent.lost_datagrams, 3);
+        assert_eq!(agent.restarts, 0);
+    }
+
+    #[test]
+    fn sequence_numbers_and_uptime_wrap_around() {
+        assert_eq!(sequence_gap(u32::MAX, 1), Some(2));
+        assert_eq!(sequence_gap(5, 4), None);
+
+        let mut agent = AgentStats::default();
+        agent.record_datagram(0, u32::MAX - 1, u32::MAX);
+        agent.record_datagram(0, 1, 10);
+        assert_eq!(agent.lost_datagrams, 2);
+        assert_eq!(agent.restarts, 0);
+    }
+
+    #[test]
+    fn detects_agent_restarts() {
+        let mut agent = AgentStats::default();
+        agent.record_datagram(0, 1000, 50_000);
+        // the sequence starts over
+        agent.record_datagram(0, 1, 50_100);
+        assert_eq!(agent.restarts, 1);
+        // the sequence moves on, but the uptime went back: the agent was down
+        // long enough for the new sequence to pass the old one
+        agent.record_datagram(0, 2000, 10);
+        assert_eq!(agent.restarts, 2);
+        assert_eq!(agent.lost_datagrams, 0);
+    }
+
+    #[test]
+    fn counts_gaps_in_the_sample_sequences_as_lost() {
+        let mut agent = AgentStats::default();
+        record(&mut agent, &flow_sample(1, 5, 100, 100, 0));
+        record(&mut agent, &flow_sample(4, 5, 100, 400, 0));
+        assert_eq!(agent.lost_samples, 2);
+
+        // counter samples and other sources have sequences of their own
+        record(&mut agent, &counter_sample(1, 5));
+        record(&mut agent, &counter_sample(3, 5));
+        record(&mut agent, &flow_sample(100, 6, 100, 100, 0));
+        assert_eq!(agent.lost_samples, 3);
+
+        // a reset source isn't counted as a loss
+        record(&mut agent, &flow_sample(1, 5, 100, 500, 0));
+        assert_eq!(agent.lost_samples, 3);
+
+        assert_eq!(agent.samples["1"].packets, 4);
+        assert_eq!(agent.samples["1"].bytes, 4 * 32);
+        assert_eq!(agent.samples["2"].packets, 2);
+    }
+
+    #[test]
+    fn scales_flow_samples_by_the_sample_pool_delta() {
+        let mut agent = AgentStats::default();
+        // nothing to take a delta from yet, so the configured rate it is
+        assert_eq!(record(&mut agent, &flow_sample(1, 5, 100, 1000, 0)), 100);
+        assert_eq!(record(&mut agent, &flow_sample(2, 5, 100, 1100, 0)), 100);
+        // two samples went missing, so this one stands for their packets too
+        assert_eq!(record(&mut agent, &flow_sample(5, 5, 100, 1400, 0)), 300);
+        let source = &agent.sources["0:5"];
+        assert_eq!(source.sampling_rate, 100);
+        assert_eq!(source.effective_sampling_rate, 500.0 / 3.0);
+
+        // the pool wraps around like a sequence number
+        let mut agent = AgentStats::default();
+        record(&mut agent, &flow_sample(1, 5, 100, u32::MAX - 49, 0));
+        assert_eq!(record(&mut agent, &flow_sample(2, 5, 100, 50, 0)), 100);
+    }
+
+    #[test]
+    fn falls_back_to_the_sampling_rate_when_the_pool_is_of_no_use() {
+        let mut agent = AgentStats::default();
+        record(&mut agent, &flow_sample(1, 5, 100, 1000, 0));
+        // an agent that doesn't fill in the pool
+        assert_eq!(record(&mut agent, &flow_sample(2, 5, 100, 1000, 0)), 100);
+        // a pool that went back because the source was reset
+        assert_eq!(record(&mut agent, &flow_sample(1, 5, 256, 10, 0)), 256);
+        assert_eq!(agent.sources["0:5"].sampling_rate, 256);
+        // counter samples stand for themselves
+        assert_eq!(record(&mut agent, &counter_sample(1, 5)), 1);
+    }
+
+    #[test]
+    fn adds_up_the_drops_of_each_source() {
+        let mut agent = AgentStats::default();
+        // drops from before the first sample aren't new
+        record(&mut agent, &flow_sample(1, 5, 100, 100, 7));
+        record(&mut agent, &flow_sample(2, 5, 100, 200, 10));
+        record(&mut agent, &flow_sample(1, 6, 100, 100, 1));
+        record(&mut agent, &flow_sample(2, 6, 100, 200, 3));
+        assert_eq!(agent.agent_drops, 5);
+        assert_eq!(agent.sources["0:5"].drops, 10);
+        // a drop counter that went back adds nothing
+        record(&mut agent, &flow_sample(3, 5, 100, 300, 0));
+        assert_eq!(agent.agent_drops, 5);
+    }
+}
@@ -0,0 +1,718 @@
+use crate::expiry::{EvictionPolicy, Expire, Expiring, Limits};
+use crate::sflow5::{SampledFlow, SampledIp};
+use byteorder::{BigEndian, ByteOrder};
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+// Templates and sampling rates an exporter stops refreshing are dropped after
+// this long, which also bounds the caches once exporters go away.
+const TEMPLATE_TIMEOUT: Duration = Duration::from_secs(3600);
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+// IPFIX fields of this length carry their real length in front of the value
+const VARIABLE_LENGTH: u16 = 65535;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Truncated(&'static str),
+    LengthOverflow(&'static str, u32),
+    UnsupportedVersion(u16),
+    UnknownTemplate(u16), // data before its template
+}
+
+impl DecodeError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DecodeError::Truncated(_) => "truncated",
+            DecodeError::LengthOverflow(_, _) => "length_overflow",
+            DecodeError::UnsupportedVersion(_) => "unsupported_version",
+            DecodeError::UnknownTemplate(_) => "unknown_template",
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            DecodeError::Truncated(what) => write!(f, "Truncated {what}"),
+            DecodeError::LengthOverflow(what, len) => {
+                write!(f, "{what} length {len} overflows its container")
+            }
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported NetFlow version: {version}")
+            }
+            DecodeError::UnknownTemplate(id) => write!(f, "Data for unknown template {id}"),
+        }
+    }
+}
+
+/// A NetFlow v9 or IPFIX flow record, with its counts already scaled up by the
+/// exporter's sampling rate.
+#[derive(Debug)]
+pub struct FlowRecord {
+    pub flow: SampledFlow,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Version {
+    Netflow9,
+    Ipfix,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Field {
+    id: u16,
+    length: u16,
+    variable: bool, // IPFIX only, NetFlow v9 has no variable length fields
+    // enterprise specific fields, and NetFlow v9 scope fields whose types
+    // overlap the regular ones, are stepped over without being read
+    ignored: bool,
+}
+
+struct Template {
+    fields: Vec<Field>,
+    options: bool,
+    last_seen: Instant,
+}
+
+struct Sampling {
+    rate: u64,
+    last_seen: Instant,
+}
+
+// Both caches only age on how long ago the exporter last announced an entry
+impl Expiring for Template {
+    fn first_seen(&self) -> Option<Instant> {
+        None
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        Some(self.last_seen)
+    }
+
+    fn bytes(&self) -> u64 {
+        0
+    }
+}
+
+impl Expiring for Sampling {
+    fn first_seen(&self) -> Option<Instant> {
+        None
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        Some(self.last_seen)
+    }
+
+    fn bytes(&self) -> u64 {
+        0
+    }
+}
+
+type TemplateKey = (IpAddr, u32, u16); // exporter, observation domain, template id
+type SamplerKey = (IpAddr, u32, Option<u64>); // exporter, domain, sampler id
+
+/// Decodes NetFlow v9 (RFC 3954) and IPFIX (RFC 7011) datagrams, keeping the
+/// templates and sampling options each exporter announced. An exporter is
+/// always handled by the same decode worker, so one decoder per worker sees
+/// all of its templates.
+pub struct NetflowDecoder {
+    templates: HashMap<TemplateKey, Template>,
+    sampling: HashMap<SamplerKey, Sampling>,
+    limits: Limits,
+    last_expiry: Option<Instant>,
+    evicted: u64,
+}
+
+/// Tells NetFlow v9 and IPFIX apart from sFlow, whose 32 bit version puts
+/// zeroes where their 16 bit one is.
+pub fn is_netflow(datagram: &[u8]) -> bool {
+    datagram.len() >= 2 && matches!(BigEndian::read_u16(datagram), 9 | 10)
+}
+
+fn read_u16(bytes: &[u8], offset: usize, what: &'static str) -> Result<u16, DecodeError> {
+    match bytes.get(offset..offset + 2) {
+        Some(word) => Ok(BigEndian::read_u16(word)),
+        None => Err(DecodeError::Truncated(what)),
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize, what: &'static str) -> Result<u32, DecodeError> {
+    match bytes.get(offset..offset + 4) {
+        Some(word) => Ok(BigEndian::read_u32(word)),
+        None => Err(DecodeError::Truncated(what)),
+    }
+}
+
+impl NetflowDecoder {
+    /// Keeps at most `max_entries` templates and as many sampling rates,
+    /// dropping the ones announced least recently beyond that.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            templates: HashMap::new(),
+            sampling: HashMap::new(),
+            limits: Limits {
+                idle_timeout: Some(TEMPLATE_TIMEOUT),
+                active_timeout: None,
+                max_entries,
+                eviction: EvictionPolicy::Lru,
+            },
+            last_expiry: None,
+            evicted: 0,
+        }
+    }
+
+    /// Returns how many templates and sampling rates were dropped since the
+    /// last call, for having expired or being over the cap.
+    pub fn take_evicted(&mut self) -> u64 {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Appends the flow records of `datagram` to `records`. Sets referring to
+    /// a template the exporter hasn't sent yet are skipped and reported once
+    /// the rest of the datagram is decoded, so `records` may have grown even
+    /// when this fails.
+    pub fn decode(
+        &mut self,
+        exporter: IpAddr,
+        datagram: &[u8],
+        records: &mut Vec<FlowRecord>,
+    ) -> Result<(), DecodeError> {
+        self.decode_at(exporter, datagram, records, Instant::now())
+    }
+
+    fn decode_at(
+        &mut self,
+        exporter: IpAddr,
+        datagram: &[u8],
+        records: &mut Vec<FlowRecord>,
+        now: Instant,
+    ) -> Result<(), DecodeError> {
+        let (version, domain, datagram, mut offset) = match read_u16(datagram, 0, "header")? {
+            9 => {
+                let source_id = read_u32(datagram, 16, "header")?;
+                (Version::Netflow9, source_id, datagram, 20)
+            }
+            10 => {
+                // the message length leaves out whatever padding came with it
+                let length = read_u16(datagram, 2, "header")?;
+                let message = datagram
+                    .get(..length as usize)
+                    .ok_or(DecodeError::LengthOverflow("message", length as u32))?;
+                let domain = read_u32(message, 12, "header")?;
+                (Version::Ipfix, domain, message, 16)
+            }
+            version => return Err(DecodeError::UnsupportedVersion(version)),
+        };
+        self.expire(now);
+
+        let mut skipped = Ok(());
+        while offset + 4 <= datagram.len() {
+            let set_id = read_u16(datagram, offset, "set header")?;
+            let length = read_u16(datagram, offset + 2, "set header")? as usize;
+            if length < 4 || offset + length > datagram.len() {
+                return Err(DecodeError::LengthOverflow("set", length as u32));
+            }
+            let body = &datagram[offset + 4..offset + length];
+            offset += length;
+            match (version, set_id) {
+                (Version::Netflow9, 0) | (Version::Ipfix, 2) => {
+                    self.template_set(version, exporter, domain, body, false, now)?
+                }
+                (Version::Netflow9, 1) | (Version::Ipfix, 3) => {
+                    self.template_set(version, exporter, domain, body, true, now)?
+                }
+                (_, 256..) => {
+                    if let Err(e) = self.data_set(exporter, domain, set_id, body, records, now) {
+                        skipped = Err(e);
+                    }
+                }
+                _ => {} // reserved set ids
+            }
+        }
+        skipped
+    }
+
+    fn expire(&mut self, now: Instant) {
+        if self
+            .last_expiry
+            .is_some_and(|last| now.duration_since(last) < EXPIRY_INTERVAL)
+        {
+            return;
+        }
+        self.last_expiry = Some(now);
+        self.evicted += self.templates.expire(&self.limits, now);
+        self.evicted += self.sampling.expire(&self.limits, now);
+    }
+
+    fn template_set(
+        &mut self,
+        version: Version,
+        exporter: IpAddr,
+        domain: u32,
+        body: &[u8],
+        options: bool,
+        now: Instant,
+    ) -> Result<(), DecodeError> {
+        let mut offset = 0;
+        // anything too short for another template header is padding
+        while offset + 4 <= body.len() {
+            let id = read_u16(body, offset, "template")?;
+            if id < 256 {
+                break;
+            }
+            let key = (exporter, domain, id);
+            let (scope_count, field_count) = match (version, options) {
+                (_, false) => {
+                    let count = read_u16(body, offset + 2, "template")?;
+                    offset += 4;
+                    (0, count)
+                }
+                // IPFIX withdraws a template by sending it without fields
+                (Version::Ipfix, true) if read_u16(body, offset + 2, "template")? == 0 => {
+                    offset += 4;
+                    (0, 0)
+                }
+                (Version::Ipfix, true) => {
+                    let count = read_u16(body, offset + 2, "options template")?;
+                    let scope_count = read_u16(body, offset + 4, "options template")?;
+                    offset += 6;
+                    (scope_count, count.saturating_sub(scope_count))
+                }
+                // NetFlow v9 gives the scope and option lengths in bytes
+                (Version::Netflow9, true) => {
+                    let scope_length = read_u16(body, offset + 2, "options template")?;
+                    let option_length = read_u16(body, offset + 4, "options template")?;
+                    offset += 6;
+                    (scope_length / 4, option_length / 4)
+                }
+            };
+            if scope_count + field_count == 0 {
+                self.templates.remove(&key);
+                continue;
+            }
+
+            let mut fields = Vec::new();
+            for i in 0..scope_count + field_count {
+                let id = read_u16(body, offset, "template field")?;
+                let length = read_u16(body, offset + 2, "template field")?;
+                offset += 4;
+                // the enterprise bit is followed by the private enterprise number
+                let enterprise = version == Version::Ipfix && id & 0x8000 != 0;
+                if enterprise {
+                    offset += 4;
+                }
+                fields.push(Field {
+                    id: id & 0x7fff,
+                    length,
+                    variable: version == Version::Ipfix && length == VARIABLE_LENGTH,
+                    ignored: enterprise || (version == Version::Netflow9 && i < scope_count),
+                });
+            }
+            if offset > body.len() {
+                return Err(DecodeError::Truncated("template field"));
+            }
+            let template = Template {
+                fields,
+                options,
+                last_seen: now,
+            };
+            self.templates.insert(key, template);
+            self.evicted += self.templates.enforce_cap(&self.limits);
+        }
+        Ok(())
+    }
+
+    fn data_set(
+        &mut self,
+        exporter: IpAddr,
+        domain: u32,
+        id: u16,
+        body: &[u8],
+        records: &mut Vec<FlowRecord>,
+        now: Instant,
+    ) -> Result<(), DecodeError> {
+        let template = self
+            .templates
+            .get(&(exporter, domain, id))
+            .ok_or(DecodeError::UnknownTemplate(id))?;
+        let min_length: usize = template
+            .fields
+            .iter()
+            .map(|field| match field.variable {
+                true => 1,
+                false => field.length as usize,
+            })
+            .sum();
+        if min_length == 0 {
+            return Ok(());
+        }
+
+        let mut offset = 0;
+        // anything too short for another record is padding
+        while offset + min_length <= body.len() {
+            let mut record = Record(Vec::with_capacity(template.fields.len()));
+            for field in &template.fields {
+                let mut length = field.length as usize;
+                if field.variable {
+                    length = *body
+                        .get(offset)
+                        .ok_or(DecodeError::Truncated("field length"))?
+                        as usize;
+                    offset += 1;
+                    if length == 255 {
+                        length = read_u16(body, offset, "field length")? as usize;
+                        offset += 2;
+                    }
+                }
+                let value = body
+                    .get(offset..offset + length)
+                    .ok_or(DecodeError::Truncated("data record"))?;
+                offset += length;
+                if !field.ignored {
+                    record.0.push((field.id, value));
+                }
+            }
+
+            let sampler = record.number(48).or(record.number(302));
+            if template.options {
+                // options records without a sampler id set the domain default
+                if let Some(rate) = record.sampling_rate() {
+                    let sampling = Sampling {
+                        rate,
+                        last_seen: now,
+                    };
+                    self.sampling.insert((exporter, domain, sampler), sampling);
+                    self.evicted += self.sampling.enforce_cap(&self.limits);
+                }
+                continue;
+            }
+            let rate = record
+                .sampling_rate()
+                .or_else(|| {
+                    let sampler = sampler?;
+                    Some(self.sampling.get(&(exporter, domain, Some(sampler)))?.rate)
+                })
+                .or_else(|| Some(self.sampling.get(&(exporter, domain, None))?.rate))
+                .unwrap_or(1);
+            if let Some(flow) = record.flow(rate) {
+                records.push(flow);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The fields of a data record, by information element id
+struct Record<'a>(Vec<(u16, &'a [u8])>);
+
+impl Record<'_> {
+    fn get(&self, id: u16) -> Option<&[u8]> {
+        self.0
+            .iter()
+            .find(|(field, _)| *field == id)
+            .map(|(_, value)| *value)
+    }
+
+    // Unsigned fields may be sent in fewer bytes than their type has (reduced
+    // size encoding, RFC 7011 section 6.2)
+    fn number(&self, id: u16) -> Option<u64> {
+        let value = self.get(id)?;
+        Some(value.iter().fold(0, |n, byte| n << 8 | *byte as u64))
+    }
+
+    fn small(&self, id: u16) -> Option<u32> {
+        self.number(id).map(|n| n as u32)
+    }
+
+    fn address(&self, id: u16) -> Option<IpAddr> {
+        match self.get(id)? {
+            value if value.len() == 4 => Some(Ipv4Addr::from(BigEndian::read_u32(value)).into()),
+            value if value.len() == 16 => Some(Ipv6Addr::from(BigEndian::read_u128(value)).into()),
+            _ => None,
+        }
+    }
+
+    fn mac(&self, id: u16) -> Option<MacAddr> {
+        match self.get(id)? {
+            &[a, b, c, d, e, f] => Some(MacAddr::new(a, b, c, d, e, f)),
+            _ => None,
+        }
+    }
+
+    /// One in how many packets were sampled, from a samplingInterval (or
+    /// samplerRandomInterval), packet interval and space, or size and
+    /// population.
+    fn sampling_rate(&self) -> Option<u64> {
+        let rate = if let Some(interval) = self.number(34).or(self.number(50)) {
+            interval
+        } else if let Some(interval) = self.number(305).filter(|n| *n > 0) {
+            (interval + self.number(306).unwrap_or(0)) / interval
+        } else {
+            let size = self.number(309).filter(|n| *n > 0)?;
+            self.number(310)? / size
+        };
+        Some(rate).filter(|rate| *rate > 0)
+    }
+
+    fn flow(&self, rate: u64) -> Option<FlowRecord> {
+        // only delta counts, the total ones (85 and 86) would count a
+        // long-lived flow over again with every record
+        let bytes = self.number(1).unwrap_or(0);
+        let packets = self.number(2).unwrap_or(0);
+        if packets == 0 && bytes == 0 {
+            return None;
+        }
+        let src_ip = self.address(8).or(self.address(27));
+        let dst_ip = self.address(12).or(self.address(28));
+        let ip = match (src_ip, dst_ip) {
+            (Some(src_ip), Some(dst_ip)) => Some(SampledIp {
+                src_ip,
+                dst_ip,
+                protocol: self.small(4).unwrap_or(0),
+                src_port: self.small(7).unwrap_or(0),
+                dst_port: self.small(11).unwrap_or(0),
+                tcp_flags: self.small(6).unwrap_or(0),
+            }),
+            _ => None,
+        };
+        let flow = SampledFlow {
+            src_mac: self.mac(56),
+            dst_mac: self.mac(80).or(self.mac(57)),
+            vlan: self.small(58).or(self.small(243)),
+            ip,
+            frame_length: (bytes / packets.max(1)) as u32,
+            input_interface: self.small(10),
+            output_interface: self.small(14),
+            src_as: self.small(16),
+            dst_as: self.small(17),
+            ..Default::default()
+        };
+        Some(FlowRecord {
+            flow,
+            packets: packets.saturating_mul(rate),
+            bytes: bytes.saturating_mul(rate),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A template of packetDeltaCount and interfaceName, the latter sent with
+    // the length IPFIX reserves for variable length fields, and one record.
+    fn message(version: u16) -> Vec<u8> {
+        let mut sets = Vec::new();
+        let template_set: u16 = if version == 10 { 2 } else { 0 };
+        for word in [template_set, 16, 256, 2, 2, 4, 82, VARIABLE_LENGTH, 256, 12] {
+            sets.extend_from_slice(&word.to_be_bytes());
+        }
+        sets.extend_from_slice(&10u32.to_be_bytes());
+        sets.extend_from_slice(&[3, b'e', b't', b'h']);
+
+        let mut message = version.to_be_bytes().to_vec();
+        match version {
+            10 => message.extend_from_slice(&(16 + sets.len() as u16).to_be_bytes()),
+            _ => message.extend_from_slice(&2u16.to_be_bytes()),
+        }
+        let header_length = if version == 10 { 16 } else { 20 };
+        message.resize(header_length, 0);
+        message.extend_from_slice(&sets);
+        message
+    }
+
+    #[test]
+    fn only_ipfix_has_variable_length_fields() {
+        let exporter = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut decoder = NetflowDecoder::new(1000);
+        let mut records = Vec::new();
+        decoder
+            .decode(exporter, &message(10), &mut records)
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].packets, 10);
+
+        // to NetFlow v9 the field is 65535 bytes long, more than the set holds
+        let mut records = Vec::new();
+        decoder.decode(exporter, &message(9), &mut records).unwrap();
+        assert!(records.is_empty());
+    }
+
+    fn words(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    fn set(id: u16, body: &[u8]) -> Vec<u8> {
+        let mut set = words(&[id, 4 + body.len() as u16]);
+        set.extend_from_slice(body);
+        set
+    }
+
+    // An IPFIX message of observation domain `domain`
+    fn ipfix(domain: u32, sets: &[Vec<u8>]) -> Vec<u8> {
+        let length = 16 + sets.iter().map(Vec::len).sum::<usize>() as u16;
+        let mut message = words(&[10, length, 0, 0, 0, 0]);
+        message.extend_from_slice(&domain.to_be_bytes());
+        for set in sets {
+            message.extend_from_slice(set);
+        }
+        message
+    }
+
+    // Template `id` of packetDeltaCount and octetDeltaCount
+    fn counts_template(id: u16) -> Vec<u8> {
+        set(2, &words(&[id, 2, 2, 4, 1, 4]))
+    }
+
+    fn counts(id: u16, packets: u32, bytes: u32) -> Vec<u8> {
+        let mut body = packets.to_be_bytes().to_vec();
+        body.extend_from_slice(&bytes.to_be_bytes());
+        set(id, &body)
+    }
+
+    fn exporter() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+    }
+
+    fn decode_at(
+        decoder: &mut NetflowDecoder,
+        message: &[u8],
+        now: Instant,
+    ) -> Result<Vec<FlowRecord>, DecodeError> {
+        let mut records = Vec::new();
+        decoder.decode_at(exporter(), message, &mut records, now)?;
+        Ok(records)
+    }
+
+    #[test]
+    fn keeps_templates_across_datagrams() {
+        let mut decoder = NetflowDecoder::new(1000);
+        let now = Instant::now();
+        let records = decode_at(&mut decoder, &ipfix(1, &[counts_template(256)]), now).unwrap();
+        assert!(records.is_empty());
+
+        let records = decode_at(&mut decoder, &ipfix(1, &[counts(256, 3, 300)]), now).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].packets, records[0].bytes), (3, 300));
+        assert_eq!(records[0].flow.frame_length, 100);
+
+        // templates belong to one exporter and observation domain
+        assert!(matches!(
+            decode_at(&mut decoder, &ipfix(2, &[counts(256, 3, 300)]), now),
+            Err(DecodeError::UnknownTemplate(256))
+        ));
+        let mut records = Vec::new();
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        assert!(matches!(
+            decoder.decode_at(other, &ipfix(1, &[counts(256, 3, 300)]), &mut records, now),
+            Err(DecodeError::UnknownTemplate(256))
+        ));
+    }
+
+    #[test]
+    fn scales_records_by_the_announced_sampling_rates() {
+        let mut decoder = NetflowDecoder::new(1000);
+        let now = Instant::now();
+        // options templates scoped on a selectorId and on the observation
+        // domain, each with a samplingInterval
+        let options = set(
+            3,
+            &words(&[257, 2, 1, 302, 2, 34, 4, 258, 2, 1, 149, 4, 34, 4]),
+        );
+        // a data template with a selectorId
+        let sampled = set(2, &words(&[259, 3, 302, 2, 2, 4, 1, 4]));
+        let mut rates = words(&[5]);
+        rates.extend_from_slice(&100u32.to_be_bytes());
+        let mut default_rate = 1u32.to_be_bytes().to_vec();
+        default_rate.extend_from_slice(&10u32.to_be_bytes());
+        let sets = [
+            options,
+            sampled,
+            counts_template(256),
+            set(257, &rates),
+            set(258, &default_rate),
+        ];
+        decode_at(&mut decoder, &ipfix(1, &sets), now).unwrap();
+
+        let mut from_selector = words(&[5]);
+        from_selector.extend_from_slice(&2u32.to_be_bytes());
+        from_selector.extend_from_slice(&200u32.to_be_bytes());
+        let mut unknown_selector = words(&[6]);
+        unknown_selector.extend_from_slice(&2u32.to_be_bytes());
+        unknown_selector.extend_from_slice(&200u32.to_be_bytes());
+        let sets = [
+            set(259, &from_selector),
+            set(259, &unknown_selector),
+            counts(256, 2, 200),
+        ];
+        let records = decode_at(&mut decoder, &ipfix(1, &sets), now).unwrap();
+        let scaled: Vec<_> = records.iter().map(|r| (r.packets, r.bytes)).collect();
+        // selectors without a rate of their own fall back to the domain's
+        assert_eq!(scaled, [(200, 20000), (20, 2000), (20, 2000)]);
+    }
+
+    #[test]
+    fn ignores_total_counts() {
+        let mut decoder = NetflowDecoder::new(1000);
+        let now = Instant::now();
+        let mut totals = set(2, &words(&[256, 2, 86, 8, 85, 8]));
+        let mut body = 5u64.to_be_bytes().to_vec();
+        body.extend_from_slice(&500u64.to_be_bytes());
+        totals.extend_from_slice(&set(256, &body));
+        assert!(decode_at(&mut decoder, &ipfix(1, &[totals]), now)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn expires_templates_that_are_not_refreshed() {
+        let mut decoder = NetflowDecoder::new(1000);
+        let start = Instant::now();
+        let templates = [counts_template(256), counts_template(257)];
+        decode_at(&mut decoder, &ipfix(1, &templates), start).unwrap();
+        let refreshed = start + TEMPLATE_TIMEOUT / 2;
+        decode_at(&mut decoder, &ipfix(1, &[counts_template(257)]), refreshed).unwrap();
+
+        let later = start + TEMPLATE_TIMEOUT + EXPIRY_INTERVAL;
+        assert!(matches!(
+            decode_at(&mut decoder, &ipfix(1, &[counts(256, 1, 100)]), later),
+            Err(DecodeError::UnknownTemplate(256))
+        ));
+        let records = decode_at(&mut decoder, &ipfix(1, &[counts(257, 1, 100)]), later).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(decoder.take_evicted(), 1);
+        assert_eq!(decoder.take_evicted(), 0);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_announced_templates_over_the_cap() {
+        let mut decoder = NetflowDecoder::new(10);
+        let start = Instant::now();
+        for (i, id) in (256..=266).enumerate() {
+            let now = start + Duration::from_secs(i as u64);
+            decode_at(&mut decoder, &ipfix(1, &[counts_template(id)]), now).unwrap();
+        }
+        // down to 90% of the cap, oldest first
+        assert_eq!(decoder.templates.len(), 9);
+        assert_eq!(decoder.take_evicted(), 2);
+        let now = start + Duration::from_secs(11);
+        for id in [256, 257] {
+            assert!(matches!(
+                decode_at(&mut decoder, &ipfix(1, &[counts(id, 1, 100)]), now),
+                Err(DecodeError::UnknownTemplate(_))
+            ));
+        }
+        assert_eq!(
+            decode_at(&mut decoder, &ipfix(1, &[counts(258, 1, 100)]), now)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,300 @@
+use crate::config::{OtlpConfig, OtlpProtocol};
+use crate::metrics::{FlowCounterKey, Stats};
+use opentelemetry::metrics::{
+    self, AsyncInstrument, Meter, MeterProvider as _, ObservableCounter, Unit,
+};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{MetricsExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::metrics::MeterProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use std::time::Duration;
+
+/// Pushes the exporter, agent and flow counters to an OpenTelemetry collector
+/// every `interval` seconds. The instruments are observable counters read
+/// straight from `Stats` at export time, so nothing is recorded on the decode
+/// path.
+#[tokio::main]
+pub async fn start_otlp_exporter(stats: Stats, config: OtlpConfig) {
+    let Some(endpoint) = config.endpoint.clone() else {
+        return;
+    };
+    let provider = match pipeline(endpoint, &config) {
+        Ok(provider) => provider,
+        Err(e) => {
+            println!("Error: cannot start the OTLP pipeline: {}", e);
+            return;
+        }
+    };
+    // the callbacks live as long as the instruments
+    let _instruments = instruments(&provider.meter("oxyflow"), &stats);
+    std::future::pending::<()>().await
+}
+
+fn pipeline(endpoint: String, config: &OtlpConfig) -> metrics::Result<MeterProvider> {
+    let exporter: MetricsExporterBuilder = match config.protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(endpoint)
+            .into(),
+        OtlpProtocol::Http => opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .into(),
+    };
+    opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(exporter)
+        .with_period(Duration::from_secs(config.interval.max(1)))
+        .with_resource(Resource::new([KeyValue::new("service.name", "oxyflow")]))
+        .build()
+}
+
+fn counter<F>(
+    meter: &Meter,
+    name: &'static str,
+    unit: &'static str,
+    description: &'static str,
+    stats: &Stats,
+    observe: F,
+) -> ObservableCounter<u64>
+where
+    F: Fn(&Stats, &dyn AsyncInstrument<u64>) + Send + Sync + 'static,
+{
+    let stats = stats.clone();
+    meter
+        .u64_observable_counter(name)
+        .with_unit(Unit::new(unit))
+        .with_description(description)
+        .with_callback(move |instrument| observe(&stats, instrument))
+        .init()
+}
+
+fn instruments(meter: &Meter, stats: &Stats) -> Vec<ObservableCounter<u64>> {
+    vec![
+        counter(
+            meter,
+            "oxyflow.exporter.datagrams",
+            "{datagram}",
+            "Datagrams received from each exporter",
+            stats,
+            |stats, instrument| {
+                stats.merge_exporters();
+                for (exporter, counter) in stats.exporters.read().unwrap().iter() {
+                    let exporter = KeyValue::new("exporter", exporter.to_string());
+                    instrument.observe(counter.packets, &[exporter]);
+                }
+            },
+        ),
+        counter(
+            meter,
+            "oxyflow.exporter.bytes",
+            "By",
+            "Bytes received from each exporter",
+            stats,
+            |stats, instrument| {
+                stats.merge_exporters();
+                for (exporter, counter) in stats.exporters.read().unwrap().iter() {
+                    let exporter = KeyValue::new("exporter", exporter.to_string());
+                    instrument.observe(counter.bytes, &[exporter]);
+                }
+            },
+        ),
+        counter(
+            meter,
+            "oxyflow.agent.samples",
+            "{sample}",
+            "Samples received from each agent by sample type",
+            stats,
+            |stats, instrument| {
+                for (agent, agent_stats) in stats.agents.read().unwrap().iter() {
+                    for (sample_type, counter) in &agent_stats.samples {
+                        let attributes = [
+                            KeyValue::new("agent", agent.to_string()),
+                            KeyValue::new("sample_type", sample_type.clone()),
+                        ];
+                        instrument.observe(counter.packets, &attributes);
+                    }
+                }
+            },
+        ),
+        counter(
+            meter,
+            "oxyflow.agent.lost_datagrams",
+            "{datagram}",
+            "Datagrams missing from each agent's sequence numbers",
+            stats,
+            |stats, instrument| {
+                for (agent, agent_stats) in stats.agents.read().unwrap().iter() {
+                    let agent = KeyValue::new("agent", agent.to_string());
+                    instrument.observe(agent_stats.lost_datagrams, &[agent]);
+                }
+            },
+        ),
+        counter(
+            meter,
+            "oxyflow.agent.lost_samples",
+            "{sample}",
+            "Samples missing from each agent's sample sequence numbers",
+            stats,
+            |stats, instrument| {
+                for (agent, agent_stats) in stats.agents.read().unwrap().iter() {
+                    let agent = KeyValue::new("agent", agent.to_string());
+                    instrument.observe(agent_stats.lost_samples, &[agent]);
+                }
+            },
+        ),
+        counter(
+            meter,
+            "oxyflow.flow.packets",
+            "{packet}",
+            "Packets estimated from flow samples",
+            stats,
+            |stats, instrument| {
+                for (key, estimate) in stats.flows.read().unwrap().iter() {
+                    instrument.observe(estimate.packets, &flow_attributes(key));
+                }
+            },
+        ),
+        counter(
+            meter,
+            "oxyflow.flow.bytes",
+            "By",
+            "Bytes estimated from flow samples",
+            stats,
+            |stats, instrument| {
+                for (key, estimate) in stats.flows.read().unwrap().iter() {
+                    instrument.observe(estimate.bytes, &flow_attributes(key));
+                }
+            },
+        ),
+    ]
+}
+
+// Every field of the key, so that no two flows report under the same
+// attributes
+fn flow_attributes(key: &FlowCounterKey) -> [KeyValue; 6] {
+    [
+        KeyValue::new("src_mac", key.src_mac.to_string()),
+        KeyValue::new("dst_mac", key.dst_mac.to_string()),
+        KeyValue::new("vlan", key.vlan as i64),
+        KeyValue::new("protocol", key.protocol as i64),
+        KeyValue::new("input_interface", key.input_interface as i64),
+        KeyValue::new("output_interface", key.output_interface as i64),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{AgentStats, Counter, Estimate};
+    use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+    use opentelemetry_proto::tonic::common::v1::any_value::Value;
+    use opentelemetry_proto::tonic::metrics::v1::metric::Data;
+    use pnet::util::MacAddr;
+    use prost::Message;
+    use std::collections::HashMap;
+    use warp::Filter;
+
+    #[test]
+    fn exports_the_counters_over_otlp_http() {
+        let stats = Stats::default();
+        let agent = "192.0.2.1".parse().unwrap();
+        stats
+            .exporters
+            .write()
+            .unwrap()
+            .insert(agent, Counter::default());
+        let mut agent_stats = AgentStats::default();
+        agent_stats
+            .samples
+            .insert("1".to_string(), Counter::default());
+        stats.agents.write().unwrap().insert(agent, agent_stats);
+        let key = FlowCounterKey {
+            src_mac: MacAddr(0, 1, 2, 3, 4, 5),
+            dst_mac: MacAddr(6, 7, 8, 9, 10, 11),
+            vlan: 3210,
+            protocol: 1,
+            input_interface: 38,
+            output_interface: 61,
+        };
+        let mut estimate = Estimate::default();
+        estimate.packets = 512;
+        stats.flows.write().unwrap().insert(key, estimate);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let route = warp::post()
+            .and(warp::path!("v1" / "metrics"))
+            .and(warp::body::bytes())
+            .map(move |body: bytes::Bytes| {
+                sender.send(body).unwrap();
+                ""
+            });
+        let provider = runtime.block_on(async {
+            let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+            tokio::spawn(server);
+            let config = OtlpConfig {
+                endpoint: None,
+                protocol: OtlpProtocol::Http,
+                interval: 3600,
+            };
+            pipeline(format!("http://{address}"), &config).unwrap()
+        });
+        let _instruments = instruments(&provider.meter("oxyflow"), &stats);
+        provider.force_flush().unwrap();
+
+        let body = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        let request = ExportMetricsServiceRequest::decode(body).unwrap();
+        let metrics: HashMap<_, _> = request
+            .resource_metrics
+            .iter()
+            .flat_map(|resource| &resource.scope_metrics)
+            .flat_map(|scope| &scope.metrics)
+            .map(|metric| (metric.name.as_str(), metric))
+            .collect();
+        let attributes = |name: &str| {
+            let Some(Data::Sum(sum)) = &metrics[name].data else {
+                panic!("{name} is not a sum");
+            };
+            let mut attributes: Vec<_> = sum.data_points[0]
+                .attributes
+                .iter()
+                .map(|kv| match &kv.value.as_ref().unwrap().value {
+                    Some(Value::StringValue(value)) => format!("{}={}", kv.key, value),
+                    Some(Value::IntValue(value)) => format!("{}={}", kv.key, value),
+                    value => panic!("unexpected value {value:?}"),
+                })
+                .collect();
+            attributes.sort();
+            attributes
+        };
+
+        assert_eq!(
+            attributes("oxyflow.exporter.datagrams"),
+            ["exporter=192.0.2.1"]
+        );
+        assert_eq!(
+            attributes("oxyflow.agent.samples"),
+            ["agent=192.0.2.1", "sample_type=1"]
+        );
+        assert_eq!(
+            attributes("oxyflow.flow.packets"),
+            [
+                "dst_mac=06:07:08:09:0a:0b",
+                "input_interface=38",
+                "output_interface=61",
+                "protocol=1",
+                "src_mac=00:01:02:03:04:05",
+                "vlan=3210",
+            ]
+        );
+        for name in [
+            "oxyflow.exporter.bytes",
+            "oxyflow.agent.lost_datagrams",
+            "oxyflow.agent.lost_samples",
+            "oxyflow.flow.bytes",
+        ] {
+            assert!(metrics.contains_key(name), "{name} is missing");
+        }
+    }
+}
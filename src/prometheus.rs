@@ -0,0 +1,533 @@
+use crate::metrics::{AgentStats, Counter, FlowCounter, Stats};
+use crate::replicate::ReplicaStats;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// Label value of the series everything over the cardinality limit is folded
+// into, so the totals across a family stay right.
+const OTHER: &str = "other";
+
+type Labels = Vec<(&'static str, String)>;
+type Series = Vec<(Labels, u64)>;
+
+// name, help and the stats field of a per-agent or per-replica family
+type AgentFamily = (&'static str, &'static str, fn(&AgentStats) -> u64);
+type ReplicaFamily = (&'static str, &'static str, fn(&ReplicaStats) -> &AtomicU64);
+
+/// Builds a scrape in either the Prometheus text format or OpenMetrics, which
+/// differ in how counters are declared and in the terminating `# EOF`.
+struct Exposition {
+    openmetrics: bool,
+    out: String,
+}
+
+impl Exposition {
+    fn counter(&mut self, name: &str, help: &str, series: &[(Labels, u64)]) {
+        // OpenMetrics names the family without the _total its samples carry
+        let family = match self.openmetrics {
+            true => name.to_string(),
+            false => format!("{}_total", name),
+        };
+        let _ = writeln!(self.out, "# HELP {} {}", family, help);
+        let _ = writeln!(self.out, "# TYPE {} counter", family);
+        for (labels, value) in series {
+            let _ = writeln!(self.out, "{}_total{} {}", name, render(labels), value);
+        }
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, series: &[(Labels, u64)]) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} gauge", name);
+        for (labels, value) in series {
+            let _ = writeln!(self.out, "{}{} {}", name, render(labels), value);
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if self.openmetrics {
+            self.out.push_str("# EOF\n");
+        }
+        self.out
+    }
+}
+
+fn render(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Whether each series of a family is emitted on its own (true) or summed into
+// `other` (false)
+type Membership = HashMap<Labels, bool>;
+
+// Decides for series not seen before whether they go on their own, heaviest
+// first while fewer than `max_series - 1` do, leaving a series for `other`. A
+// series never moves between the two afterwards, so that neither its own
+// counter nor `other` jumps. Series gone from the stats are forgotten.
+fn assign<'a>(
+    members: &mut Membership,
+    weights: impl Iterator<Item = (&'a Labels, u64)>,
+    max_series: usize,
+) {
+    let weights: HashMap<&Labels, u64> = weights.collect();
+    members.retain(|labels, _| weights.contains_key(labels));
+    let mut individual = members.values().filter(|individual| **individual).count();
+    let mut new: Vec<(&Labels, u64)> = weights
+        .into_iter()
+        .filter(|(labels, _)| !members.contains_key(*labels))
+        .collect();
+    new.sort_unstable_by_key(|(_, weight)| std::cmp::Reverse(*weight));
+    for (labels, _) in new {
+        let on_its_own = individual < max_series.saturating_sub(1);
+        individual += on_its_own as usize;
+        members.insert(labels.clone(), on_its_own);
+    }
+}
+
+/// Keeps at most `max_series` (packets, bytes) series, the ones the heaviest
+/// by bytes when first seen, summing the rest into `other`.
+fn cap_series(
+    members: &mut Membership,
+    series: HashMap<Labels, (u64, u64)>,
+    max_series: usize,
+    other: Labels,
+) -> Vec<(Labels, (u64, u64))> {
+    assign(
+        members,
+        series.iter().map(|(labels, (_, bytes))| (labels, *bytes)),
+        max_series,
+    );
+    let mut kept = Vec::new();
+    let mut folded = None;
+    for (labels, (packets, bytes)) in series {
+        if members[&labels] {
+            kept.push((labels, (packets, bytes)));
+        } else {
+            let (p, b) = folded.get_or_insert((0, 0));
+            *p += packets;
+            *b += bytes;
+        }
+    }
+    kept.extend(folded.map(|folded| (other, folded)));
+    kept
+}
+
+/// Renders scrapes, remembering across them which series each family put on
+/// their own and which it summed into `other`.
+pub struct Scraper {
+    max_series: usize,
+    membership: Mutex<HashMap<&'static str, Membership>>, // by family
+}
+
+impl Scraper {
+    pub fn new(max_series: usize) -> Self {
+        Self {
+            max_series,
+            membership: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Renders the exporter, agent, flow, queue, replica and decode error
+    /// stats as labelled series, at most `max_series` per family.
+    pub fn scrape(&self, stats: &Stats, openmetrics: bool) -> String {
+        let mut membership = self.membership.lock().unwrap();
+        let mut families = Families {
+            membership: &mut membership,
+            max_series: self.max_series,
+        };
+        scrape(stats, &mut families, openmetrics)
+    }
+}
+
+struct Families<'a> {
+    membership: &'a mut HashMap<&'static str, Membership>,
+    max_series: usize,
+}
+
+impl Families<'_> {
+    fn members(&mut self, family: &'static str) -> &mut Membership {
+        self.membership.entry(family).or_default()
+    }
+}
+
+fn scrape(stats: &Stats, families: &mut Families, openmetrics: bool) -> String {
+    let mut exposition = Exposition {
+        openmetrics,
+        out: String::new(),
+    };
+    stats.merge_exporters();
+    exporter_series(&mut exposition, &stats.exporters.read().unwrap(), families);
+    agent_series(&mut exposition, &stats.agents.read().unwrap(), families);
+    flow_series(&mut exposition, &stats.flows.read().unwrap(), families);
+
+    let queues = stats.queues.read().unwrap();
+    let worker = |i: usize| vec![("worker", i.to_string())];
+    let depth: Series = queues
+        .iter()
+        .enumerate()
+        .map(|(i, queue)| (worker(i), queue.depth.load(Ordering::Relaxed) as u64))
+        .collect();
+    exposition.gauge(
+        "oxyflow_queue_depth",
+        "Datagrams waiting in each decode worker's queue",
+        &depth,
+    );
+    let dropped: Series = queues
+        .iter()
+        .enumerate()
+        .map(|(i, queue)| (worker(i), queue.dropped.load(Ordering::Relaxed)))
+        .collect();
+    exposition.counter(
+        "oxyflow_queue_dropped",
+        "Datagrams dropped because a decode worker's queue was full",
+        &dropped,
+    );
+
+    let replicas = stats.replicas.read().unwrap();
+    let families: [ReplicaFamily; 4] = [
+        (
+            "oxyflow_replica_datagrams",
+            "Datagrams re-sent to each downstream collector",
+            |replica| &replica.datagrams,
+        ),
+        (
+            "oxyflow_replica_bytes",
+            "Bytes re-sent to each downstream collector",
+            |replica| &replica.bytes,
+        ),
+        (
+            "oxyflow_replica_filtered",
+            "Datagrams not re-sent to each downstream collector for its filters",
+            |replica| &replica.filtered,
+        ),
+        (
+            "oxyflow_replica_errors",
+            "Datagrams that failed to be re-sent to each downstream collector",
+            |replica| &replica.errors,
+        ),
+    ];
+    for (name, help, value) in families {
+        let series: Series = replicas
+            .iter()
+            .map(|replica| {
+                let destination = vec![("destination", replica.destination.to_string())];
+                (destination, value(replica).load(Ordering::Relaxed))
+            })
+            .collect();
+        exposition.counter(name, help, &series);
+    }
+
+    let decode_errors: Series = stats
+        .decode_errors
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(kind, count)| (vec![("kind", kind.clone())], *count))
+        .collect();
+    exposition.counter(
+        "oxyflow_decode_errors",
+        "Datagrams, samples and records dropped as undecodable, by error kind",
+        &decode_errors,
+    );
+    exposition.finish()
+}
+
+fn exporter_series(
+    exposition: &mut Exposition,
+    exporters: &HashMap<IpAddr, Counter>,
+    families: &mut Families,
+) {
+    let series = exporters
+        .iter()
+        .map(|(ip, counter)| {
+            let labels = vec![("exporter", ip.to_string())];
+            (labels, (counter.packets, counter.bytes))
+        })
+        .collect();
+    let other = vec![("exporter", OTHER.to_string())];
+    let max_series = families.max_series;
+    let series = cap_series(families.members("exporter"), series, max_series, other);
+    let (datagrams, bytes) = split(series);
+    exposition.counter(
+        "oxyflow_exporter_datagrams",
+        "Datagrams received from each exporter",
+        &datagrams,
+    );
+    exposition.counter(
+        "oxyflow_exporter_bytes",
+        "Bytes received from each exporter",
+        &bytes,
+    );
+}
+
+fn agent_series(
+    exposition: &mut Exposition,
+    agents: &HashMap<IpAddr, AgentStats>,
+    families: &mut Families,
+) {
+    let max_series = families.max_series;
+    let samples = agents
+        .iter()
+        .flat_map(|(agent, stats)| {
+            stats.samples.iter().map(move |(sample_type, counter)| {
+                let labels = vec![
+                    ("agent", agent.to_string()),
+                    ("sample_type", sample_type.clone()),
+                ];
+                (labels, (counter.packets, counter.bytes))
+            })
+        })
+        .collect();
+    let other = vec![
+        ("agent", OTHER.to_string()),
+        ("sample_type", OTHER.to_string()),
+    ];
+    let samples = cap_series(families.members("agent_sample"), samples, max_series, other);
+    let (samples, sample_bytes) = split(samples);
+    exposition.counter(
+        "oxyflow_agent_samples",
+        "Samples received from each agent by sample type",
+        &samples,
+    );
+    exposition.counter(
+        "oxyflow_agent_sample_bytes",
+        "Bytes of samples received from each agent by sample type",
+        &sample_bytes,
+    );
+
+    // one series per agent, the busiest ones by datagram count when first seen
+    let agents: Vec<(Labels, &AgentStats)> = agents
+        .iter()
+        .map(|(agent, stats)| (vec![("agent", agent.to_string())], stats))
+        .collect();
+    let members = families.members("agent");
+    assign(
+        members,
+        agents
+            .iter()
+            .map(|(labels, stats)| (labels, stats.datagrams)),
+        max_series,
+    );
+    let families: [AgentFamily; 5] = [
+        (
+            "oxyflow_agent_datagrams",
+            "Datagrams decoded from each agent",
+            |stats| stats.datagrams,
+        ),
+        (
+            "oxyflow_agent_lost_datagrams",
+            "Datagrams missing from each agent's sequence numbers",
+            |stats| stats.lost_datagrams,
+        ),
+        (
+            "oxyflow_agent_lost_samples",
+            "Samples missing from each agent's sample sequence numbers",
+            |stats| stats.lost_samples,
+        ),
+        (
+            "oxyflow_agent_restarts",
+            "Times each agent was seen restarting",
+            |stats| stats.restarts,
+        ),
+        (
+            "oxyflow_agent_drops",
+            "Samples each agent reported dropping for lack of resources",
+            |stats| stats.agent_drops,
+        ),
+    ];
+    for (name, help, value) in families {
+        let mut series = Series::new();
+        let mut folded = None;
+        for (labels, stats) in &agents {
+            match members[labels] {
+                true => series.push((labels.clone(), value(stats))),
+                false => *folded.get_or_insert(0) += value(stats),
+            }
+        }
+        if let Some(total) = folded {
+            series.push((vec![("agent", OTHER.to_string())], total));
+        }
+        exposition.counter(name, help, &series);
+    }
+}
+
+// Flows are summed over the interfaces, leaving the L2 labels
+fn flow_series(exposition: &mut Exposition, flows: &FlowCounter, families: &mut Families) {
+    let mut series: HashMap<Labels, (u64, u64)> = HashMap::new();
+    for (key, estimate) in flows {
+        let labels = vec![
+            ("src_mac", key.src_mac.to_string()),
+            ("dst_mac", key.dst_mac.to_string()),
+            ("vlan", key.vlan.to_string()),
+            ("protocol", key.protocol.to_string()),
+        ];
+        let totals = series.entry(labels).or_default();
+        totals.0 += estimate.packets;
+        totals.1 += estimate.bytes;
+    }
+    let other = ["src_mac", "dst_mac", "vlan", "protocol"]
+        .map(|name| (name, OTHER.to_string()))
+        .to_vec();
+    let max_series = families.max_series;
+    let series = cap_series(families.members("flow"), series, max_series, other);
+    let (packets, bytes) = split(series);
+    exposition.counter(
+        "oxyflow_flow_packets",
+        "Packets estimated from flow samples",
+        &packets,
+    );
+    exposition.counter(
+        "oxyflow_flow_bytes",
+        "Bytes estimated from flow samples",
+        &bytes,
+    );
+}
+
+// Splits (packets, bytes) series into one family each
+fn split(series: Vec<(Labels, (u64, u64))>) -> (Series, Series) {
+    series
+        .into_iter()
+        .map(|(labels, (packets, bytes))| ((labels.clone(), packets), (labels, bytes)))
+        .unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_exporter(stats: &Stats, exporter: &str, bytes: u64) {
+        stats.exporters.write().unwrap().insert(
+            exporter.parse().unwrap(),
+            Counter {
+                packets: 1,
+                bytes,
+                last_seen: None,
+            },
+        );
+    }
+
+    // The exporter_bytes samples of a scrape, by exporter label
+    fn exporter_bytes(scrape: &str) -> HashMap<String, u64> {
+        scrape
+            .lines()
+            .filter_map(|line| line.strip_prefix("oxyflow_exporter_bytes_total{exporter=\""))
+            .map(|line| {
+                let (exporter, value) = line.split_once("\"} ").unwrap();
+                (exporter.to_string(), value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn declares_counters_for_either_format() {
+        let stats = Stats::default();
+        add_exporter(&stats, "192.0.2.1", 1500);
+        stats
+            .decode_errors
+            .write()
+            .unwrap()
+            .insert("truncated".to_string(), 3);
+        let scraper = Scraper::new(10);
+
+        let prometheus = scraper.scrape(&stats, false);
+        assert!(prometheus.contains("# TYPE oxyflow_exporter_bytes_total counter\n"));
+        assert!(prometheus.contains("oxyflow_exporter_bytes_total{exporter=\"192.0.2.1\"} 1500\n"));
+        assert!(prometheus.contains("oxyflow_decode_errors_total{kind=\"truncated\"} 3\n"));
+        assert!(prometheus.contains("# TYPE oxyflow_queue_depth gauge\n"));
+        assert!(!prometheus.contains("# EOF"));
+
+        let openmetrics = scraper.scrape(&stats, true);
+        assert!(openmetrics.contains("# TYPE oxyflow_exporter_bytes counter\n"));
+        assert!(openmetrics.contains("oxyflow_exporter_bytes_total{exporter=\"192.0.2.1\"} 1500\n"));
+        assert!(openmetrics.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let labels = vec![("kind", "a \"b\"\\c\nd".to_string())];
+        assert_eq!(render(&labels), "{kind=\"a \\\"b\\\"\\\\c\\nd\"}");
+        assert_eq!(render(&Vec::new()), "");
+    }
+
+    #[test]
+    fn folds_series_over_the_limit_into_other() {
+        let stats = Stats::default();
+        add_exporter(&stats, "192.0.2.1", 300);
+        add_exporter(&stats, "192.0.2.2", 200);
+        add_exporter(&stats, "192.0.2.3", 100);
+        let scraper = Scraper::new(2);
+        let scraped = exporter_bytes(&scraper.scrape(&stats, false));
+        assert_eq!(scraped.len(), 2);
+        assert_eq!(scraped["192.0.2.1"], 300);
+        assert_eq!(scraped[OTHER], 300);
+    }
+
+    #[test]
+    fn series_stay_on_their_side_of_the_limit() {
+        let stats = Stats::default();
+        add_exporter(&stats, "192.0.2.1", 100);
+        add_exporter(&stats, "192.0.2.2", 50);
+        let scraper = Scraper::new(2);
+        scraper.scrape(&stats, false);
+
+        // 192.0.2.2 outgrowing 192.0.2.1 doesn't swap them
+        add_exporter(&stats, "192.0.2.2", 1000);
+        let scraped = exporter_bytes(&scraper.scrape(&stats, false));
+        assert_eq!(scraped["192.0.2.1"], 100);
+        assert_eq!(scraped[OTHER], 1000);
+
+        // and a heavy newcomer joins `other` once the limit is reached
+        add_exporter(&stats, "192.0.2.3", 5000);
+        let scraped = exporter_bytes(&scraper.scrape(&stats, false));
+        assert_eq!(scraped.len(), 2);
+        assert_eq!(scraped[OTHER], 6000);
+
+        // a series gone from the stats makes room for new ones
+        stats
+            .exporters
+            .write()
+            .unwrap()
+            .remove(&"192.0.2.1".parse().unwrap());
+        add_exporter(&stats, "192.0.2.4", 1);
+        let scraped = exporter_bytes(&scraper.scrape(&stats, false));
+        assert_eq!(scraped["192.0.2.4"], 1);
+        assert_eq!(scraped[OTHER], 6000);
+    }
+
+    #[test]
+    fn caps_the_per_agent_families_together() {
+        let stats = Stats::default();
+        for (agent, datagrams) in [("192.0.2.1", 10), ("192.0.2.2", 20), ("192.0.2.3", 30)] {
+            let mut agent_stats = AgentStats::default();
+            agent_stats.datagrams = datagrams;
+            agent_stats.restarts = 1;
+            let agent = agent.parse().unwrap();
+            stats.agents.write().unwrap().insert(agent, agent_stats);
+        }
+        let scrape = Scraper::new(2).scrape(&stats, false);
+        assert!(scrape.contains("oxyflow_agent_datagrams_total{agent=\"192.0.2.3\"} 30\n"));
+        assert!(scrape.contains("oxyflow_agent_datagrams_total{agent=\"other\"} 30\n"));
+        assert!(scrape.contains("oxyflow_agent_restarts_total{agent=\"192.0.2.3\"} 1\n"));
+        assert!(scrape.contains("oxyflow_agent_restarts_total{agent=\"other\"} 2\n"));
+    }
+}
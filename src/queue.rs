@@ -0,0 +1,144 @@
+use bytes::{Bytes, BytesMut};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+
+// Large enough for any datagram a socket receives off the wire, jumbo frames
+// included. Capture receivers reassemble fragments into larger ones.
+pub const MAX_DATAGRAM: usize = 9000;
+
+// Each receiver carves its datagrams out of one allocation this many
+// datagrams long, and reclaims it once the decoders have released them.
+const POOL_DATAGRAMS: usize = 64;
+
+/// What a receiver does when its worker's queue is full.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Discard the datagram that didn't fit and count it
+    DropNewest,
+    /// Wait for the worker, leaving the kernel to drop once its buffer fills
+    Block,
+}
+
+/// A received datagram and the address it came from, which is all NetFlow and
+/// IPFIX have to tell exporters apart.
+pub struct Datagram {
+    pub src: SocketAddr,
+    pub payload: Bytes,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct QueueStats {
+    pub capacity: usize,
+    pub depth: AtomicUsize,
+    pub enqueued: AtomicU64,
+    pub dropped: AtomicU64,
+}
+
+#[derive(Clone)]
+pub struct QueueSender {
+    tx: SyncSender<Datagram>,
+    policy: OverflowPolicy,
+    stats: Arc<QueueStats>,
+}
+
+pub struct QueueReceiver {
+    rx: Receiver<Datagram>,
+    stats: Arc<QueueStats>,
+}
+
+/// A bounded queue of datagrams that keeps its depth and drop count in
+/// `QueueStats`.
+pub fn bounded(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (QueueSender, QueueReceiver, Arc<QueueStats>) {
+    let (tx, rx) = mpsc::sync_channel(capacity);
+    let stats = Arc::new(QueueStats {
+        capacity,
+        ..Default::default()
+    });
+    let sender = QueueSender {
+        tx,
+        policy,
+        stats: stats.clone(),
+    };
+    let receiver = QueueReceiver {
+        rx,
+        stats: stats.clone(),
+    };
+    (sender, receiver, stats)
+}
+
+impl QueueSender {
+    /// Queues a datagram, returning false once the receiving side is gone.
+    pub fn send(&self, datagram: Datagram) -> bool {
+        // count before sending so the receiver never sees the depth go negative
+        self.stats.depth.fetch_add(1, Ordering::Relaxed);
+        let sent = match self.policy {
+            OverflowPolicy::Block => self.tx.send(datagram).is_ok(),
+            OverflowPolicy::DropNewest => match self.tx.try_send(datagram) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        };
+        if sent {
+            self.stats.enqueued.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        sent
+    }
+}
+
+impl QueueReceiver {
+    /// Waits for the next datagram, or None once every sender is gone.
+    pub fn recv(&self) -> Option<Datagram> {
+        let datagram = self.rx.recv().ok()?;
+        self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+        Some(datagram)
+    }
+}
+
+/// Hands out datagram buffers from a shared allocation. A frozen datagram
+/// keeps its slice of the allocation alive until the decoder drops it.
+pub struct BufferPool {
+    buf: BytesMut,
+    datagram_size: usize,
+}
+
+impl BufferPool {
+    /// A pool lending out buffers of `datagram_size` bytes.
+    pub fn new(datagram_size: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(datagram_size * POOL_DATAGRAMS),
+            datagram_size,
+        }
+    }
+
+    /// Lends out a buffer of `datagram_size` bytes to receive into. Whatever
+    /// the last datagram didn't use is lent out again as it is, so only as
+    /// many bytes as it took get initialized anew.
+    pub fn buffer(&mut self) -> &mut [u8] {
+        if self.buf.capacity() < self.datagram_size {
+            // reuses the allocation if everything handed out from it is gone
+            self.buf.reserve(self.datagram_size * POOL_DATAGRAMS);
+        }
+        self.buf.resize(self.datagram_size, 0);
+        &mut self.buf
+    }
+
+    /// Takes the first `len` bytes of the last buffer as a datagram.
+    pub fn take(&mut self, len: usize) -> Bytes {
+        self.buf.split_to(len).freeze()
+    }
+}
@@ -0,0 +1,470 @@
+use crate::config::ReplicaConfig;
+use crate::sflow5::SFlowPacket;
+use byteorder::{BigEndian, ByteOrder};
+use nix::errno::Errno;
+use nix::sys::socket::{
+    sendto, socket, AddressFamily, MsgFlags, SockFlag, SockProtocol, SockType, SockaddrStorage,
+};
+use pnet::packet::Packet;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+const TTL: u8 = 64;
+
+#[derive(Serialize, Debug)]
+pub struct ReplicaStats {
+    pub destination: SocketAddr,
+    pub datagrams: AtomicU64,
+    pub bytes: AtomicU64,
+    pub filtered: AtomicU64, // datagrams the filters left nothing of
+    pub errors: AtomicU64,
+}
+
+struct Replica {
+    config: ReplicaConfig,
+    socket: UdpSocket,
+    // sends with a source address of our choosing, including its IP header
+    raw: Option<OwnedFd>,
+    stats: Arc<ReplicaStats>,
+    flow_samples: AtomicU64, // seen, for sampling down
+    datagrams: AtomicU64,    // seen, for sampling down what isn't sFlow v5
+    failed: AtomicBool,      // the first failed send was logged
+}
+
+/// Re-sends every received datagram to downstream collectors, as it came in
+/// unless a replica filters or samples it down. Only sFlow v5 samples can be
+/// told apart without decoding the whole datagram, so other datagrams are
+/// filtered by their source address in place of the agent's, sampled down
+/// whole, and not sent at all to replicas filtering on sample type.
+pub struct Replicator {
+    replicas: Vec<Replica>,
+}
+
+impl Replicator {
+    pub fn new(configs: &[ReplicaConfig]) -> Result<Self, io::Error> {
+        let replicas = configs
+            .iter()
+            .map(|config| {
+                let (local, family): (SocketAddr, _) = match config.destination {
+                    SocketAddr::V4(_) => (([0, 0, 0, 0], 0).into(), AddressFamily::Inet),
+                    SocketAddr::V6(_) => (([0u16; 8], 0).into(), AddressFamily::Inet6),
+                };
+                if config
+                    .spoof_source
+                    .is_some_and(|source| source.is_ipv4() != config.destination.is_ipv4())
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("cannot spoof a source for {}", config.destination),
+                    ));
+                }
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(config.destination)?;
+                // IPPROTO_RAW implies the header is included, and needs
+                // CAP_NET_RAW
+                let raw = match config.preserve_source || config.spoof_source.is_some() {
+                    true => Some(socket_raw(family, config.destination)?),
+                    false => None,
+                };
+                let stats = Arc::new(ReplicaStats {
+                    destination: config.destination,
+                    datagrams: AtomicU64::new(0),
+                    bytes: AtomicU64::new(0),
+                    filtered: AtomicU64::new(0),
+                    errors: AtomicU64::new(0),
+                });
+                let mut config = config.clone();
+                config.sample_down = config.sample_down.filter(|rate| *rate > 1);
+                Ok(Replica {
+                    config,
+                    socket,
+                    raw,
+                    stats,
+                    flow_samples: AtomicU64::new(0),
+                    datagrams: AtomicU64::new(0),
+                    failed: AtomicBool::new(false),
+                })
+            })
+            .collect::<Result<_, io::Error>>()?;
+        Ok(Self { replicas })
+    }
+
+    pub fn stats(&self) -> Vec<Arc<ReplicaStats>> {
+        self.replicas
+            .iter()
+            .map(|replica| replica.stats.clone())
+            .collect()
+    }
+
+    pub fn replicate(&self, src: SocketAddr, datagram: &[u8]) {
+        if self.replicas.is_empty() {
+            return;
+        }
+        let sflow = SFlowPacket::decode(datagram).ok();
+        let agent = sflow
+            .as_ref()
+            .and_then(|sflow| sflow.get_agent_address().ok())
+            .unwrap_or(src.ip());
+        for replica in &self.replicas {
+            replica.replicate(src, agent, datagram, sflow.as_ref());
+        }
+    }
+}
+
+// Counts one more and tells whether it is the one in `rate` that is kept
+fn one_in(seen: &AtomicU64, rate: u32) -> bool {
+    seen.fetch_add(1, Ordering::Relaxed)
+        .is_multiple_of(rate as u64)
+}
+
+fn socket_raw(family: AddressFamily, destination: SocketAddr) -> Result<OwnedFd, io::Error> {
+    let raw = socket(
+        family,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::Raw,
+    );
+    raw.map_err(|e| match e {
+        Errno::EPERM => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "cannot replicate to {destination}: spoofing or preserving the source \
+                 needs CAP_NET_RAW"
+            ),
+        ),
+        e => e.into(),
+    })
+}
+
+impl Replica {
+    fn replicate(
+        &self,
+        src: SocketAddr,
+        agent: IpAddr,
+        datagram: &[u8],
+        sflow: Option<&SFlowPacket>,
+    ) {
+        let config = &self.config;
+        let selected = match sflow {
+            _ if !config.agents.is_empty() && !config.agents.contains(&agent) => None,
+            Some(sflow) if !config.sample_types.is_empty() || config.sample_down.is_some() => {
+                self.select(datagram, sflow).map(Cow::Owned)
+            }
+            None if !config.sample_types.is_empty() => None,
+            None => match config.sample_down {
+                Some(rate) if !one_in(&self.datagrams, rate) => None,
+                _ => Some(Cow::Borrowed(datagram)),
+            },
+            Some(_) => Some(Cow::Borrowed(datagram)),
+        };
+        let Some(payload) = selected else {
+            self.stats.filtered.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        match self.send(src, &payload) {
+            Ok(_) => {
+                self.stats.datagrams.fetch_add(1, Ordering::Relaxed);
+                self.stats
+                    .bytes
+                    .fetch_add(payload.len() as u64, Ordering::Relaxed);
+            }
+            // a destination that is down fails every send, so only the
+            // first failure is logged and the rest show in the errors count
+            Err(e) => {
+                self.stats.errors.fetch_add(1, Ordering::Relaxed);
+                if !self.failed.swap(true, Ordering::Relaxed) {
+                    println!("Error: cannot replicate to {}: {}", config.destination, e);
+                }
+            }
+        }
+    }
+
+    // Rebuilds the datagram with only the samples this replica takes, or
+    // None if that leaves none. Flow samples kept while sampling down have
+    // their sampling rate scaled up so that estimates downstream stay right.
+    fn select(&self, datagram: &[u8], sflow: &SFlowPacket) -> Option<Vec<u8>> {
+        let samples = sflow.get_samples().ok()?;
+        let address_length = match sflow.get_agent_address_type() {
+            1 => 4,
+            2 => 16,
+            _ => 0,
+        };
+        // version, address type and address, then sub agent id, sequence
+        // number, uptime and the sample count
+        let header_length = 8 + address_length + 16;
+        let mut out = datagram.get(..header_length)?.to_vec();
+        let mut kept = 0;
+        for sample in &samples {
+            let sample_type = sample.get_sample_type();
+            let types = &self.config.sample_types;
+            if !types.is_empty() && !types.contains(&sample_type) {
+                continue;
+            }
+            let start = out.len();
+            out.extend_from_slice(sample.packet());
+            if let (Some(rate), 1 | 3) = (self.config.sample_down, sample_type) {
+                if !one_in(&self.flow_samples, rate) {
+                    out.truncate(start);
+                    continue;
+                }
+                // past the sample's type and length, its sequence number and
+                // its compact or expanded source id
+                let offset = start + 12 + if sample_type == 1 { 4 } else { 8 };
+                let Some(field) = out.get_mut(offset..offset + 4) else {
+                    out.truncate(start);
+                    continue;
+                };
+                let sampling_rate = BigEndian::read_u32(field).saturating_mul(rate);
+                BigEndian::write_u32(field, sampling_rate);
+            }
+            kept += 1;
+        }
+        if kept == 0 {
+            return None;
+        }
+        BigEndian::write_u32(&mut out[header_length - 4..header_length], kept);
+        Some(out)
+    }
+
+    fn send(&self, src: SocketAddr, payload: &[u8]) -> Result<usize, io::Error> {
+        let destination = self.config.destination;
+        let source = match self.config.spoof_source {
+            Some(ip) => Some(SocketAddr::new(ip, src.port())),
+            None => Some(src).filter(|_| self.config.preserve_source),
+        };
+        // an exporter of the other address family can't be passed off as
+        // the source, so those datagrams go out from our own address
+        match (&self.raw, source) {
+            (Some(raw), Some(source)) if source.is_ipv4() == destination.is_ipv4() => {
+                let packet = ip_packet(source, destination, payload)?;
+                // raw sockets take the port of the protocol, not UDP's
+                let address = SockaddrStorage::from(SocketAddr::new(destination.ip(), 0));
+                match sendto(raw.as_raw_fd(), &packet, &address, MsgFlags::empty()) {
+                    // the kernel won't fragment a packet we wrote the IP
+                    // header of, so those over the MTU go out from our own
+                    // address and get fragmented like any other datagram
+                    Err(Errno::EMSGSIZE) => self.socket.send(payload),
+                    sent => Ok(sent?),
+                }
+            }
+            _ => self.socket.send(payload),
+        }
+    }
+}
+
+// An IPv4 or IPv6 header and a UDP header in front of the payload. The kernel
+// fills in the IPv4 checksum and identification. Routers on the way may
+// fragment it, but payloads too long for the length fields are rejected.
+fn ip_packet(
+    source: SocketAddr,
+    destination: SocketAddr,
+    payload: &[u8],
+) -> Result<Vec<u8>, io::Error> {
+    let udp_length = 8 + payload.len();
+    let ip_header_length = if destination.is_ipv4() { 20 } else { 0 };
+    if ip_header_length + udp_length > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("datagram of {} bytes is too long", payload.len()),
+        ));
+    }
+    let mut udp = Vec::with_capacity(udp_length);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_length as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(40 + udp_length);
+    let mut pseudo_header = Vec::with_capacity(40);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + udp_length) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0, 0, TTL, 17, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&src.octets());
+            pseudo_header.extend_from_slice(&dst.octets());
+            pseudo_header.extend_from_slice(&[0, 17]);
+            pseudo_header.extend_from_slice(&(udp_length as u16).to_be_bytes());
+        }
+        (src, dst) => {
+            let (src, dst) = (ipv6_octets(src), ipv6_octets(dst));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_length as u16).to_be_bytes());
+            packet.extend_from_slice(&[17, TTL]);
+            packet.extend_from_slice(&src);
+            packet.extend_from_slice(&dst);
+            pseudo_header.extend_from_slice(&src);
+            pseudo_header.extend_from_slice(&dst);
+            pseudo_header.extend_from_slice(&(udp_length as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, 17]);
+        }
+    }
+    let checksum = udp_checksum(&pseudo_header, &udp);
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    Ok(packet)
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+// RFC 768: the ones' complement sum over the pseudo header and the datagram,
+// where a result of zero is sent as all ones
+fn udp_checksum(pseudo_header: &[u8], udp: &[u8]) -> u16 {
+    let mut sum: u64 = 0;
+    for bytes in [pseudo_header, udp] {
+        for word in bytes.chunks(2) {
+            sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u64;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    match !(sum as u16) {
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    // A v5 datagram from 192.0.2.1 with sequence number 42
+    fn datagram(samples: &[Vec<u8>]) -> Vec<u8> {
+        let header = words(&[5, 1, 0xc000_0201, 0, 42, 1000, samples.len() as u32]);
+        [header, samples.concat()].concat()
+    }
+
+    // Flow samples with no records, compact (1) or expanded (3), from ifIndex
+    // `source` with a sample pool of 1000
+    fn flow_sample(sample_type: u32, source: u32, rate: u32) -> Vec<u8> {
+        match sample_type {
+            1 => words(&[1, 32, 1, source, rate, 1000, 0, source, 0, 0]),
+            _ => words(&[3, 44, 1, 0, source, rate, 1000, 0, 0, source, 0, 0, 0]),
+        }
+    }
+
+    fn counter_sample() -> Vec<u8> {
+        words(&[2, 12, 1, 5, 0])
+    }
+
+    fn replica(config: ReplicaConfig) -> Replicator {
+        Replicator::new(&[config]).unwrap()
+    }
+
+    fn select(replicator: &Replicator, datagram: &[u8]) -> Option<Vec<u8>> {
+        let sflow = SFlowPacket::decode(datagram).unwrap();
+        replicator.replicas[0].select(datagram, &sflow)
+    }
+
+    fn destination() -> SocketAddr {
+        ([127, 0, 0, 1], 6343).into()
+    }
+
+    #[test]
+    fn selects_samples_by_type() {
+        let samples = [
+            flow_sample(1, 5, 100),
+            counter_sample(),
+            flow_sample(3, 6, 100),
+            counter_sample(),
+        ];
+        let datagram = datagram(&samples);
+        let replicator = replica(ReplicaConfig {
+            sample_types: vec![2],
+            ..destination().into()
+        });
+        let selected = select(&replicator, &datagram).unwrap();
+        let sflow = SFlowPacket::decode(&selected).unwrap();
+        assert_eq!(sflow.get_sequence_number().unwrap(), 42);
+        let types: Vec<_> = sflow
+            .get_samples()
+            .unwrap()
+            .iter()
+            .map(|sample| sample.get_sample_type())
+            .collect();
+        assert_eq!(types, [2, 2]);
+        assert_eq!(selected.len(), 28 + 2 * counter_sample().len());
+
+        let replicator = replica(ReplicaConfig {
+            sample_types: vec![4],
+            ..destination().into()
+        });
+        assert!(select(&replicator, &datagram).is_none());
+    }
+
+    #[test]
+    fn scales_up_the_rate_of_flow_samples_kept_when_sampling_down() {
+        let samples = [
+            flow_sample(1, 5, 100),
+            flow_sample(1, 5, 100),
+            flow_sample(3, 6, 300),
+            flow_sample(3, 6, 300),
+            counter_sample(),
+        ];
+        let replicator = replica(ReplicaConfig {
+            sample_down: Some(2),
+            ..destination().into()
+        });
+        let selected = select(&replicator, &datagram(&samples)).unwrap();
+        let sflow = SFlowPacket::decode(&selected).unwrap();
+        let samples = sflow.get_samples().unwrap();
+        assert_eq!(samples.len(), 3);
+        for (sample, (source, rate)) in samples.iter().zip([(5, 200), (6, 600)]) {
+            let flow_sample = sample.get_flow_sample().unwrap();
+            assert_eq!(flow_sample.source_id_index, source);
+            assert_eq!(flow_sample.sampling_rate, rate);
+            assert_eq!(flow_sample.sample_pool, 1000);
+        }
+        assert_eq!(samples[2].get_sample_type(), 2);
+    }
+
+    #[test]
+    fn checksums_like_rfc_1071() {
+        // the example of RFC 1071 section 3
+        assert_eq!(
+            udp_checksum(&[0x00, 0x01, 0xf2, 0x03], &[0xf4, 0xf5, 0xf6, 0xf7]),
+            0x220d
+        );
+        // an odd byte is padded with a zero
+        assert_eq!(udp_checksum(&[], &[0x00, 0x01, 0xf2]), !0xf201);
+        // a zero checksum means there is none, so it goes out as all ones
+        assert_eq!(udp_checksum(&[], &[0xff, 0xff]), 0xffff);
+    }
+
+    #[test]
+    fn rejects_payloads_too_long_for_the_length_fields() {
+        let v4 = (([192, 0, 2, 1], 6343).into(), destination());
+        let v6: (SocketAddr, SocketAddr) = (
+            "[2001:db8::1]:6343".parse().unwrap(),
+            "[2001:db8::2]:6343".parse().unwrap(),
+        );
+        for ((source, destination), longest) in [(v4, 65507), (v6, 65527)] {
+            let packet = ip_packet(source, destination, &vec![0; longest]).unwrap();
+            let length_field = if source.is_ipv4() { 2 } else { 4 };
+            assert_eq!(
+                BigEndian::read_u16(&packet[length_field..]),
+                65535,
+                "{destination}"
+            );
+            assert!(ip_packet(source, destination, &vec![0; longest + 1]).is_err());
+        }
+    }
+}
@@ -0,0 +1,405 @@
+use crate::sflow5::DecodeError;
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+
+// v4 counter blocks carry no length, it follows from the counters type
+const GENERIC_COUNTERS_LENGTH: usize = 88;
+const ETHERNET_COUNTERS_LENGTH: usize = 52;
+const TOKENRING_COUNTERS_LENGTH: usize = 72;
+const VG_COUNTERS_LENGTH: usize = 80;
+const VLAN_COUNTERS_LENGTH: usize = 28;
+
+// v4 samples and their data are XDR unions without a length in front, so the
+// only way to find where one ends is to walk through every field of it.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    what: &'static str,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        let value = self
+            .bytes
+            .get(self.offset..)
+            .and_then(|rest| rest.get(..length))
+            .ok_or(DecodeError::Truncated(self.what))?;
+        self.offset += length;
+        Ok(value)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    // An address along with its type, laid out the same in both versions
+    fn address(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.offset;
+        match self.u32()? {
+            0 => {}
+            1 => _ = self.take(4)?,
+            2 => _ = self.take(16)?,
+            typ => return Err(DecodeError::UnsupportedAddressType(typ)),
+        }
+        Ok(&self.bytes[start..self.offset])
+    }
+
+    // A variable length array (opaque<>, string<>, unsigned int<>) along with
+    // its count and padding
+    fn array(&mut self, element_size: usize) -> Result<&'a [u8], DecodeError> {
+        let start = self.offset;
+        let length = self.u32()? as usize * element_size;
+        self.take(length.div_ceil(4) * 4)?;
+        Ok(&self.bytes[start..self.offset])
+    }
+
+    fn since(&self, start: usize) -> &'a [u8] {
+        &self.bytes[start..self.offset]
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+// A v5 sample or record: its type, length and body
+fn put_structure(out: &mut Vec<u8>, typ: u32, body: &[u8]) {
+    put_u32(out, typ);
+    put_u32(out, body.len() as u32);
+    out.extend_from_slice(body);
+}
+
+/// Rewrites an sFlow v4 (RFC 3176) datagram into the v5 datagram carrying
+/// the same samples, so that it is read through the same sample model.
+/// Every other version is passed through for `SFlowPacket::decode` to accept
+/// or reject.
+pub fn upgrade(datagram: Bytes) -> Result<Bytes, DecodeError> {
+    if datagram.len() < 4 || BigEndian::read_u32(&datagram) != 4 {
+        return Ok(datagram);
+    }
+    let mut cursor = Cursor {
+        bytes: &datagram,
+        offset: 4,
+        what: "datagram header",
+    };
+    let agent_address = cursor.address()?;
+    let sequence_number = cursor.u32()?;
+    let uptime = cursor.u32()?;
+    let num_samples = cursor.u32()?;
+
+    let mut out = Vec::with_capacity(datagram.len() * 2);
+    put_u32(&mut out, 5);
+    out.extend_from_slice(agent_address);
+    put_u32(&mut out, 0); // v4 has no sub-agents
+    put_u32(&mut out, sequence_number);
+    put_u32(&mut out, uptime);
+    put_u32(&mut out, num_samples);
+    for _ in 0..num_samples {
+        cursor.what = "sample";
+        // the compact v5 sample types share their numbers with v4
+        let typ = cursor.u32()?;
+        let sample = match typ {
+            1 => flow_sample(&mut cursor)?,
+            2 => counter_sample(&mut cursor)?,
+            typ => return Err(DecodeError::UnsupportedSampleType(typ)),
+        };
+        put_structure(&mut out, typ, &sample);
+    }
+    Ok(out.into())
+}
+
+// The v4 flow sample header matches the compact v5 one. The sampled packet
+// and each extended data structure become v5 flow records.
+fn flow_sample(cursor: &mut Cursor) -> Result<Vec<u8>, DecodeError> {
+    cursor.what = "flow sample";
+    let mut sample = Vec::new();
+    // sequence, source id, sampling rate, sample pool, drops, input, output
+    sample.extend_from_slice(cursor.take(28)?);
+
+    let mut records: Vec<(u32, Vec<u8>)> = Vec::new();
+    match cursor.u32()? {
+        1 => {
+            cursor.what = "sampled header";
+            let mut header = Vec::new();
+            put_u32(&mut header, cursor.u32()?); // header protocol
+            put_u32(&mut header, cursor.u32()?); // frame length
+            put_u32(&mut header, 0); // v4 doesn't report stripped bytes
+            header.extend_from_slice(cursor.array(1)?);
+            records.push((1, header));
+        }
+        2 => {
+            cursor.what = "sampled IPv4";
+            records.push((3, cursor.take(32)?.to_vec()));
+        }
+        3 => {
+            cursor.what = "sampled IPv6";
+            records.push((4, cursor.take(56)?.to_vec()));
+        }
+        typ => return Err(DecodeError::UnsupportedRecordType(typ)),
+    }
+
+    cursor.what = "extended data";
+    let mut next_hop = None;
+    let mut gateway = None;
+    for _ in 0..cursor.u32()? {
+        match cursor.u32()? {
+            1 => records.push((1001, cursor.take(16)?.to_vec())),
+            2 => {
+                let start = cursor.offset;
+                next_hop = Some(cursor.address()?);
+                cursor.take(8)?; // source and destination mask lengths
+                records.push((1002, cursor.since(start).to_vec()));
+            }
+            3 => {
+                let start = cursor.offset;
+                cursor.take(12)?; // as, src_as, src_peer_as
+                for _ in 0..cursor.u32()? {
+                    cursor.u32()?; // segment type
+                    cursor.array(4)?;
+                }
+                cursor.array(4)?; // communities
+                cursor.u32()?; // localpref
+                gateway = Some(cursor.since(start));
+            }
+            4 => {
+                // v5 added a character set in front of each user name
+                let mut user = Vec::new();
+                put_u32(&mut user, 0);
+                user.extend_from_slice(cursor.array(1)?);
+                put_u32(&mut user, 0);
+                user.extend_from_slice(cursor.array(1)?);
+                records.push((1004, user));
+            }
+            5 => {
+                let mut url = cursor.take(4)?.to_vec(); // direction
+                url.extend_from_slice(cursor.array(1)?);
+                put_u32(&mut url, 0); // v5 added the host, left empty
+                records.push((1005, url));
+            }
+            typ => return Err(DecodeError::UnsupportedRecordType(typ)),
+        }
+    }
+    // v5 moved the next hop into the gateway record, v4 only has it in the
+    // router one
+    if let Some(gateway) = gateway {
+        let mut body = next_hop.unwrap_or(&[0, 0, 0, 0]).to_vec();
+        body.extend_from_slice(gateway);
+        records.push((1003, body));
+    }
+
+    put_u32(&mut sample, records.len() as u32);
+    for (typ, body) in records {
+        put_structure(&mut sample, typ, &body);
+    }
+    Ok(sample)
+}
+
+// The v4 counter sample header matches the compact v5 one but for the
+// sampling interval, which v5 dropped. The counter blocks become v5 records.
+fn counter_sample(cursor: &mut Cursor) -> Result<Vec<u8>, DecodeError> {
+    cursor.what = "counter sample";
+    let mut sample = Vec::new();
+    sample.extend_from_slice(cursor.take(8)?); // sequence, source id
+    cursor.u32()?; // sampling interval
+    let blocks: &[(u32, usize)] = match cursor.u32()? {
+        1 | 4 | 6 => &[(1, GENERIC_COUNTERS_LENGTH)], // generic, FDDI, WAN
+        2 => &[(1, GENERIC_COUNTERS_LENGTH), (2, ETHERNET_COUNTERS_LENGTH)],
+        3 => &[(1, GENERIC_COUNTERS_LENGTH), (3, TOKENRING_COUNTERS_LENGTH)],
+        5 => &[(1, GENERIC_COUNTERS_LENGTH), (4, VG_COUNTERS_LENGTH)],
+        7 => &[(5, VLAN_COUNTERS_LENGTH)],
+        typ => return Err(DecodeError::UnsupportedRecordType(typ)),
+    };
+    put_u32(&mut sample, blocks.len() as u32);
+    for (typ, length) in blocks {
+        put_structure(&mut sample, *typ, cursor.take(*length)?);
+    }
+    Ok(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sflow5::*;
+    use pnet::packet::Packet;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = words(&[value.len() as u32]);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.resize(4 + value.len().div_ceil(4) * 4, 0);
+        bytes
+    }
+
+    // A v4 datagram from 192.0.2.1 with sequence number 42 and uptime 1000
+    fn datagram(samples: &[Vec<u8>]) -> Bytes {
+        let header = words(&[4, 1, 0xc000_0201, 42, 1000, samples.len() as u32]);
+        [header, samples.concat()].concat().into()
+    }
+
+    // A flow sample of an IPv4 packet from ifIndex 5 to 6, sampled 1 in 100,
+    // followed by `extended` records
+    fn flow_sample(extended: &[Vec<u8>]) -> Vec<u8> {
+        let header = words(&[1, 1, 5, 100, 1000, 0, 5, 6]);
+        let ipv4 = words(&[2, 1500, 6, 0x0a00_0001, 0x0a00_0002, 1234, 80, 0x18, 0]);
+        let extended = [words(&[extended.len() as u32]), extended.concat()].concat();
+        [header, ipv4, extended].concat()
+    }
+
+    fn counter_sample(counters_type: u32, blocks: &[Vec<u8>]) -> Vec<u8> {
+        [words(&[2, 3, 5, 30, counters_type]), blocks.concat()].concat()
+    }
+
+    // Generic interface counters of ifIndex 5, 1000 octets in and 2000 out
+    fn generic_counters() -> Vec<u8> {
+        let mut counters = words(&[5, 6, 0, 1_000_000_000, 1, 3, 0, 1000]);
+        counters.resize(GENERIC_COUNTERS_LENGTH, 0);
+        counters[56..64].copy_from_slice(&2000u64.to_be_bytes());
+        counters
+    }
+
+    fn decode(datagram: Bytes) -> Bytes {
+        upgrade(datagram).unwrap()
+    }
+
+    #[test]
+    fn upgrades_the_datagram_header() {
+        let upgraded = decode(datagram(&[]));
+        let datagram = SFlowPacket::decode(&upgraded).unwrap();
+        assert_eq!(
+            datagram.get_agent_address().unwrap(),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+        );
+        assert_eq!(datagram.get_sub_agent_id().unwrap(), 0);
+        assert_eq!(datagram.get_sequence_number().unwrap(), 42);
+        assert_eq!(datagram.get_uptime().unwrap(), 1000);
+        assert!(datagram.get_samples().unwrap().is_empty());
+    }
+
+    #[test]
+    fn upgrades_flow_samples_with_router_gateway_and_user_data() {
+        let router = words(&[2, 1, 0xc000_02fe, 24, 16]);
+        let gateway = words(&[3, 65000, 65001, 65002, 1, 2, 2, 65003, 65004, 1, 100, 200]);
+        let user = [words(&[4]), string("alice"), string("bob")].concat();
+        let upgraded = decode(datagram(&[flow_sample(&[router, gateway, user])]));
+
+        let datagram = SFlowPacket::decode(&upgraded).unwrap();
+        let samples = datagram.get_samples().unwrap();
+        assert_eq!(samples.len(), 1);
+        let sample = samples[0].get_flow_sample().unwrap();
+        assert_eq!((sample.source_id_type, sample.source_id_index), (0, 5));
+        assert_eq!(sample.sampling_rate, 100);
+        let flow = sample.sampled_flow().unwrap();
+        assert_eq!(
+            (flow.input_interface, flow.output_interface),
+            (Some(5), Some(6))
+        );
+        assert_eq!(flow.frame_length, 1500);
+        let ip = flow.ip.unwrap();
+        assert_eq!(ip.src_ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(ip.dst_ip, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!((ip.protocol, ip.src_port, ip.dst_port), (6, 1234, 80));
+
+        let next_hop = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254));
+        let router = flow.extended.router.unwrap();
+        assert_eq!(router.next_hop, next_hop);
+        assert_eq!((router.src_mask_len, router.dst_mask_len), (24, 16));
+        // v4 only has the next hop in the router data, v5 also in the gateway
+        let gateway = flow.extended.gateway.unwrap();
+        assert_eq!(gateway.next_hop, next_hop);
+        assert_eq!(gateway.as_number, 65000);
+        assert_eq!((gateway.src_as, gateway.src_peer_as), (65001, 65002));
+        assert_eq!(gateway.dst_as_path.len(), 1);
+        assert_eq!(gateway.dst_as_path[0].segment_type, 2);
+        assert_eq!(gateway.dst_as_path[0].as_numbers, [65003, 65004]);
+        assert_eq!(gateway.communities, [100]);
+        assert_eq!(gateway.local_pref, 200);
+        assert_eq!((flow.src_as, flow.dst_as), (Some(65001), Some(65004)));
+        assert_eq!(flow.next_hop, Some(next_hop));
+        let user = flow.extended.user.unwrap();
+        assert_eq!((user.src_charset, user.src_user.as_str()), (0, "alice"));
+        assert_eq!((user.dst_charset, user.dst_user.as_str()), (0, "bob"));
+    }
+
+    #[test]
+    fn upgrades_ethernet_counter_samples() {
+        let ethernet = [words(&[7, 8]), vec![0; ETHERNET_COUNTERS_LENGTH - 8]].concat();
+        let sample = counter_sample(2, &[generic_counters(), ethernet]);
+        let upgraded = decode(datagram(&[sample]));
+
+        let datagram = SFlowPacket::decode(&upgraded).unwrap();
+        let samples = datagram.get_samples().unwrap();
+        let sample = samples[0].get_counter_sample().unwrap();
+        assert_eq!((sample.source_id_type, sample.source_id_index), (0, 5));
+        let types: Vec<_> = sample.records.iter().map(|r| r.get_record_type()).collect();
+        assert_eq!(types, [1, 2]);
+
+        let generic =
+            SFlowGenericInterfaceCountersPacket::new(sample.records[0].payload()).unwrap();
+        assert_eq!(generic.get_if_index(), 5);
+        assert_eq!(generic.get_if_speed(), 1_000_000_000);
+        assert_eq!(generic.get_if_in_octets(), 1000);
+        assert_eq!(generic.get_if_out_octets(), 2000);
+        let ethernet = SFlowEthernetCountersPacket::new(sample.records[1].payload()).unwrap();
+        assert_eq!(ethernet.get_alignment_errors(), 7);
+        assert_eq!(ethernet.get_fcs_errors(), 8);
+    }
+
+    #[test]
+    fn upgrades_vlan_counter_samples() {
+        let vlan = words(&[10, 0, 5000, 11, 12, 13, 14]);
+        let upgraded = decode(datagram(&[counter_sample(7, &[vlan])]));
+
+        let datagram = SFlowPacket::decode(&upgraded).unwrap();
+        let samples = datagram.get_samples().unwrap();
+        let sample = samples[0].get_counter_sample().unwrap();
+        assert_eq!(sample.records.len(), 1);
+        assert_eq!(sample.records[0].get_record_type(), 5);
+        let vlan = SFlowVlanCountersPacket::new(sample.records[0].payload()).unwrap();
+        assert_eq!(vlan.get_vlan_id(), 10);
+        assert_eq!(vlan.get_octets(), 5000);
+        assert_eq!(vlan.get_ucast_pkts(), 11);
+        assert_eq!(vlan.get_discards(), 14);
+    }
+
+    #[test]
+    fn rejects_truncated_datagrams_at_every_length() {
+        let gateway = words(&[3, 65000, 65001, 65002, 1, 2, 2, 65003, 65004, 1, 100, 200]);
+        let samples = [
+            flow_sample(&[gateway]),
+            counter_sample(2, &[generic_counters(), vec![0; ETHERNET_COUNTERS_LENGTH]]),
+        ];
+        let full = datagram(&samples);
+        assert!(upgrade(full.clone()).is_ok());
+        for length in 4..full.len() {
+            assert!(
+                matches!(
+                    upgrade(full.slice(..length)),
+                    Err(DecodeError::Truncated(_))
+                ),
+                "{length} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_types_it_cannot_step_over() {
+        let mut packet_data = flow_sample(&[]);
+        packet_data[32..36].copy_from_slice(&9u32.to_be_bytes());
+        let extended = flow_sample(&[words(&[6])]);
+        let counters = counter_sample(8, &[]);
+        for (sample, typ) in [(packet_data, 9), (extended, 6), (counters, 8)] {
+            assert!(matches!(
+                upgrade(datagram(&[sample])),
+                Err(DecodeError::UnsupportedRecordType(found)) if found == typ
+            ));
+        }
+        assert!(matches!(
+            upgrade(datagram(&[words(&[3])])),
+            Err(DecodeError::UnsupportedSampleType(3))
+        ));
+    }
+}
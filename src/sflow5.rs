@@ -1,41 +1,344 @@
-use byteorder::{BigEndian, ReadBytesExt};
-use pnet::packet::ethernet::EthernetPacket;
+use crate::dissector::{dissect, DecodedHeader, TransportHeader, TunnelType};
+use byteorder::{BigEndian, ByteOrder};
 use pnet::packet::Packet;
 use pnet::util::MacAddr;
 use pnet_macros::packet;
 use pnet_macros_support::types::*;
-use std::io::Cursor;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Truncated(&'static str),
+    LengthOverflow(&'static str, u32),
+    BadCount(&'static str, u32),
+    UnsupportedVersion(u32),
+    UnsupportedAddressType(u32),
+    UnsupportedSampleType(u32),
+    UnsupportedRecordType(u32), // sFlow v4 data, which has no length to skip it by
+}
+
+impl DecodeError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DecodeError::Truncated(_) => "truncated",
+            DecodeError::LengthOverflow(_, _) => "length_overflow",
+            DecodeError::BadCount(_, _) => "bad_count",
+            DecodeError::UnsupportedVersion(_) => "unsupported_version",
+            DecodeError::UnsupportedAddressType(_) => "unsupported_address_type",
+            DecodeError::UnsupportedSampleType(_) => "unsupported_sample_type",
+            DecodeError::UnsupportedRecordType(_) => "unsupported_record_type",
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            DecodeError::Truncated(what) => write!(f, "Truncated {what}"),
+            DecodeError::LengthOverflow(what, len) => {
+                write!(f, "{what} length {len} overflows its container")
+            }
+            DecodeError::BadCount(what, count) => write!(f, "Bad {what} count: {count}"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported sFlow version: {version}")
+            }
+            DecodeError::UnsupportedAddressType(typ) => {
+                write!(f, "Unsupported agent address type: {typ}")
+            }
+            DecodeError::UnsupportedSampleType(typ) => {
+                write!(f, "Unsupported sample type: {typ}")
+            }
+            DecodeError::UnsupportedRecordType(typ) => {
+                write!(f, "Unsupported record type: {typ}")
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize, what: &'static str) -> Result<u32, DecodeError> {
+    match bytes.get(offset..offset + 4) {
+        Some(word) => Ok(BigEndian::read_u32(word)),
+        None => Err(DecodeError::Truncated(what)),
+    }
+}
+
+// Splits `count` length-prefixed XDR structures (type, length, body) off the
+// front of `bytes`, making sure every declared length stays inside the buffer.
+fn split_structures<'a>(
+    bytes: &'a [u8],
+    count: u32,
+    what: &'static str,
+) -> Result<Vec<&'a [u8]>, DecodeError> {
+    // every structure carries at least its 8 byte type/length header
+    if count as usize > bytes.len() / 8 {
+        return Err(DecodeError::BadCount(what, count));
+    }
+    let mut structures = Vec::with_capacity(count as usize);
+    let mut offset = 0;
+    for _ in 0..count {
+        let length = read_u32(bytes, offset + 4, what)?;
+        let end = (offset + 8)
+            .checked_add(length as usize)
+            .filter(|end| *end <= bytes.len())
+            .ok_or(DecodeError::LengthOverflow(what, length))?;
+        structures.push(&bytes[offset..end]);
+        offset = end;
+    }
+    Ok(structures)
+}
+
+// The agent address is an XDR union on agent_address_type, so the rest of the
+// datagram header (sub_agent_id, sequence_number, uptime, num_samples) sits at
+// an offset that depends on the address length and is read by hand below.
+// The fields of #[packet] structs only describe the layout and are read
+// through the generated accessors, so they are never read as fields.
+#[allow(dead_code)]
 #[packet]
 pub struct SFlow {
     pub version: u32be,
     pub agent_address_type: u32be,
-    #[construct_with(u8, u8, u8, u8)]
-    pub agent_address: Ipv4Addr,
-    pub sub_agent_id: u32be,
-    pub sequence_number: u32be,
-    pub uptime: u32be,
-    pub num_samples: u32be,
     #[payload]
     pub payload: Vec<u8>,
 }
-impl SFlowPacket<'_> {
-    pub fn get_samples(&self) -> Vec<SFlowSamplePacket> {
-        let mut samples = Vec::new();
-        let mut offset = 0;
-        for _ in 0..self.get_num_samples() {
-            let sample = SFlowSamplePacket::new(&self.payload()[offset..]).unwrap();
-            offset += sample.get_sample_length() as usize + 8;
-            samples.push(sample);
+impl<'p> SFlowPacket<'p> {
+    /// Wraps `bytes` as an sFlow v5 datagram, rejecting anything too short to
+    /// hold the fixed header or carrying another version. v4 datagrams are
+    /// brought to v5 by `sflow4::upgrade` first.
+    pub fn decode(bytes: &'p [u8]) -> Result<SFlowPacket<'p>, DecodeError> {
+        let datagram = SFlowPacket::new(bytes).ok_or(DecodeError::Truncated("datagram header"))?;
+        match datagram.get_version() {
+            5 => Ok(datagram),
+            version => Err(DecodeError::UnsupportedVersion(version)),
         }
-        samples
+    }
+
+    fn agent_address_length(&self) -> Result<usize, DecodeError> {
+        match self.get_agent_address_type() {
+            0 => Ok(0),  // UNKNOWN carries no address
+            1 => Ok(4),  // IP_V4
+            2 => Ok(16), // IP_V6
+            typ => Err(DecodeError::UnsupportedAddressType(typ)),
+        }
+    }
+
+    fn read_header_u32(&self, offset: usize) -> Result<u32, DecodeError> {
+        read_u32(
+            self.payload(),
+            self.agent_address_length()? + offset,
+            "datagram header",
+        )
+    }
+
+    pub fn get_agent_address(&self) -> Result<IpAddr, DecodeError> {
+        let length = self.agent_address_length()?;
+        let address = self
+            .payload()
+            .get(..length)
+            .ok_or(DecodeError::Truncated("agent address"))?;
+        match length {
+            4 => Ok(IpAddr::V4(Ipv4Addr::new(
+                address[0], address[1], address[2], address[3],
+            ))),
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(address);
+                Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        }
+    }
+
+    pub fn get_sub_agent_id(&self) -> Result<u32, DecodeError> {
+        self.read_header_u32(0)
+    }
+
+    pub fn get_sequence_number(&self) -> Result<u32, DecodeError> {
+        self.read_header_u32(4)
+    }
+
+    pub fn get_uptime(&self) -> Result<u32, DecodeError> {
+        self.read_header_u32(8)
+    }
+
+    pub fn get_num_samples(&self) -> Result<u32, DecodeError> {
+        self.read_header_u32(12)
+    }
+
+    pub fn get_samples(&self) -> Result<Vec<SFlowSamplePacket<'_>>, DecodeError> {
+        let offset = self.agent_address_length()? + 16;
+        let samples = self
+            .payload()
+            .get(offset..)
+            .ok_or(DecodeError::Truncated("datagram header"))?;
+        split_structures(samples, self.get_num_samples()?, "sample")?
+            .into_iter()
+            .map(|sample| SFlowSamplePacket::new(sample).ok_or(DecodeError::Truncated("sample")))
+            .collect()
     }
 }
 
+// Every sample is an opaque structure tagged with its format; the body is
+// decoded with one of the flow or counter sample layouts below.
+#[allow(dead_code)]
 #[packet]
 pub struct SFlowSample {
     pub sample_type: u32be,
     pub sample_length: u32be,
+    #[length = "sample_length"]
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+impl SFlowSamplePacket<'_> {
+    /// Reads the sequence number, source id type and source id index that
+    /// flow and counter samples start with, without decoding their records.
+    pub fn get_sequence(&self) -> Result<(u32, u32, u32), DecodeError> {
+        let payload = self.payload();
+        let sequence_number = read_u32(payload, 0, "sample")?;
+        let (source_id_type, source_id_index) = match self.get_sample_type() {
+            1 | 2 => split_source_id(read_u32(payload, 4, "sample")?),
+            3 | 4 => (
+                read_u32(payload, 4, "sample")?,
+                read_u32(payload, 8, "sample")?,
+            ),
+            typ => return Err(DecodeError::UnsupportedSampleType(typ)),
+        };
+        Ok((sequence_number, source_id_type, source_id_index))
+    }
+
+    pub fn get_flow_sample(&self) -> Result<FlowSample<'_>, DecodeError> {
+        let payload = self.payload();
+        let (mut sample, num_records, offset) = match self.get_sample_type() {
+            1 => {
+                let compact = SFlowCompactFlowSamplePacket::new(payload)
+                    .ok_or(DecodeError::Truncated("flow sample"))?;
+                let (source_id_type, source_id_index) = split_source_id(compact.get_source_id());
+                let (input_interface_format, input_interface_value) =
+                    split_interface(compact.get_input_interface());
+                let (output_interface_format, output_interface_value) =
+                    split_interface(compact.get_output_interface());
+                let sample = FlowSample {
+                    source_id_type,
+                    source_id_index,
+                    sampling_rate: compact.get_sampling_rate(),
+                    sample_pool: compact.get_sample_pool(),
+                    drops: compact.get_drops(),
+                    input_interface_format,
+                    input_interface_value,
+                    output_interface_format,
+                    output_interface_value,
+                    records: Vec::new(),
+                };
+                (
+                    sample,
+                    compact.get_num_records(),
+                    SFlowCompactFlowSamplePacket::minimum_packet_size(),
+                )
+            }
+            3 => {
+                let expanded = SFlowExpandedFlowSamplePacket::new(payload)
+                    .ok_or(DecodeError::Truncated("expanded flow sample"))?;
+                let sample = FlowSample {
+                    source_id_type: expanded.get_source_id_type(),
+                    source_id_index: expanded.get_source_id_index(),
+                    sampling_rate: expanded.get_sampling_rate(),
+                    sample_pool: expanded.get_sample_pool(),
+                    drops: expanded.get_drops(),
+                    input_interface_format: expanded.get_input_interface_format(),
+                    input_interface_value: expanded.get_input_interface_value(),
+                    output_interface_format: expanded.get_output_interface_format(),
+                    output_interface_value: expanded.get_output_interface_value(),
+                    records: Vec::new(),
+                };
+                (
+                    sample,
+                    expanded.get_num_records(),
+                    SFlowExpandedFlowSamplePacket::minimum_packet_size(),
+                )
+            }
+            typ => return Err(DecodeError::UnsupportedSampleType(typ)),
+        };
+        sample.records = get_records(&payload[offset..], num_records, "flow record")?;
+        Ok(sample)
+    }
+
+    pub fn get_counter_sample(&self) -> Result<CounterSample<'_>, DecodeError> {
+        let payload = self.payload();
+        let (source_id_type, source_id_index, num_records, offset) = match self.get_sample_type() {
+            2 => {
+                let sample = SFlowCompactCounterSamplePacket::new(payload)
+                    .ok_or(DecodeError::Truncated("counter sample"))?;
+                let (source_id_type, source_id_index) = split_source_id(sample.get_source_id());
+                (
+                    source_id_type,
+                    source_id_index,
+                    sample.get_num_records(),
+                    SFlowCompactCounterSamplePacket::minimum_packet_size(),
+                )
+            }
+            4 => {
+                let sample = SFlowExpandedCounterSamplePacket::new(payload)
+                    .ok_or(DecodeError::Truncated("expanded counter sample"))?;
+                (
+                    sample.get_source_id_type(),
+                    sample.get_source_id_index(),
+                    sample.get_num_records(),
+                    SFlowExpandedCounterSamplePacket::minimum_packet_size(),
+                )
+            }
+            typ => return Err(DecodeError::UnsupportedSampleType(typ)),
+        };
+        Ok(CounterSample {
+            source_id_type,
+            source_id_index,
+            records: get_records(&payload[offset..], num_records, "counter record")?,
+        })
+    }
+}
+
+fn get_records<'a>(
+    bytes: &'a [u8],
+    count: u32,
+    what: &'static str,
+) -> Result<Vec<SFlowRecordPacket<'a>>, DecodeError> {
+    split_structures(bytes, count, what)?
+        .into_iter()
+        .map(|record| SFlowRecordPacket::new(record).ok_or(DecodeError::Truncated(what)))
+        .collect()
+}
+
+// Compact samples pack the source id type into the top byte of source_id and
+// the index into the remaining 24 bits.
+fn split_source_id(source_id: u32) -> (u32, u32) {
+    (source_id >> 24, source_id & 0x00ff_ffff)
+}
+
+// Compact flow samples pack the interface format into the top 2 bits and the
+// value (ifIndex, drop reason or interface count) into the remaining 30 bits.
+fn split_interface(interface: u32) -> (u32, u32) {
+    (interface >> 30, interface & 0x3fff_ffff)
+}
+
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowCompactFlowSample {
+    pub sequence_number: u32be,
+    pub source_id: u32be,
+    pub sampling_rate: u32be,
+    pub sample_pool: u32be,
+    pub drops: u32be,
+    pub input_interface: u32be,
+    pub output_interface: u32be,
+    pub num_records: u32be,
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowExpandedFlowSample {
     pub sequence_number: u32be,
     pub source_id_type: u32be,
     pub source_id_index: u32be,
@@ -46,25 +349,67 @@ pub struct SFlowSample {
     pub input_interface_value: u32be,
     pub output_interface_format: u32be,
     pub output_interface_value: u32be,
-    pub num_sampled_records: u32be,
-    #[length = "sample_length"]
+    pub num_records: u32be,
     #[payload]
     pub payload: Vec<u8>,
 }
 
-impl SFlowSamplePacket<'_> {
-    pub fn get_records(&self) -> Vec<SFlowRecordPacket> {
-        let mut records = Vec::new();
-        let mut offset = 0;
-        for _ in 0..self.get_num_sampled_records() {
-            let record = SFlowRecordPacket::new(&self.payload()[offset..]).unwrap();
-            offset += record.get_length() as usize + 8; // add the header length
-            records.push(record);
-        }
-        records
+/// Flow sample fields shared by the compact (1) and expanded (3) layouts.
+pub struct FlowSample<'a> {
+    pub source_id_type: u32,
+    pub source_id_index: u32,
+    pub sampling_rate: u32,
+    pub sample_pool: u32,
+    pub drops: u32,
+    pub input_interface_format: u32,
+    pub input_interface_value: u32,
+    pub output_interface_format: u32,
+    pub output_interface_value: u32,
+    pub records: Vec<SFlowRecordPacket<'a>>,
+}
+
+impl FlowSample<'_> {
+    /// The input ifIndex, if the interface is a single known port.
+    pub fn input_if_index(&self) -> Option<u32> {
+        (self.input_interface_format == 0).then_some(self.input_interface_value)
+    }
+
+    /// The output ifIndex, if the packet went out a single known port rather
+    /// than being dropped or flooded to several interfaces.
+    pub fn output_if_index(&self) -> Option<u32> {
+        (self.output_interface_format == 0).then_some(self.output_interface_value)
     }
 }
 
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowCompactCounterSample {
+    pub sequence_number: u32be,
+    pub source_id: u32be,
+    pub num_records: u32be,
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowExpandedCounterSample {
+    pub sequence_number: u32be,
+    pub source_id_type: u32be,
+    pub source_id_index: u32be,
+    pub num_records: u32be,
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+/// Counter sample fields shared by the compact (2) and expanded (4) layouts.
+pub struct CounterSample<'a> {
+    pub source_id_type: u32,
+    pub source_id_index: u32,
+    pub records: Vec<SFlowRecordPacket<'a>>,
+}
+
+#[allow(dead_code)]
 #[packet]
 pub struct SFlowRecord {
     pub record_type: u32be,
@@ -74,6 +419,7 @@ pub struct SFlowRecord {
     pub payload: Vec<u8>,
 }
 
+#[allow(dead_code)]
 #[packet]
 pub struct SFlowRawHeader {
     pub protocol: u32be,
@@ -86,33 +432,198 @@ pub struct SFlowRawHeader {
 }
 
 impl SFlowRawHeaderPacket<'_> {
-    pub fn get_src_mac(&self) -> MacAddr {
-        let ethernet_packet = EthernetPacket::new(self.payload()).unwrap();
-        return ethernet_packet.get_source();
+    pub fn get_decoded_header(&self) -> DecodedHeader {
+        dissect(self.get_protocol(), self.payload())
     }
+}
+
+/// The 5-tuple and TCP flags of a sampled IP packet, read either from a raw
+/// packet header or from a sampled IPv4/IPv6 record.
+#[derive(Clone, Debug)]
+pub struct SampledIp {
+    pub src_ip: IpAddr,
+    pub dst_ip: IpAddr,
+    pub protocol: u32,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub tcp_flags: u32,
+}
 
-    pub fn get_dst_mac(&self) -> MacAddr {
-        let ethernet_packet = EthernetPacket::new(self.payload()).unwrap();
-        return ethernet_packet.get_destination();
+impl SampledIp {
+    /// Returns `None` when the decoded header didn't reach an IP layer.
+    pub fn from_header(header: &DecodedHeader) -> Option<Self> {
+        let ip = header.ip.as_ref()?;
+        let mut sampled = SampledIp {
+            src_ip: ip.src_ip,
+            dst_ip: ip.dst_ip,
+            protocol: ip.protocol as u32,
+            src_port: 0,
+            dst_port: 0,
+            tcp_flags: 0,
+        };
+        match header.transport {
+            Some(TransportHeader::Tcp {
+                src_port,
+                dst_port,
+                flags,
+            }) => {
+                sampled.src_port = src_port as u32;
+                sampled.dst_port = dst_port as u32;
+                sampled.tcp_flags = flags as u32;
+            }
+            Some(TransportHeader::Udp { src_port, dst_port }) => {
+                sampled.src_port = src_port as u32;
+                sampled.dst_port = dst_port as u32;
+            }
+            // ICMP has no ports, so like NetFlow put its type and code in the
+            // destination port
+            Some(TransportHeader::Icmp {
+                icmp_type,
+                icmp_code,
+            }) => sampled.dst_port = (icmp_type as u32) << 8 | icmp_code as u32,
+            None => {}
+        }
+        Some(sampled)
     }
+}
 
-    pub fn get_vlan(&self) -> Result<u32, String> {
-        let ethernet_packet = EthernetPacket::new(self.payload()).unwrap();
-        if ethernet_packet.get_ethertype() == pnet::packet::ethernet::EtherTypes::Vlan {
-            let vlan_packet =
-                pnet::packet::vlan::VlanPacket::new(ethernet_packet.payload()).unwrap();
-            return Ok(vlan_packet.get_vlan_identifier() as u32);
+impl From<&SFlowIpv4Packet<'_>> for SampledIp {
+    fn from(ipv4: &SFlowIpv4Packet) -> Self {
+        SampledIp {
+            src_ip: IpAddr::V4(ipv4.get_src_ip()),
+            dst_ip: IpAddr::V4(ipv4.get_dst_ip()),
+            protocol: ipv4.get_protocol(),
+            src_port: ipv4.get_src_port(),
+            dst_port: ipv4.get_dst_port(),
+            tcp_flags: ipv4.get_tcp_flags(),
+        }
+    }
+}
+
+impl From<&SFlowIpv6Packet<'_>> for SampledIp {
+    fn from(ipv6: &SFlowIpv6Packet) -> Self {
+        SampledIp {
+            src_ip: IpAddr::V6(ipv6.get_src_ip()),
+            dst_ip: IpAddr::V6(ipv6.get_dst_ip()),
+            protocol: ipv6.get_protocol(),
+            src_port: ipv6.get_src_port(),
+            dst_port: ipv6.get_dst_port(),
+            tcp_flags: ipv6.get_tcp_flags(),
         }
-        return Err(format!(
-            "Ethernet packet does not contain a VLAN (ethertype) {}",
-            ethernet_packet.get_ethertype()
-        )
-        .into());
     }
 }
 
+/// What the flow records of one sample say about the sampled packet, whichever
+/// of the raw header, decoded and extended records the agent chose to send.
+#[derive(Clone, Debug, Default)]
+pub struct SampledFlow {
+    pub src_mac: Option<MacAddr>,
+    pub dst_mac: Option<MacAddr>,
+    pub vlan: Option<u32>,
+    pub ethertype: Option<u32>,
+    pub ip: Option<SampledIp>,
+    pub tunnel: Option<SampledTunnel>,
+    pub frame_length: u32,
+    pub input_interface: Option<u32>,
+    pub output_interface: Option<u32>,
+    pub src_as: Option<u32>,
+    pub dst_as: Option<u32>,
+    pub src_peer_as: Option<u32>,
+    pub dst_peer_as: Option<u32>,
+    pub next_hop: Option<IpAddr>,
+    pub extended: SFlowExtendedData,
+}
+
+/// An encapsulation in the sampled header, where `ip` of the flow is the
+/// outer packet.
+#[derive(Clone, Debug)]
+pub struct SampledTunnel {
+    pub tunnel_type: TunnelType,
+    pub vni: Option<u32>,
+    pub inner: Option<SampledIp>,
+}
+
+impl FlowSample<'_> {
+    /// Reads the sampled packet out of the flow records. Later records win
+    /// over earlier ones describing the same field.
+    pub fn sampled_flow(&self) -> Result<SampledFlow, DecodeError> {
+        let mut flow = SampledFlow {
+            input_interface: self.input_if_index(),
+            output_interface: self.output_if_index(),
+            ..Default::default()
+        };
+        for record in &self.records {
+            match record.get_record_type() {
+                1 => {
+                    let raw_packet_header = SFlowRawHeaderPacket::new(record.payload())
+                        .ok_or(DecodeError::Truncated("sampled header"))?;
+                    flow.frame_length = raw_packet_header.get_frame_length();
+                    let header = raw_packet_header.get_decoded_header();
+                    flow.src_mac = header.src_mac.or(flow.src_mac);
+                    flow.dst_mac = header.dst_mac.or(flow.dst_mac);
+                    if let Some(vlan) = header.vlans.first() {
+                        flow.vlan = Some(*vlan as u32);
+                    }
+                    if let Some(ethertype) = header.ethertype {
+                        flow.ethertype = Some(ethertype as u32);
+                    }
+                    if let Some(ip) = SampledIp::from_header(&header) {
+                        flow.ip = Some(ip);
+                    }
+                    flow.tunnel = header.tunnel.as_ref().map(|tunnel| SampledTunnel {
+                        tunnel_type: tunnel.tunnel_type,
+                        vni: tunnel.vni,
+                        inner: SampledIp::from_header(&tunnel.inner),
+                    });
+                }
+                2 => {
+                    let ethernet_frame = SFlowEthernetFrame::try_from(record.payload())?;
+                    flow.src_mac = Some(ethernet_frame.src_mac);
+                    flow.dst_mac = Some(ethernet_frame.dst_mac);
+                    flow.ethertype = Some(ethernet_frame.ethertype);
+                }
+                3 => {
+                    let ipv4 = SFlowIpv4Packet::new(record.payload())
+                        .ok_or(DecodeError::Truncated("sampled IPv4"))?;
+                    flow.frame_length = ipv4.get_length();
+                    flow.ethertype = flow.ethertype.or(Some(0x0800));
+                    flow.ip = Some(SampledIp::from(&ipv4));
+                }
+                4 => {
+                    let ipv6 = SFlowIpv6Packet::new(record.payload())
+                        .ok_or(DecodeError::Truncated("sampled IPv6"))?;
+                    flow.frame_length = ipv6.get_length();
+                    flow.ethertype = flow.ethertype.or(Some(0x86dd));
+                    flow.ip = Some(SampledIp::from(&ipv6));
+                }
+                1001 => {
+                    let extended_switch = SFlowExtendedSwitchPacket::new(record.payload())
+                        .ok_or(DecodeError::Truncated("extended switch"))?;
+                    flow.vlan = Some(extended_switch.get_src_vlan());
+                }
+                1003 => {
+                    let gateway = SFlowExtendedGateway::try_from(record.payload())?;
+                    flow.src_as = Some(gateway.src_as);
+                    flow.src_peer_as = Some(gateway.src_peer_as);
+                    // an empty AS path means the destination is in the
+                    // router's own AS
+                    flow.dst_as = Some(gateway.dst_as().unwrap_or(gateway.as_number));
+                    flow.dst_peer_as = Some(gateway.dst_peer_as().unwrap_or(gateway.as_number));
+                    flow.next_hop = Some(gateway.next_hop);
+                    flow.extended.gateway = Some(gateway);
+                }
+                1002 | 1004..=1007 => flow.extended.add(record)?,
+                _ => {}
+            }
+        }
+        Ok(flow)
+    }
+}
+
+// SFlowEthernetFrame is 24 bytes with padding. The length is only part of
+// the layout and never read.
 #[derive(Debug)]
-// SFlowEthernetFrame is 24 bytes with padding
+#[allow(dead_code)]
 pub struct SFlowEthernetFrame {
     pub length: u32be,    // 4 bytes
     pub src_mac: MacAddr, // 6 bytes + 2 bytes of padding
@@ -120,39 +631,29 @@ pub struct SFlowEthernetFrame {
     pub ethertype: u32be, // 4 bytes
 }
 
-impl From<&[u8]> for SFlowEthernetFrame {
-    fn from(bytes: &[u8]) -> Self {
-        let mut payload = Cursor::new(bytes);
-        let length = payload.read_u32::<BigEndian>().unwrap();
-        let src_mac = MacAddr::new(
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-        );
-        payload.set_position(payload.position() + 2);
+impl TryFrom<&[u8]> for SFlowEthernetFrame {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        if bytes.len() < 24 {
+            return Err(DecodeError::Truncated("ethernet frame record"));
+        }
+        let length = BigEndian::read_u32(&bytes[0..4]);
+        let src_mac = MacAddr::new(bytes[4], bytes[5], bytes[6], bytes[7], bytes[8], bytes[9]);
         let dst_mac = MacAddr::new(
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
-            payload.read_u8().unwrap(),
+            bytes[12], bytes[13], bytes[14], bytes[15], bytes[16], bytes[17],
         );
-        payload.set_position(payload.position() + 2);
-
-        let ethertype = payload.read_u32::<BigEndian>().unwrap();
-        SFlowEthernetFrame {
+        let ethertype = BigEndian::read_u32(&bytes[20..24]);
+        Ok(SFlowEthernetFrame {
             length,
             src_mac,
             dst_mac,
             ethertype,
-        }
+        })
     }
 }
 
+#[allow(dead_code)]
 #[packet]
 pub struct SFlowIpv4 {
     pub length: u32be,
@@ -169,6 +670,7 @@ pub struct SFlowIpv4 {
     pub payload: Vec<u8>,
 }
 
+#[allow(dead_code)]
 #[packet]
 pub struct SFlowIpv6 {
     pub length: u32be,
@@ -186,6 +688,7 @@ pub struct SFlowIpv6 {
     pub payload: Vec<u8>,
 }
 
+#[allow(dead_code)]
 #[packet]
 pub struct SFlowExtendedSwitch {
     pub src_vlan: u32be,
@@ -195,3 +698,738 @@ pub struct SFlowExtendedSwitch {
     #[payload]
     pub payload: Vec<u8>,
 }
+
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowGenericInterfaceCounters {
+    pub if_index: u32be,
+    pub if_type: u32be,
+    pub if_speed: u64be,
+    pub if_direction: u32be,
+    pub if_status: u32be,
+    pub if_in_octets: u64be,
+    pub if_in_ucast_pkts: u32be,
+    pub if_in_multicast_pkts: u32be,
+    pub if_in_broadcast_pkts: u32be,
+    pub if_in_discards: u32be,
+    pub if_in_errors: u32be,
+    pub if_in_unknown_protos: u32be,
+    pub if_out_octets: u64be,
+    pub if_out_ucast_pkts: u32be,
+    pub if_out_multicast_pkts: u32be,
+    pub if_out_broadcast_pkts: u32be,
+    pub if_out_discards: u32be,
+    pub if_out_errors: u32be,
+    pub if_promiscuous_mode: u32be,
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowEthernetCounters {
+    pub alignment_errors: u32be,
+    pub fcs_errors: u32be,
+    pub single_collision_frames: u32be,
+    pub multiple_collision_frames: u32be,
+    pub sqe_test_errors: u32be,
+    pub deferred_transmissions: u32be,
+    pub late_collisions: u32be,
+    pub excessive_collisions: u32be,
+    pub internal_mac_transmit_errors: u32be,
+    pub carrier_sense_errors: u32be,
+    pub frame_too_longs: u32be,
+    pub internal_mac_receive_errors: u32be,
+    pub symbol_errors: u32be,
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowVlanCounters {
+    pub vlan_id: u32be,
+    pub octets: u64be,
+    pub ucast_pkts: u32be,
+    pub multicast_pkts: u32be,
+    pub broadcast_pkts: u32be,
+    pub discards: u32be,
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+// CPU utilisation is reported in hundredths of a percent
+#[allow(dead_code)]
+#[packet]
+pub struct SFlowProcessorCounters {
+    pub cpu_5s: u32be,
+    pub cpu_1m: u32be,
+    pub cpu_5m: u32be,
+    pub total_memory: u64be,
+    pub free_memory: u64be,
+    #[payload]
+    pub payload: Vec<u8>,
+}
+
+// The extended data records below hold variable length XDR (addresses,
+// strings, arrays), so they are read with a cursor instead of #[packet].
+struct XdrReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    what: &'static str,
+}
+
+impl<'a> XdrReader<'a> {
+    fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        XdrReader {
+            bytes,
+            offset: 0,
+            what,
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let value = read_u32(self.bytes, self.offset, self.what)?;
+        self.offset += 4;
+        Ok(value)
+    }
+
+    fn u32_array(&mut self) -> Result<Vec<u32>, DecodeError> {
+        let count = self.u32()?;
+        if count as usize > (self.bytes.len() - self.offset) / 4 {
+            return Err(DecodeError::BadCount(self.what, count));
+        }
+        (0..count).map(|_| self.u32()).collect()
+    }
+
+    // opaque<> and string<> are padded to a multiple of 4 bytes
+    fn opaque(&mut self) -> Result<&'a [u8], DecodeError> {
+        let length = self.u32()?;
+        let value = self
+            .bytes
+            .get(self.offset..)
+            .and_then(|rest| rest.get(..length as usize))
+            .ok_or(DecodeError::LengthOverflow(self.what, length))?;
+        self.offset += (length as usize).div_ceil(4) * 4;
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        Ok(String::from_utf8_lossy(self.opaque()?).into_owned())
+    }
+
+    fn address(&mut self) -> Result<IpAddr, DecodeError> {
+        match self.u32()? {
+            0 => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            1 => {
+                let octets = self.u32()?;
+                Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            2 => {
+                let octets = self
+                    .bytes
+                    .get(self.offset..self.offset + 16)
+                    .ok_or(DecodeError::Truncated(self.what))?;
+                self.offset += 16;
+                let mut address = [0u8; 16];
+                address.copy_from_slice(octets);
+                Ok(IpAddr::V6(Ipv6Addr::from(address)))
+            }
+            typ => Err(DecodeError::UnsupportedAddressType(typ)),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SFlowExtendedRouter {
+    pub next_hop: IpAddr,
+    pub src_mask_len: u32,
+    pub dst_mask_len: u32,
+}
+
+impl TryFrom<&[u8]> for SFlowExtendedRouter {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = XdrReader::new(bytes, "extended router");
+        Ok(SFlowExtendedRouter {
+            next_hop: reader.address()?,
+            src_mask_len: reader.u32()?,
+            dst_mask_len: reader.u32()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SFlowAsPathSegment {
+    pub segment_type: u32, // 1 = AS_SET, 2 = AS_SEQUENCE
+    pub as_numbers: Vec<u32>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SFlowExtendedGateway {
+    pub next_hop: IpAddr,
+    pub as_number: u32,
+    pub src_as: u32,
+    pub src_peer_as: u32,
+    pub dst_as_path: Vec<SFlowAsPathSegment>,
+    pub communities: Vec<u32>,
+    pub local_pref: u32,
+}
+
+impl SFlowExtendedGateway {
+    /// The AS the destination was learned from, i.e. the first AS on the path.
+    pub fn dst_peer_as(&self) -> Option<u32> {
+        self.dst_as_path
+            .iter()
+            .flat_map(|segment| segment.as_numbers.iter())
+            .next()
+            .copied()
+    }
+
+    /// The origin AS of the destination, i.e. the last AS on the path.
+    pub fn dst_as(&self) -> Option<u32> {
+        self.dst_as_path
+            .iter()
+            .flat_map(|segment| segment.as_numbers.iter())
+            .last()
+            .copied()
+    }
+}
+
+impl TryFrom<&[u8]> for SFlowExtendedGateway {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = XdrReader::new(bytes, "extended gateway");
+        let next_hop = reader.address()?;
+        let as_number = reader.u32()?;
+        let src_as = reader.u32()?;
+        let src_peer_as = reader.u32()?;
+        let num_segments = reader.u32()?;
+        // every segment carries at least its type and length
+        if num_segments as usize > bytes.len() / 8 {
+            return Err(DecodeError::BadCount("AS path segment", num_segments));
+        }
+        let mut dst_as_path = Vec::with_capacity(num_segments as usize);
+        for _ in 0..num_segments {
+            dst_as_path.push(SFlowAsPathSegment {
+                segment_type: reader.u32()?,
+                as_numbers: reader.u32_array()?,
+            });
+        }
+        Ok(SFlowExtendedGateway {
+            next_hop,
+            as_number,
+            src_as,
+            src_peer_as,
+            dst_as_path,
+            communities: reader.u32_array()?,
+            local_pref: reader.u32()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SFlowExtendedUser {
+    pub src_charset: u32,
+    pub src_user: String,
+    pub dst_charset: u32,
+    pub dst_user: String,
+}
+
+impl TryFrom<&[u8]> for SFlowExtendedUser {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = XdrReader::new(bytes, "extended user");
+        Ok(SFlowExtendedUser {
+            src_charset: reader.u32()?,
+            src_user: reader.string()?,
+            dst_charset: reader.u32()?,
+            dst_user: reader.string()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SFlowExtendedUrl {
+    pub direction: u32, // 1 = source address is the server, 2 = destination is
+    pub url: String,
+    pub host: String,
+}
+
+impl TryFrom<&[u8]> for SFlowExtendedUrl {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = XdrReader::new(bytes, "extended url");
+        Ok(SFlowExtendedUrl {
+            direction: reader.u32()?,
+            url: reader.string()?,
+            host: reader.string()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SFlowExtendedMpls {
+    pub next_hop: IpAddr,
+    pub in_labels: Vec<u32>,
+    pub out_labels: Vec<u32>,
+}
+
+impl TryFrom<&[u8]> for SFlowExtendedMpls {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = XdrReader::new(bytes, "extended mpls");
+        Ok(SFlowExtendedMpls {
+            next_hop: reader.address()?,
+            in_labels: reader.u32_array()?,
+            out_labels: reader.u32_array()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SFlowExtendedNat {
+    pub src_address: IpAddr,
+    pub dst_address: IpAddr,
+}
+
+impl TryFrom<&[u8]> for SFlowExtendedNat {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = XdrReader::new(bytes, "extended nat");
+        Ok(SFlowExtendedNat {
+            src_address: reader.address()?,
+            dst_address: reader.address()?,
+        })
+    }
+}
+
+/// The extended data records (1002-1007) seen in a flow sample.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct SFlowExtendedData {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub router: Option<SFlowExtendedRouter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<SFlowExtendedGateway>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<SFlowExtendedUser>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<SFlowExtendedUrl>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mpls: Option<SFlowExtendedMpls>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nat: Option<SFlowExtendedNat>,
+}
+
+impl SFlowExtendedData {
+    /// Decodes `record` if it is an extended data record; other record types
+    /// are left to the caller.
+    pub fn add(&mut self, record: &SFlowRecordPacket) -> Result<(), DecodeError> {
+        let payload = record.payload();
+        match record.get_record_type() {
+            1002 => self.router = Some(payload.try_into()?),
+            1003 => self.gateway = Some(payload.try_into()?),
+            1004 => self.user = Some(payload.try_into()?),
+            1005 => self.url = Some(payload.try_into()?),
+            1006 => self.mpls = Some(payload.try_into()?),
+            1007 => self.nat = Some(payload.try_into()?),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Replaces whatever `newer` carries, keeping older data it doesn't have.
+    pub fn update(&mut self, newer: SFlowExtendedData) {
+        if newer.router.is_some() {
+            self.router = newer.router;
+        }
+        if newer.gateway.is_some() {
+            self.gateway = newer.gateway;
+        }
+        if newer.user.is_some() {
+            self.user = newer.user;
+        }
+        if newer.url.is_some() {
+            self.url = newer.url;
+        }
+        if newer.mpls.is_some() {
+            self.mpls = newer.mpls;
+        }
+        if newer.nat.is_some() {
+            self.nat = newer.nat;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[u32]) -> Vec<u8> {
+        words.iter().flat_map(|word| word.to_be_bytes()).collect()
+    }
+
+    // A v5 datagram from sub-agent 7 with sequence number 42 and uptime 1000.
+    fn datagram(agent: IpAddr, samples: &[Vec<u8>]) -> Vec<u8> {
+        let mut datagram = match agent {
+            IpAddr::V4(address) => [words(&[5, 1]), address.octets().to_vec()].concat(),
+            IpAddr::V6(address) => [words(&[5, 2]), address.octets().to_vec()].concat(),
+        };
+        datagram.extend(words(&[7, 42, 1000, samples.len() as u32]));
+        datagram.extend(samples.concat());
+        datagram
+    }
+
+    #[test]
+    fn reads_the_header_past_an_ipv6_agent_address() {
+        let agent = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let sample = [words(&[2, 12]), words(&[1, 3, 0])].concat();
+        let bytes = datagram(agent, &[sample]);
+        let datagram = SFlowPacket::decode(&bytes).unwrap();
+        assert_eq!(datagram.get_agent_address().unwrap(), agent);
+        assert_eq!(datagram.get_sub_agent_id().unwrap(), 7);
+        assert_eq!(datagram.get_sequence_number().unwrap(), 42);
+        assert_eq!(datagram.get_uptime().unwrap(), 1000);
+        let samples = datagram.get_samples().unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].get_sample_type(), 2);
+    }
+
+    #[test]
+    fn an_ipv6_agent_address_cut_short_is_truncated() {
+        let agent = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let bytes = datagram(agent, &[]);
+        let datagram = SFlowPacket::decode(&bytes[..20]).unwrap();
+        assert!(matches!(
+            datagram.get_agent_address(),
+            Err(DecodeError::Truncated("agent address"))
+        ));
+        assert!(matches!(
+            datagram.get_sub_agent_id(),
+            Err(DecodeError::Truncated("datagram header"))
+        ));
+    }
+
+    #[test]
+    fn malformed_headers_are_decode_errors() {
+        let agent = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let bytes = datagram(agent, &[]);
+        assert!(matches!(
+            SFlowPacket::decode(&bytes[..6]),
+            Err(DecodeError::Truncated("datagram header"))
+        ));
+
+        let mut v4 = bytes.clone();
+        v4[3] = 4;
+        assert!(matches!(
+            SFlowPacket::decode(&v4),
+            Err(DecodeError::UnsupportedVersion(4))
+        ));
+
+        let mut unknown_address = bytes.clone();
+        unknown_address[7] = 3;
+        let datagram = SFlowPacket::decode(&unknown_address).unwrap();
+        assert!(matches!(
+            datagram.get_agent_address(),
+            Err(DecodeError::UnsupportedAddressType(3))
+        ));
+    }
+
+    #[test]
+    fn counts_and_lengths_must_fit_the_datagram() {
+        let agent = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        // the datagram claims a million samples but carries none
+        let mut bytes = datagram(agent, &[]);
+        bytes[24..28].copy_from_slice(&1_000_000u32.to_be_bytes());
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        assert!(matches!(
+            datagram_packet.get_samples(),
+            Err(DecodeError::BadCount("sample", 1_000_000))
+        ));
+
+        // a sample whose length runs past the end of the datagram
+        let sample = [words(&[1, 4096]), words(&[0; 8])].concat();
+        let bytes = datagram(agent, &[sample]);
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        assert!(matches!(
+            datagram_packet.get_samples(),
+            Err(DecodeError::LengthOverflow("sample", 4096))
+        ));
+
+        // a length of 0xffffffff must not wrap the end offset around
+        let sample = [words(&[1, u32::MAX]), words(&[0; 8])].concat();
+        let bytes = datagram(agent, &[sample]);
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        assert!(matches!(
+            datagram_packet.get_samples(),
+            Err(DecodeError::LengthOverflow("sample", u32::MAX))
+        ));
+    }
+
+    #[test]
+    fn malformed_samples_are_decode_errors() {
+        let agent = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let unknown = [words(&[9, 4]), words(&[0])].concat();
+        // a compact flow sample cut short after its sequence number
+        let short = [words(&[1, 4]), words(&[1])].concat();
+        // an expanded flow sample with one record claiming 64 bytes of 4
+        let record_overflow = [
+            words(&[3, 56]),
+            words(&[1, 0, 5, 256, 256, 0, 0, 1, 0, 2, 1]),
+            words(&[1, 64, 0]),
+        ]
+        .concat();
+        let bytes = datagram(agent, &[unknown, short, record_overflow]);
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        let samples = datagram_packet.get_samples().unwrap();
+        assert!(matches!(
+            samples[0].get_flow_sample(),
+            Err(DecodeError::UnsupportedSampleType(9))
+        ));
+        assert!(matches!(
+            samples[0].get_counter_sample(),
+            Err(DecodeError::UnsupportedSampleType(9))
+        ));
+        assert!(matches!(
+            samples[1].get_flow_sample(),
+            Err(DecodeError::Truncated("flow sample"))
+        ));
+        assert!(matches!(
+            samples[2].get_flow_sample(),
+            Err(DecodeError::LengthOverflow("flow record", 64))
+        ));
+    }
+
+    // ifIndex 5, 10 Gbit/s, up, with 2^32 + 1 octets in and 1500 out
+    fn generic_interface_counters() -> Vec<u8> {
+        let mut record = words(&[1, 88, 5, 6, 2, 1_410_065_408, 1, 3, 1, 1]);
+        record.extend(words(&[10, 20, 30, 0, 0, 0, 0, 1500, 40, 0, 0, 0, 0, 0]));
+        record
+    }
+
+    #[test]
+    fn decodes_compact_and_expanded_counter_samples() {
+        let agent = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        // CPU at 12.34% over 5s, 1 GiB of 2 GiB free
+        let processor = words(&[1001, 28, 1234, 1000, 900, 0, 1 << 31, 0, 1 << 30]);
+        let compact = [
+            words(&[2, 12 + 96 + 36]),
+            words(&[17, 5, 2]),
+            generic_interface_counters(),
+            processor,
+        ]
+        .concat();
+        let expanded = [
+            words(&[4, 16 + 96]),
+            words(&[18, 0, 70_000, 1]),
+            generic_interface_counters(),
+        ]
+        .concat();
+        let bytes = datagram(agent, &[compact, expanded]);
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        let samples = datagram_packet.get_samples().unwrap();
+        assert_eq!(samples[0].get_sequence().unwrap(), (17, 0, 5));
+        assert_eq!(samples[1].get_sequence().unwrap(), (18, 0, 70_000));
+
+        let compact = samples[0].get_counter_sample().unwrap();
+        assert_eq!((compact.source_id_type, compact.source_id_index), (0, 5));
+        assert_eq!(compact.records.len(), 2);
+        let interface =
+            SFlowGenericInterfaceCountersPacket::new(compact.records[0].payload()).unwrap();
+        assert_eq!(interface.get_if_index(), 5);
+        assert_eq!(interface.get_if_speed(), 10_000_000_000);
+        assert_eq!(interface.get_if_in_octets(), (1 << 32) + 1);
+        assert_eq!(interface.get_if_in_ucast_pkts(), 10);
+        assert_eq!(interface.get_if_out_octets(), 1500);
+        assert_eq!(interface.get_if_out_ucast_pkts(), 40);
+        assert_eq!(compact.records[1].get_record_type(), 1001);
+        let processor = SFlowProcessorCountersPacket::new(compact.records[1].payload()).unwrap();
+        assert_eq!(processor.get_cpu_5s(), 1234);
+        assert_eq!(processor.get_total_memory(), 1 << 31);
+        assert_eq!(processor.get_free_memory(), 1 << 30);
+
+        // the expanded layout has room for ifIndex values past 24 bits
+        let expanded = samples[1].get_counter_sample().unwrap();
+        assert_eq!(
+            (expanded.source_id_type, expanded.source_id_index),
+            (0, 70_000)
+        );
+        assert_eq!(expanded.records.len(), 1);
+        assert!(matches!(
+            samples[1].get_flow_sample(),
+            Err(DecodeError::UnsupportedSampleType(4))
+        ));
+    }
+
+    fn record(record_type: u32, payload: Vec<u8>) -> Vec<u8> {
+        [words(&[record_type, payload.len() as u32]), payload].concat()
+    }
+
+    // An XDR string, zero padded to a multiple of 4 bytes
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = words(&[value.len() as u32]);
+        bytes.extend(value.as_bytes());
+        bytes.resize(4 + value.len().div_ceil(4) * 4, 0);
+        bytes
+    }
+
+    // A compact flow sample from ifIndex 5, sampling 1 in 256
+    fn flow_sample(records: &[Vec<u8>]) -> Vec<u8> {
+        let body = [
+            words(&[1, 5, 256, 256, 0, 5, 6, records.len() as u32]),
+            records.concat(),
+        ]
+        .concat();
+        [words(&[1, body.len() as u32]), body].concat()
+    }
+
+    fn extended_data(sample: &FlowSample) -> Result<SFlowExtendedData, DecodeError> {
+        let mut extended = SFlowExtendedData::default();
+        for record in &sample.records {
+            extended.add(record)?;
+        }
+        Ok(extended)
+    }
+
+    fn extended_records() -> Vec<Vec<u8>> {
+        let v6_next_hop = "2001:db8::ff".parse::<Ipv6Addr>().unwrap().octets();
+        // via 192.0.2.254 from AS 64500 (peer 64501), out AS_SEQUENCE
+        // 64510 64520 64530, community 65000:1, local pref 100
+        let gateway = [
+            words(&[1, 0xc000_02fe, 64499, 64500, 64501, 1]),
+            words(&[2, 3, 64510, 64520, 64530]),
+            words(&[1, 0xfde8_0001, 100]),
+        ]
+        .concat();
+        vec![
+            record(
+                1002,
+                [words(&[2]), v6_next_hop.to_vec(), words(&[24, 16])].concat(),
+            ),
+            record(1003, gateway),
+            record(
+                1004,
+                [words(&[106]), string("alice"), words(&[106]), string("bob")].concat(),
+            ),
+            record(
+                1005,
+                [words(&[1]), string("/index.html"), string("example.com")].concat(),
+            ),
+            record(1006, words(&[1, 0x0a00_0001, 2, 16, 17, 1, 18])),
+            record(1007, words(&[1, 0xc0a8_0001, 1, 0xcb00_7101])),
+        ]
+    }
+
+    #[test]
+    fn decodes_extended_data_records() {
+        let agent = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let bytes = datagram(agent, &[flow_sample(&extended_records())]);
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        let samples = datagram_packet.get_samples().unwrap();
+        let sample = samples[0].get_flow_sample().unwrap();
+        let extended = extended_data(&sample).unwrap();
+
+        let router = extended.router.unwrap();
+        assert_eq!(router.next_hop, "2001:db8::ff".parse::<IpAddr>().unwrap());
+        assert_eq!((router.src_mask_len, router.dst_mask_len), (24, 16));
+
+        let gateway = extended.gateway.unwrap();
+        assert_eq!(gateway.next_hop, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 254)));
+        assert_eq!(gateway.as_number, 64499);
+        assert_eq!((gateway.src_as, gateway.src_peer_as), (64500, 64501));
+        assert_eq!(gateway.dst_as_path.len(), 1);
+        assert_eq!(gateway.dst_as_path[0].segment_type, 2);
+        assert_eq!(gateway.dst_peer_as(), Some(64510));
+        assert_eq!(gateway.dst_as(), Some(64530));
+        assert_eq!(gateway.communities, [0xfde8_0001]);
+        assert_eq!(gateway.local_pref, 100);
+
+        let user = extended.user.unwrap();
+        assert_eq!((user.src_charset, user.src_user.as_str()), (106, "alice"));
+        assert_eq!((user.dst_charset, user.dst_user.as_str()), (106, "bob"));
+
+        let url = extended.url.unwrap();
+        assert_eq!(url.direction, 1);
+        assert_eq!(url.url, "/index.html");
+        assert_eq!(url.host, "example.com");
+
+        let mpls = extended.mpls.unwrap();
+        assert_eq!(mpls.next_hop, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(mpls.in_labels, [16, 17]);
+        assert_eq!(mpls.out_labels, [18]);
+
+        let nat = extended.nat.unwrap();
+        assert_eq!(nat.src_address, IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)));
+        assert_eq!(nat.dst_address, IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)));
+
+        // the gateway record also fills in the AS fields of the sampled flow
+        let flow = sample.sampled_flow().unwrap();
+        assert_eq!((flow.src_as, flow.src_peer_as), (Some(64500), Some(64501)));
+        assert_eq!((flow.dst_as, flow.dst_peer_as), (Some(64530), Some(64510)));
+        assert_eq!(flow.next_hop, Some(gateway.next_hop));
+        assert_eq!(flow.input_interface, Some(5));
+    }
+
+    #[test]
+    fn an_empty_as_path_falls_back_to_the_routers_as() {
+        let gateway = record(1003, words(&[1, 0xc000_02fe, 64499, 0, 0, 0, 0, 0]));
+        let agent = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let bytes = datagram(agent, &[flow_sample(&[gateway])]);
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        let samples = datagram_packet.get_samples().unwrap();
+        let flow = samples[0]
+            .get_flow_sample()
+            .unwrap()
+            .sampled_flow()
+            .unwrap();
+        assert_eq!((flow.dst_as, flow.dst_peer_as), (Some(64499), Some(64499)));
+    }
+
+    #[test]
+    fn malformed_extended_records_are_decode_errors() {
+        let cases = [
+            // an IPv6 next hop cut short
+            (record(1002, words(&[2, 0, 0])), "extended router"),
+            // a million AS path segments in a few bytes
+            (
+                record(1003, words(&[1, 1, 1, 1, 1, 1_000_000, 0, 0])),
+                "AS path segment",
+            ),
+            // a user name longer than the record
+            (record(1004, words(&[106, 64, 0])), "extended user"),
+            // a label array longer than the record
+            (record(1006, words(&[1, 1, 1000, 16])), "extended mpls"),
+            // no room for the destination address
+            (record(1007, words(&[1, 1])), "extended nat"),
+        ];
+        let agent = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        for (record, what) in cases {
+            let bytes = datagram(agent, &[flow_sample(&[record])]);
+            let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+            let samples = datagram_packet.get_samples().unwrap();
+            let sample = samples[0].get_flow_sample().unwrap();
+            match extended_data(&sample) {
+                Err(DecodeError::Truncated(name))
+                | Err(DecodeError::LengthOverflow(name, _))
+                | Err(DecodeError::BadCount(name, _)) => assert_eq!(name, what),
+                other => panic!("{what}: {other:?}"),
+            }
+        }
+
+        let unknown_address = record(1007, words(&[3, 0, 1, 1]));
+        let bytes = datagram(agent, &[flow_sample(&[unknown_address])]);
+        let datagram_packet = SFlowPacket::decode(&bytes).unwrap();
+        let samples = datagram_packet.get_samples().unwrap();
+        let sample = samples[0].get_flow_sample().unwrap();
+        assert!(matches!(
+            extended_data(&sample),
+            Err(DecodeError::UnsupportedAddressType(3))
+        ));
+    }
+}
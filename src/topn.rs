@@ -0,0 +1,272 @@
+use crate::expiry::{Expire, Limits};
+use crate::metrics::FlowSink;
+use crate::sflow5::SampledFlow;
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Tracks the heaviest keys of an unbounded stream in at most `capacity`
+/// counters (Metwally et al., "Efficient Computation of Frequent and Top-k
+/// Elements in Data Streams"). A key that shows up once the counters are full
+/// takes over the smallest one, so every count is an overestimate by at most
+/// its `error`, and any key heavier than total / capacity is guaranteed to be
+/// tracked.
+pub struct SpaceSaving<K> {
+    capacity: usize,
+    counters: HashMap<K, Slot>,
+    by_count: BTreeSet<(u64, K)>,
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    count: u64,
+    error: u64,
+}
+
+impl<K: Clone + Eq + Hash + Ord> SpaceSaving<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            counters: HashMap::new(),
+            by_count: BTreeSet::new(),
+        }
+    }
+
+    pub fn add(&mut self, key: K, weight: u64) {
+        if let Some(slot) = self.counters.get_mut(&key) {
+            self.by_count.remove(&(slot.count, key.clone()));
+            slot.count += weight;
+            self.by_count.insert((slot.count, key));
+            return;
+        }
+        let mut slot = Slot {
+            count: weight,
+            error: 0,
+        };
+        if self.counters.len() >= self.capacity {
+            if let Some((min, evicted)) = self.by_count.pop_first() {
+                self.counters.remove(&evicted);
+                slot = Slot {
+                    count: min + weight,
+                    error: min,
+                };
+            }
+        }
+        self.by_count.insert((slot.count, key.clone()));
+        self.counters.insert(key, slot);
+    }
+
+    /// The `n` heaviest keys, heaviest first, with their count and how much of
+    /// it may be overestimated.
+    pub fn top(&self, n: usize) -> impl Iterator<Item = (&K, u64, u64)> {
+        self.by_count
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(count, key)| (key, *count, self.counters[key].error))
+    }
+}
+
+/// What the top lists can be keyed on, as given in `?key=`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dimension {
+    Agent,
+    SrcIp,
+    DstIp,
+    SrcMac,
+    DstMac,
+    Vlan,
+    Protocol,
+    SrcPort,
+    DstPort,
+    InputInterface,
+    OutputInterface,
+    SrcAs,
+    DstAs,
+}
+
+/// What the top lists are ranked by, as given in `?by=`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Bytes,
+    Packets,
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(untagged)]
+pub enum TopKey {
+    Ip(IpAddr),
+    Mac(MacAddr),
+    Number(u32),
+}
+
+/// Heavy hitters over every flow sample since startup, one Space-Saving
+/// summary per dimension and metric, so memory stays bounded however many
+/// distinct keys the exact tables have to evict.
+pub struct TopTalkers {
+    capacity: usize,
+    summaries: HashMap<(Dimension, Metric), SpaceSaving<TopKey>>,
+}
+
+impl TopTalkers {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            summaries: HashMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn top(&self, key: Dimension, by: Metric, n: usize) -> Vec<(&TopKey, u64, u64)> {
+        self.summaries
+            .get(&(key, by))
+            .map(|summary| summary.top(n).collect())
+            .unwrap_or_default()
+    }
+
+    fn add(&mut self, dimension: Dimension, key: TopKey, packets: u64, bytes: u64) {
+        for (metric, weight) in [(Metric::Bytes, bytes), (Metric::Packets, packets)] {
+            self.summaries
+                .entry((dimension, metric))
+                .or_insert_with(|| SpaceSaving::new(self.capacity))
+                .add(key.clone(), weight);
+        }
+    }
+}
+
+impl Default for TopTalkers {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl FlowSink for TopTalkers {
+    fn add_flow(&mut self, agent: IpAddr, flow: &SampledFlow, packets: u64, bytes: u64) {
+        let mut keys = vec![(Dimension::Agent, TopKey::Ip(agent))];
+        let numbers = [
+            (Dimension::InputInterface, flow.input_interface),
+            (Dimension::OutputInterface, flow.output_interface),
+            (Dimension::Vlan, flow.vlan),
+            (Dimension::SrcAs, flow.src_as),
+            (Dimension::DstAs, flow.dst_as),
+        ];
+        for (dimension, number) in numbers {
+            if let Some(number) = number {
+                keys.push((dimension, TopKey::Number(number)));
+            }
+        }
+        if let Some(src_mac) = flow.src_mac {
+            keys.push((Dimension::SrcMac, TopKey::Mac(src_mac)));
+        }
+        if let Some(dst_mac) = flow.dst_mac {
+            keys.push((Dimension::DstMac, TopKey::Mac(dst_mac)));
+        }
+        if let Some(ip) = &flow.ip {
+            keys.push((Dimension::SrcIp, TopKey::Ip(ip.src_ip)));
+            keys.push((Dimension::DstIp, TopKey::Ip(ip.dst_ip)));
+            keys.push((Dimension::Protocol, TopKey::Number(ip.protocol)));
+            // only TCP and UDP have ports, the rest would all pile up on 0
+            if matches!(ip.protocol, 6 | 17) {
+                keys.push((Dimension::SrcPort, TopKey::Number(ip.src_port)));
+                keys.push((Dimension::DstPort, TopKey::Number(ip.dst_port)));
+            }
+        }
+
+        for (dimension, key) in keys {
+            self.add(dimension, key, packets, bytes);
+        }
+    }
+}
+
+// The summaries never hold more than `capacity` keys each, so there is
+// nothing to age out.
+impl Expire for TopTalkers {
+    fn expire(&mut self, _limits: &Limits, _now: Instant) -> u64 {
+        0
+    }
+
+    fn enforce_cap(&mut self, _limits: &Limits) -> u64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn top(summary: &SpaceSaving<&'static str>) -> Vec<(&'static str, u64, u64)> {
+        summary
+            .top(usize::MAX)
+            .map(|(key, count, error)| (*key, count, error))
+            .collect()
+    }
+
+    #[test]
+    fn counts_exactly_while_there_is_room() {
+        let mut summary = SpaceSaving::new(3);
+        summary.add("a", 5);
+        summary.add("b", 2);
+        summary.add("a", 1);
+        assert_eq!(top(&summary), [("a", 6, 0), ("b", 2, 0)]);
+        assert_eq!(summary.top(1).count(), 1);
+    }
+
+    #[test]
+    fn a_new_key_takes_over_the_smallest_counter() {
+        let mut summary = SpaceSaving::new(2);
+        summary.add("a", 10);
+        summary.add("b", 3);
+        summary.add("c", 1);
+        // c inherits b's 3 as its possible overestimate
+        assert_eq!(top(&summary), [("a", 10, 0), ("c", 4, 3)]);
+
+        // and b coming back takes over c in turn
+        summary.add("b", 2);
+        assert_eq!(top(&summary), [("a", 10, 0), ("b", 6, 4)]);
+    }
+
+    #[test]
+    fn counts_stay_within_their_error_bounds() {
+        // a heavy key among a long tail of distinct light ones
+        let keys: Vec<String> = (0..200).map(|i| i.to_string()).collect();
+        let mut summary = SpaceSaving::new(10);
+        let mut total = 0;
+        for (i, key) in keys.iter().enumerate() {
+            summary.add(key.as_str(), 1);
+            total += 1;
+            if i % 4 == 0 {
+                summary.add("heavy", 3);
+                total += 3;
+            }
+        }
+        // 50 * 3 = 150 of 350 is well above total / capacity, so it is kept
+        let (key, count, error) = summary.top(1).next().unwrap();
+        assert_eq!(*key, "heavy");
+        assert!(count >= 150 && count - error <= 150);
+        for (key, count, error) in summary.top(10) {
+            let actual = match *key {
+                "heavy" => 150,
+                _ => 1,
+            };
+            assert!(count >= actual, "{key} undercounted");
+            assert!(count - error <= actual, "{key} off by more than its error");
+            assert!(error <= total / 10);
+        }
+    }
+
+    #[test]
+    fn a_capacity_of_zero_still_holds_one_key() {
+        let mut summary = SpaceSaving::new(0);
+        summary.add("a", 1);
+        summary.add("b", 1);
+        assert_eq!(top(&summary), [("b", 2, 1)]);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::metrics::FlowSink;
+use crate::sflow5::SampledFlow;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counters that can be added up across windows.
+pub trait Merge {
+    fn merge(&mut self, other: &Self);
+}
+
+impl<K: Clone + Eq + Hash, V: Merge + Default> Merge for HashMap<K, V> {
+    fn merge(&mut self, other: &Self) {
+        for (key, value) in other {
+            self.entry(key.clone()).or_default().merge(value);
+        }
+    }
+}
+
+pub struct Window<C> {
+    pub start: u64, // unix seconds, aligned to the interval
+    pub end: u64,
+    pub counters: C,
+}
+
+/// Runs a collector over fixed tumbling windows. Closed windows are kept
+/// newest first, and only the last `history` of them, so memory stays at
+/// `history + 1` times whatever a single window holds.
+pub struct Windowed<C> {
+    interval: u64,
+    history: usize,
+    current: Window<C>,
+    closed: VecDeque<Window<C>>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+impl<C: Default> Windowed<C> {
+    pub fn new(interval: u64, history: usize) -> Self {
+        let interval = interval.max(1);
+        Self {
+            interval,
+            history,
+            current: Self::window_at(interval, unix_now()),
+            closed: VecDeque::with_capacity(history),
+        }
+    }
+
+    fn window_at(interval: u64, now: u64) -> Window<C> {
+        let start = now - now % interval;
+        Window {
+            start,
+            end: start + interval,
+            counters: C::default(),
+        }
+    }
+
+    /// Closes the current window if its interval is over. Intervals nothing
+    /// was collected in are closed as empty windows, so that the window
+    /// `age` back always starts `age` intervals before the open one.
+    pub fn roll(&mut self) {
+        self.roll_at(unix_now());
+    }
+
+    fn roll_at(&mut self, now: u64) {
+        if now < self.current.end {
+            return;
+        }
+        let next = Self::window_at(self.interval, now);
+        let last = std::mem::replace(&mut self.current, next);
+        let mut start = last.end;
+        self.closed.push_front(last);
+        // more empty windows than the history holds would only be dropped
+        // again, so a long gap skips ahead to the ones that are kept
+        let gap = (self.current.start - start) / self.interval;
+        start += gap.saturating_sub(self.history as u64) * self.interval;
+        while start < self.current.start {
+            self.closed.push_front(Window {
+                start,
+                end: start + self.interval,
+                counters: C::default(),
+            });
+            start += self.interval;
+        }
+        self.closed.truncate(self.history);
+    }
+
+    pub fn current_mut(&mut self) -> &mut C {
+        &mut self.current.counters
+    }
+
+    /// The window `age` windows back, where 0 is the one still open.
+    pub fn window(&self, age: usize) -> Option<&Window<C>> {
+        match age {
+            0 => Some(&self.current),
+            _ => self.closed.get(age - 1),
+        }
+    }
+
+    /// Every window overlapping [from, to), oldest first.
+    pub fn range(&self, from: u64, to: u64) -> impl Iterator<Item = &Window<C>> {
+        self.closed
+            .iter()
+            .rev()
+            .chain(std::iter::once(&self.current))
+            .filter(move |window| window.start < to && window.end > from)
+    }
+}
+
+impl<C: Default> Default for Windowed<C> {
+    fn default() -> Self {
+        Self::new(60, 60)
+    }
+}
+
+impl<C: FlowSink + Default> FlowSink for Windowed<C> {
+    fn add_flow(&mut self, agent: IpAddr, flow: &SampledFlow, packets: u64, bytes: u64) {
+        self.roll();
+        self.current.counters.add_flow(agent, flow, packets, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expiry::{EvictionPolicy, Expire, Expiring, Limits};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn idle_intervals_are_kept_as_empty_windows() {
+        let mut windows = Windowed::<u64>::new(60, 3);
+        windows.current = Windowed::window_at(60, 600);
+        *windows.current_mut() = 1;
+        windows.roll_at(730);
+        let starts: Vec<_> = (0..3)
+            .map(|age| windows.window(age).map(|window| window.start))
+            .collect();
+        assert_eq!(starts, [Some(720), Some(660), Some(600)]);
+        assert_eq!(windows.window(2).unwrap().counters, 1);
+
+        windows.roll_at(6000);
+        let starts: Vec<_> = (0..5)
+            .map(|age| windows.window(age).map(|window| window.start))
+            .collect();
+        assert_eq!(
+            starts,
+            [Some(6000), Some(5940), Some(5880), Some(5820), None]
+        );
+    }
+
+    struct Entry(Instant, Instant); // first and last seen
+
+    impl Expiring for Entry {
+        fn first_seen(&self) -> Option<Instant> {
+            Some(self.0)
+        }
+
+        fn last_seen(&self) -> Option<Instant> {
+            Some(self.1)
+        }
+
+        fn bytes(&self) -> u64 {
+            0
+        }
+    }
+
+    #[test]
+    fn the_open_window_is_only_expired_by_the_idle_timeout() {
+        let now = Instant::now();
+        let minute_ago = now - Duration::from_secs(60);
+        let mut windows = Windowed::<HashMap<u32, Entry>>::new(3600, 1);
+        // active for a minute and still busy, then idle for a minute
+        windows.current_mut().insert(1, Entry(minute_ago, now));
+        windows
+            .current_mut()
+            .insert(2, Entry(minute_ago, minute_ago));
+        let limits = Limits {
+            idle_timeout: Some(Duration::from_secs(30)),
+            active_timeout: Some(Duration::from_secs(30)),
+            max_entries: 10,
+            eviction: EvictionPolicy::Lru,
+        };
+        assert_eq!(windows.expire(&limits, now), 1);
+        assert!(windows.current_mut().contains_key(&1));
+
+        // outside a window the active timeout still applies
+        let mut map = HashMap::from([(1, Entry(minute_ago, now))]);
+        assert_eq!(map.expire(&limits, now), 1);
+    }
+}